@@ -0,0 +1,69 @@
+//! The crate files requested from the built-in server.
+//!
+//! Each download is a line of JSON in access-log.jsonl, such that `prune`
+//! can keep the files that are actually being used.
+
+use crate::quarantine::now;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    sync::Mutex,
+    time::Duration,
+};
+
+pub const FILE: &str = "access-log.jsonl";
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// The path of the crate file, like `crates/foo/foo-1.0.0.crate`.
+    file: String,
+    /// Unix timestamp of the request.
+    time: u64,
+}
+
+pub struct AccessLog {
+    log: Mutex<File>,
+}
+
+impl AccessLog {
+    pub fn open() -> Result<Self> {
+        Ok(Self {
+            log: Mutex::new(File::options().create(true).append(true).open(FILE)?),
+        })
+    }
+
+    /// Record a request for a crate file.
+    pub fn record(&self, file: &str) -> Result<()> {
+        let entry = Entry {
+            file: file.to_string(),
+            time: now(),
+        };
+        let line = serde_json::to_string(&entry)? + "\n";
+        self.log.lock().unwrap().write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// The crate files requested within `max_age`, and how long ago they were last requested.
+pub fn requested_within(max_age: Duration) -> Result<HashMap<String, Duration>> {
+    let mut files = HashMap::new();
+    let f = match File::open(FILE) {
+        Ok(f) => f,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(files),
+        Err(e) => return Err(e.into()),
+    };
+    let now = now();
+    for line in BufReader::new(f).lines() {
+        let entry: Entry =
+            serde_json::from_str(&line?).with_context(|| format!("unable to parse {FILE}"))?;
+        let age = Duration::from_secs(now.saturating_sub(entry.time));
+        if age < max_age {
+            // The log is in chronological order, so later entries are more recent.
+            files.insert(entry.file, age);
+        }
+    }
+    Ok(files)
+}
@@ -0,0 +1,160 @@
+//! Growth statistics from the history of the index.
+//!
+//! Rather than relying on the format of commit messages, this looks at the
+//! changes of each commit: every line added to a file is a newly published
+//! version, and every new file is a new crate. The first commit of the
+//! (possibly squashed) history is taken as the starting point.
+
+use crate::cold;
+use anyhow::{Context, Result};
+use std::{
+    collections::BTreeMap,
+    fs::read_dir,
+    io::{BufRead, BufReader},
+    path::Path,
+    process::{Command, Stdio},
+};
+
+#[derive(Default)]
+struct Period {
+    publishes: u64,
+    new_crates: u64,
+    deleted_crates: u64,
+}
+
+pub fn report(daily: bool) -> Result<()> {
+    let date_format = if daily { "%Y-%m-%d" } else { "%Y-%m" };
+    let mut child = Command::new("git")
+        .args([
+            "-C",
+            "crates.io-index",
+            "log",
+            "--reverse",
+            "--numstat",
+            "--summary",
+        ])
+        .arg(format!("--date=format:{date_format}"))
+        .arg("--format=commit %cd %P")
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let mut start = None;
+    let mut initial_crates = 0;
+    let mut initial_versions = 0;
+    let mut periods = BTreeMap::<String, Period>::new();
+    let mut current: Option<&mut Period> = None;
+    for line in BufReader::new(child.stdout.take().unwrap()).lines() {
+        let line = line?;
+        if let Some(rest) = line.strip_prefix("commit ") {
+            let (date, parents) = rest.split_once(' ').unwrap_or((rest, ""));
+            if parents.is_empty() {
+                start.get_or_insert_with(|| date.to_string());
+                current = None;
+            } else {
+                current = Some(periods.entry(date.to_string()).or_default());
+            }
+        } else if let Some(path) = line.strip_prefix(" create mode 100644 ") {
+            if is_crate_file(path) {
+                match &mut current {
+                    Some(p) => p.new_crates += 1,
+                    None => initial_crates += 1,
+                }
+            }
+        } else if let Some(path) = line.strip_prefix(" delete mode 100644 ") {
+            if is_crate_file(path) {
+                if let Some(p) = &mut current {
+                    p.deleted_crates += 1;
+                }
+            }
+        } else if let [added, removed, path] = line.split('\t').collect::<Vec<_>>()[..] {
+            if is_crate_file(path) {
+                let added: u64 = added.parse().unwrap_or(0);
+                let removed: u64 = removed.parse().unwrap_or(0);
+                let new = added.saturating_sub(removed);
+                match &mut current {
+                    Some(p) => p.publishes += new,
+                    None => initial_versions += new,
+                }
+            }
+        }
+    }
+    child.wait()?.exit_ok().context("git log failed")?;
+
+    let start = start.context("index has no history")?;
+    println!(
+        "History starts at {start} with {initial_crates} crates and {initial_versions} versions"
+    );
+    println!();
+    println!(
+        "{:<10} {:>10} {:>10} {:>10}",
+        "period", "publishes", "new crates", "crates"
+    );
+    let mut n_crates = initial_crates;
+    let mut n_versions = initial_versions;
+    for (date, p) in &periods {
+        n_crates = (n_crates + p.new_crates).saturating_sub(p.deleted_crates);
+        n_versions += p.publishes;
+        println!(
+            "{date:<10} {:>10} {:>10} {n_crates:>10}",
+            p.publishes, p.new_crates
+        );
+    }
+
+    // Project the size of a full mirror based on the average size of the
+    // crate files we have, and the publish rate of the last (complete) periods.
+    let (n_files, bytes) = local_crate_files()?;
+    let skip_current = (periods.len() > 1) as usize;
+    let recent: Vec<_> = periods.values().rev().skip(skip_current).take(3).collect();
+    if n_files > 0 && !recent.is_empty() {
+        let average = bytes / n_files;
+        let rate = recent.iter().map(|p| p.publishes).sum::<u64>() / recent.len() as u64;
+        let unit = if daily { "day" } else { "month" };
+        println!();
+        println!(
+            "Average crate file size: {} KiB (from {n_files} local files)",
+            average / 1024
+        );
+        println!(
+            "Recent growth: {rate} versions/{unit}, about {:.1} GiB/{unit}",
+            gib(rate * average)
+        );
+        let full = n_versions * average;
+        for periods in [0, 6, 12, 24] {
+            println!(
+                "Projected full mirror size after {periods:>2} {unit}s: {:.1} GiB",
+                gib(full + periods * rate * average)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn gib(bytes: u64) -> f64 {
+    bytes as f64 / (1u64 << 30) as f64
+}
+
+fn is_crate_file(path: &str) -> bool {
+    !path.starts_with('.') && path != "config.json"
+}
+
+/// The number and total size of the crate files in the mirror.
+fn local_crate_files() -> Result<(u64, u64)> {
+    let mut n = 0;
+    let mut bytes = 0;
+    if !Path::new("crates").exists() {
+        return Ok((0, 0));
+    }
+    for dir in read_dir("crates")? {
+        for file in read_dir(dir?.path())? {
+            let file = file?;
+            let name = file.file_name();
+            let name = name.to_string_lossy();
+            if name.ends_with(".crate") || name.ends_with(cold::SUFFIX) {
+                n += 1;
+                bytes += file.metadata()?.len();
+            }
+        }
+    }
+    Ok((n, bytes))
+}
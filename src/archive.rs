@@ -0,0 +1,197 @@
+//! Archiving the mirror into tar volumes, for tape or cold storage.
+//!
+//! Every run writes the crate files that weren't archived yet into new
+//! volumes (volume-00001.tar, volume-00002.tar, ...) of at most the given size.
+//! Existing volumes are never modified. Which file is in which volume is
+//! recorded in index.jsonl, which is used to restore files later.
+
+use crate::{
+    check_checksum, cold,
+    index::Index,
+    state::{parse_file, State},
+    store::{Hasher, Store},
+};
+use anyhow::{ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{create_dir_all, read_to_string, remove_file, rename, File},
+    io::Write,
+    path::Path,
+};
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// The path of the crate file, like `crates/foo/foo-1.0.0.crate`.
+    file: String,
+    /// The number of the volume.
+    volume: u32,
+    size: u64,
+}
+
+fn volume_path(dir: &Path, volume: u32) -> std::path::PathBuf {
+    dir.join(format!("volume-{volume:05}.tar"))
+}
+
+fn read_index(dir: &Path) -> Result<Vec<Entry>> {
+    let path = dir.join("index.jsonl");
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    read_to_string(&path)?
+        .lines()
+        .map(|line| serde_json::from_str(line).with_context(|| format!("unable to parse {path:?}")))
+        .collect()
+}
+
+/// Write the crate files that aren't in the archive yet to new volumes in `dir`.
+pub fn archive(dir: &Path, volume_size: u64) -> Result<()> {
+    create_dir_all(dir)?;
+    let entries = read_index(dir)?;
+    let archived: HashSet<&str> = entries.iter().map(|e| e.file.as_str()).collect();
+    let mut volume = entries.iter().map(|e| e.volume).max().unwrap_or(0);
+
+    // Crate files stored with zstd (see `cold`) are recorded, and archived, as the original crate file.
+    let mut files = Vec::new();
+    for file in State::open()?.present()? {
+        if !archived.contains(file.as_str()) {
+            let size = cold::size(&file)?;
+            files.push((file, size));
+        }
+    }
+    files.sort();
+
+    if files.is_empty() {
+        println!("Archive already contains all {} crate files", entries.len());
+        return Ok(());
+    }
+
+    let mut index = File::options()
+        .create(true)
+        .append(true)
+        .open(dir.join("index.jsonl"))?;
+    let mut files = files.into_iter().peekable();
+    let mut n_volumes = 0;
+    while files.peek().is_some() {
+        volume += 1;
+        n_volumes += 1;
+        let path = volume_path(dir, volume);
+        let partial = path.with_extension("tar.partial");
+        let mut builder = tar::Builder::new(File::create(&partial)?);
+        let mut size = 0;
+        let mut new_entries = Vec::new();
+        // A tar file has a 512 byte header for each file, and is padded to 512 bytes.
+        while let Some((file, len)) = files
+            .next_if(|(_, len)| size == 0 || size + 512 + len.next_multiple_of(512) <= volume_size)
+        {
+            cold::append(&mut builder, &file)?;
+            size += 512 + len.next_multiple_of(512);
+            new_entries.push(Entry {
+                file,
+                volume,
+                size: len,
+            });
+        }
+        builder.into_inner()?.sync_all()?;
+        rename(&partial, &path)?;
+        for e in &new_entries {
+            writeln!(index, "{}", serde_json::to_string(e)?)?;
+        }
+        index.sync_all()?;
+        println!(
+            "Wrote {} crate files to {}",
+            new_entries.len(),
+            path.display()
+        );
+    }
+
+    println!("Archived new crate files in {n_volumes} volumes");
+
+    Ok(())
+}
+
+/// Restore the given crates (`name` or `name@version`) from the archive in `dir`.
+///
+/// The restored files are checked against the checksums in the index.
+pub fn restore(dir: &Path, crates: &[String], store: &Store) -> Result<()> {
+    let entries = read_index(dir)?;
+
+    let mut wanted = HashSet::new();
+    for spec in crates {
+        let n = wanted.len();
+        let (name, version) = match spec.split_once('@') {
+            Some((name, version)) => (name, Some(version)),
+            None => (spec.as_str(), None),
+        };
+        for e in &entries {
+            let prefix = format!("crates/{name}/{name}-");
+            let Some(v) = e
+                .file
+                .strip_prefix(&prefix)
+                .and_then(|f| f.strip_suffix(".crate"))
+            else {
+                continue;
+            };
+            if version.is_none_or(|version| v == version) {
+                wanted.insert(e.file.as_str());
+            }
+        }
+        ensure!(wanted.len() > n, "{spec} not found in archive");
+    }
+
+    let mut volumes = BTreeMap::<u32, HashSet<&str>>::new();
+    for e in &entries {
+        if wanted.contains(e.file.as_str()) {
+            volumes.entry(e.volume).or_default().insert(&e.file);
+        }
+    }
+
+    println!("Loading index...");
+    let index = Index::read_cached()?;
+    let mut n = 0;
+    for (volume, files) in volumes {
+        let path = volume_path(dir, volume);
+        println!("Reading {}...", path.display());
+        let mut archive = tar::Archive::new(
+            File::open(&path).with_context(|| format!("unable to open {path:?}"))?,
+        );
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let file = entry
+                .path()?
+                .to_str()
+                .context("invalid utf-8 file name")?
+                .to_string();
+            if files.contains(file.as_str()) {
+                let cksum = parse_file(&file)
+                    .and_then(|(name, version)| Some(&index.crates.get(name)?.get(version)?.cksum))
+                    .with_context(|| format!("{file:?} in archive isn't in the index"))?;
+                create_dir_all(Path::new(&file).parent().unwrap())?;
+                let partial = format!("{file}.partial");
+                let mut f = File::options()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&partial)?;
+                let mut hasher = Hasher::default();
+                store.copy(&mut entry, &mut f, Some(&mut hasher))?;
+                let hashes = match check_checksum(hasher, &file, cksum) {
+                    Ok(hashes) => hashes,
+                    Err(e) => {
+                        drop(f);
+                        let _ = remove_file(&partial);
+                        return Err(e);
+                    }
+                };
+                drop(f);
+                store.commit(&partial, &file, &hashes)?;
+                n += 1;
+            }
+        }
+    }
+
+    println!("Restored {n} crate files");
+
+    Ok(())
+}
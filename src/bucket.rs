@@ -0,0 +1,154 @@
+//! Storing the crate files in an object store (S3, GCS or Azure) instead of the mirror directory.
+//!
+//! With `--object-store`, downloaded crate files are uploaded to the bucket
+//! after they are verified, under the same paths as they would have in the
+//! mirror (crates/{name}/{name}-{version}.crate). The index and the state
+//! files stay in the mirror directory.
+//!
+//! The credentials come from the environment, in the variables the cloud's
+//! own tools use, like `AWS_ACCESS_KEY_ID` and `AWS_ENDPOINT`.
+
+use crate::{filter::Filter, index::Index, state::State};
+use anyhow::{ensure, Context, Result};
+use futures_util::{StreamExt, TryStreamExt};
+use object_store::{path::Path as ObjectPath, ObjectStore, ObjectStoreExt};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    env,
+    fs::read,
+    sync::atomic::{AtomicUsize, Ordering::Relaxed},
+};
+
+pub struct Bucket {
+    store: Box<dyn ObjectStore>,
+    /// Where in the bucket the mirror is.
+    prefix: ObjectPath,
+    url: String,
+}
+
+impl Bucket {
+    /// Open the bucket at a URL like `s3://bucket/path`, `gs://bucket` or `az://container`.
+    pub fn open(url: &str) -> Result<Self> {
+        let parsed = reqwest::Url::parse(url).context("invalid URL")?;
+        let (store, prefix) = object_store::parse_url_opts(&parsed, env::vars())?;
+        Ok(Self {
+            store,
+            prefix,
+            url: url.to_string(),
+        })
+    }
+
+    fn path(&self, file: &str) -> ObjectPath {
+        file.split('/')
+            .fold(self.prefix.clone(), |path, part| path.join(part))
+    }
+
+    /// The paths of all crate files in the bucket.
+    pub async fn crate_files(&self) -> Result<HashSet<String>> {
+        let prefix = self.path("crates");
+        let strip = self.prefix.as_ref().len() + (!self.prefix.as_ref().is_empty()) as usize;
+        self.store
+            .list(Some(&prefix))
+            .map_ok(|meta| meta.location.as_ref()[strip..].to_string())
+            .try_collect()
+            .await
+            .with_context(|| format!("unable to list {}", self.url))
+    }
+
+    /// Upload a (verified) local file as `file`.
+    pub async fn upload(&self, local_file: &str, file: &str) -> Result<()> {
+        let data = read(local_file)?;
+        self.store
+            .put(&self.path(file), data.into())
+            .await
+            .with_context(|| format!("unable to upload {file:?} to {}", self.url))?;
+        Ok(())
+    }
+
+    /// Check the crate files in the bucket against the index, like `verify` does for the mirror.
+    ///
+    /// With `delete`, invalid files are removed, such that the next sync uploads them again.
+    pub fn verify(
+        &self,
+        index: &Index,
+        filter: &Filter,
+        delete: bool,
+        connections: usize,
+    ) -> Result<()> {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let state = &State::open()?;
+        runtime.block_on(async {
+            let files = self.crate_files().await?;
+            let mut queue = Vec::new();
+            let mut n_unknown = 0;
+            for file in &files {
+                let known = file
+                    .strip_prefix("crates/")
+                    .and_then(|f| f.split_once('/'))
+                    .and_then(|(name, f)| {
+                        let version = f
+                            .strip_prefix(&format!("{name}-"))?
+                            .strip_suffix(".crate")?;
+                        Some((name, index.crates.get(name)?.get(version)?))
+                    });
+                match known {
+                    Some((name, _)) if !filter.matches(name) => {}
+                    Some((_, data)) => queue.push((file, &data.cksum)),
+                    None => n_unknown += 1,
+                }
+            }
+            println!("Verifying {} crate files in {}...", queue.len(), self.url);
+
+            let n_bad = &AtomicUsize::new(0);
+            let n_errors = &AtomicUsize::new(0);
+            futures_util::stream::iter(queue)
+                .for_each_concurrent(connections.max(1), |(file, cksum)| async move {
+                    let path = self.path(file);
+                    let data = match self.store.get(&path).await {
+                        Ok(result) => result.bytes().await,
+                        Err(e) => Err(e),
+                    };
+                    let data = match data {
+                        Ok(data) => data,
+                        Err(e) => {
+                            println!("error: unable to read {file:?}: {e}");
+                            n_errors.fetch_add(1, Relaxed);
+                            return;
+                        }
+                    };
+                    let actual = base16ct::lower::encode_string(&Sha256::digest(&data));
+                    if actual != *cksum {
+                        println!("error: checksum of {file:?} is {actual}, but should be {cksum}");
+                        n_bad.fetch_add(1, Relaxed);
+                        if delete {
+                            match self.store.delete(&path).await {
+                                Ok(()) => {
+                                    if let Err(e) = state.remove(file) {
+                                        println!(
+                                            "error: unable to update the state of {file:?}: {e:#}"
+                                        );
+                                    }
+                                }
+                                Err(e) => println!("error: unable to delete {file:?}: {e}"),
+                            }
+                        }
+                    }
+                })
+                .await;
+
+            if n_unknown > 0 {
+                println!("warning: {n_unknown} files in the bucket aren't in the index");
+            }
+            let (n_bad, n_errors) = (n_bad.load(Relaxed), n_errors.load(Relaxed));
+            ensure!(
+                n_bad == 0 && n_errors == 0,
+                "{n_bad} crate files don't match the index, and {n_errors} couldn't be read"
+            );
+            println!("All crate files in the bucket match the index");
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,90 @@
+//! Selecting the most valuable part of crates.io, for partial mirrors.
+//!
+//! This uses the download counts and crate sizes from the crates.io database dump.
+//! Versions that aren't in the dump yet (or have no known size) aren't selected
+//! for a size budget until a newer dump includes them. As the selection is made
+//! again for every sync, it follows the download counts over time.
+
+use crate::{db_dump::DbDump, graph::Resolver, index::Index};
+use std::{cmp::Reverse, collections::BTreeMap};
+
+/// The `n` most downloaded crates of `index`, with all their versions, or only the latest one.
+pub fn top_crates(index: &Index, db_dump: &DbDump, n: usize, latest_only: bool) -> Index {
+    let mut names: Vec<&String> = index.crates.keys().collect();
+    names.sort_by_cached_key(|name| Reverse(db_dump.downloads(name).unwrap_or(0)));
+    names.truncate(n);
+
+    let resolver = Resolver::new(index);
+    let mut selected = Index {
+        crates: BTreeMap::new(),
+    };
+    for name in names {
+        let versions = &index.crates[name];
+        let versions = if latest_only {
+            // Crates of which all versions are yanked are skipped.
+            let Some(latest) = resolver.latest(name) else {
+                continue;
+            };
+            BTreeMap::from([(latest.to_string(), versions[latest].clone())])
+        } else {
+            versions.clone()
+        };
+        selected.crates.insert(name.clone(), versions);
+    }
+
+    println!(
+        "Selected the {} most downloaded crates, with {} versions",
+        selected.crates.len(),
+        selected.crates.values().map(|c| c.len()).sum::<usize>(),
+    );
+    selected
+}
+
+/// The part of `index` that fits in `budget` bytes.
+///
+/// Versions are picked greedily by their number of downloads per byte.
+pub fn select(index: &Index, db_dump: &DbDump, budget: u64) -> Index {
+    let mut candidates = Vec::new();
+    for (name, versions) in &index.crates {
+        for (version, data) in versions {
+            let Some(info) = db_dump.version(name, version) else {
+                continue;
+            };
+            let Some(size) = info.crate_size else {
+                continue;
+            };
+            if data.cksum == info.checksum {
+                candidates.push((name, version, info.downloads, size.max(1)));
+            }
+        }
+    }
+    // Compare downloads/size without floating point: a/b > c/d <=> a*d > c*b.
+    candidates.sort_by(|a, b| (b.2 as u128 * a.3 as u128).cmp(&(a.2 as u128 * b.3 as u128)));
+
+    let mut selected = Index {
+        crates: BTreeMap::new(),
+    };
+    let mut total = 0;
+    let mut n = 0;
+    for (name, version, _, size) in candidates {
+        // Smaller versions further down the list might still fit.
+        if total + size > budget {
+            continue;
+        }
+        total += size;
+        n += 1;
+        selected
+            .crates
+            .entry(name.clone())
+            .or_default()
+            .insert(version.clone(), index.crates[name][version].clone());
+    }
+
+    let n_crates = selected.crates.len();
+    println!(
+        "Selected {n} versions of {n_crates} crates ({} MiB) within the size budget of {} MiB",
+        total >> 20,
+        budget >> 20,
+    );
+    selected
+}
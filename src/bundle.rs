@@ -0,0 +1,410 @@
+//! Bundle files, for updating an air-gapped mirror by sneakernet.
+//!
+//! The offline mirror writes what it has with `bundle state`. The online
+//! mirror then packs everything the offline one is missing with
+//! `bundle export --since`, which `bundle import` verifies and unpacks on the
+//! offline side.
+//!
+//! Instead of a state, a delta bundle can have everything that was published
+//! between two index commits. The last exported commit is recorded in
+//! bundle-last-export.json, such that `bundle export` without options
+//! continues from there.
+//!
+//! A bundle is a tar file with, in this order:
+//!
+//!  - `manifest.json`: the index commits and the SHA-256 of every other file.
+//!  - `index.bundle`: a git bundle with the new index commits, if there are any.
+//!  - `crates/{name}/{name}-{version}.crate`: the crate files.
+
+use crate::{
+    check_checksum, cold,
+    index::Index,
+    ingest::{self, git},
+    push::git_output,
+    store::{Hasher, Store},
+};
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::{create_dir_all, read_dir, read_to_string, remove_file, rename, write, File},
+    io::{self, Read},
+    path::Path,
+    process::Command,
+};
+
+const MANIFEST: &str = "manifest.json";
+const INDEX_BUNDLE: &str = "index.bundle";
+pub const LAST_EXPORT: &str = "bundle-last-export.json";
+
+/// The ref that index.bundle has the new index commits under.
+const EXPORT_REF: &str = "refs/cratesync/export";
+
+/// What a bundle is relative to, which determines what's in it.
+pub enum Since<'a> {
+    /// Nothing, so the bundle has everything.
+    Nothing,
+    /// The state file of the other mirror, so the bundle has what it doesn't have.
+    State(&'a Path),
+    /// An index commit, so the bundle has what was published after it.
+    Commit(&'a str),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct LastExport {
+    /// The index commit that the last bundle brought the other mirror to.
+    pub commit: String,
+}
+
+/// A line of an index file, with just what a delta needs.
+#[derive(Deserialize)]
+struct IndexLine {
+    name: String,
+    vers: String,
+    cksum: String,
+}
+
+/// What a mirror has, as written by `bundle state`.
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    /// The index commit, if there is an index.
+    index: Option<String>,
+    /// The paths of all crate files.
+    crates: BTreeSet<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    /// The index commit that index.bundle builds on, if it doesn't have the whole history.
+    index_base: Option<String>,
+    /// The index commit after importing.
+    index_head: String,
+    /// The SHA-256 of index.bundle, if the index changed.
+    index_bundle: Option<String>,
+    /// Path -> SHA-256 of every crate file.
+    crates: BTreeMap<String, String>,
+}
+
+/// Write the state of this mirror to `file`, for `bundle export --since`.
+pub fn state(file: &Path) -> Result<()> {
+    let mut state = State::default();
+    if Path::new("crates.io-index/.git").exists() {
+        state.index = Index::head_commit().ok();
+    }
+    if Path::new("crates").exists() {
+        for dir in read_dir("crates")? {
+            for f in read_dir(dir?.path())? {
+                let path = f?.path();
+                let path = path.to_str().context("invalid utf-8 file name")?;
+                // Crate files stored with zstd (see `cold`) by their original name.
+                let path = path.strip_suffix(".zst").unwrap_or(path);
+                if path.ends_with(".crate") {
+                    state.crates.insert(path.to_string());
+                }
+            }
+        }
+    }
+    write(file, serde_json::to_string(&state)?)?;
+    println!(
+        "Wrote state with {} crate files to {}",
+        state.crates.len(),
+        file.display()
+    );
+    Ok(())
+}
+
+/// Write a bundle with the index up to commit `to` (or HEAD), and the crate files that the
+/// other mirror doesn't have according to `since`.
+///
+/// With [`Since::State`] or [`Since::Nothing`], `to` must be HEAD.
+pub fn export(file: &Path, since: Since, to: Option<&str>) -> Result<()> {
+    let head = Index::head_commit()?;
+    let to = match to {
+        Some(to) => git_output(&["rev-parse", "--verify", &format!("{to}^{{commit}}")])
+            .with_context(|| format!("unknown index commit {to:?}"))?,
+        None => head.clone(),
+    };
+    ensure!(
+        to == head || matches!(since, Since::Commit(_)),
+        "--to only works for delta bundles"
+    );
+
+    let (index_base, crates) = match since {
+        Since::Nothing => (None, existing(&State::default())?),
+        Since::State(path) => {
+            let state: State = serde_json::from_str(
+                &read_to_string(path).with_context(|| format!("unable to read {path:?}"))?,
+            )
+            .with_context(|| format!("unable to parse {path:?}"))?;
+            // After the index history was squashed, the whole index has to be sent.
+            let base = state.index.clone().filter(|base| is_ancestor(base, &to));
+            (base, existing(&state)?)
+        }
+        Since::Commit(from) => {
+            let from = git_output(&["rev-parse", "--verify", &format!("{from}^{{commit}}")])
+                .with_context(|| format!("unknown index commit {from:?}"))?;
+            ensure!(
+                is_ancestor(&from, &to),
+                "index commit {from} is not an ancestor of {to}"
+            );
+            println!("Finding the versions published between {from} and {to}...");
+            let mut crates = published(&from, &to)?;
+            let n = crates.len();
+            crates.retain(|path, _| Path::new(path).exists());
+            if crates.len() < n {
+                println!(
+                    "warning: {} of the {n} published crate files aren't in the mirror, so aren't in the bundle",
+                    n - crates.len()
+                );
+            }
+            (Some(from), crates)
+        }
+    };
+
+    let index_bundle = if index_base.as_ref() == Some(&to) {
+        None
+    } else {
+        git(&["-C", "crates.io-index", "update-ref", EXPORT_REF, &to])?;
+        let range = match &index_base {
+            Some(base) => format!("{base}..{EXPORT_REF}"),
+            None => EXPORT_REF.to_string(),
+        };
+        let result = git(&[
+            "-C",
+            "crates.io-index",
+            "bundle",
+            "create",
+            "--quiet",
+            &format!("../{INDEX_BUNDLE}"),
+            &range,
+        ]);
+        git(&["-C", "crates.io-index", "update-ref", "-d", EXPORT_REF])?;
+        result?;
+        Some(sha256(File::open(INDEX_BUNDLE)?)?)
+    };
+
+    let manifest = Manifest {
+        index_base,
+        index_head: to,
+        index_bundle,
+        crates,
+    };
+    let manifest_json = serde_json::to_vec_pretty(&manifest)?;
+
+    let partial = file.with_extension("partial");
+    let mut builder = tar::Builder::new(File::create(&partial)?);
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest_json.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, MANIFEST, &manifest_json[..])?;
+    if manifest.index_bundle.is_some() {
+        builder.append_path(INDEX_BUNDLE)?;
+        remove_file(INDEX_BUNDLE)?;
+    }
+    for path in manifest.crates.keys() {
+        cold::append(&mut builder, path)?;
+    }
+    builder.into_inner()?.sync_all()?;
+    rename(&partial, file)?;
+    write(
+        LAST_EXPORT,
+        serde_json::to_string(&LastExport {
+            commit: manifest.index_head.clone(),
+        })?,
+    )?;
+
+    println!(
+        "Wrote {} crate files{} to {}",
+        manifest.crates.len(),
+        if manifest.index_bundle.is_some() {
+            " and the index update"
+        } else {
+            ""
+        },
+        file.display()
+    );
+    Ok(())
+}
+
+/// Verify and unpack a bundle written by `bundle export`.
+pub fn import(file: &Path, store: &Store) -> Result<()> {
+    let mut archive =
+        tar::Archive::new(File::open(file).with_context(|| format!("unable to open {file:?}"))?);
+    let mut entries = archive.entries()?;
+
+    let mut entry = entries.next().context("empty bundle")??;
+    ensure!(
+        entry.path()?.to_str() == Some(MANIFEST),
+        "bundle doesn't start with {MANIFEST}"
+    );
+    let mut manifest = String::new();
+    entry.read_to_string(&mut manifest)?;
+    let manifest: Manifest =
+        serde_json::from_str(&manifest).with_context(|| format!("invalid {MANIFEST}"))?;
+
+    let mut next = entries.next().transpose()?;
+    if let Some(cksum) = &manifest.index_bundle {
+        let mut bundle = next
+            .filter(|e| e.path().is_ok_and(|p| p.to_str() == Some(INDEX_BUNDLE)))
+            .with_context(|| format!("bundle has no {INDEX_BUNDLE}"))?;
+        bundle.unpack(INDEX_BUNDLE)?;
+        ensure!(
+            sha256(File::open(INDEX_BUNDLE)?)? == *cksum,
+            "checksum of {INDEX_BUNDLE} doesn't match the manifest"
+        );
+        println!("Updating index to {}...", manifest.index_head);
+        let output = Command::new("git")
+            .args(["bundle", "list-heads", INDEX_BUNDLE])
+            .output()?;
+        output.status.exit_ok().context("invalid index bundle")?;
+        let heads = String::from_utf8(output.stdout)?;
+        let rev = heads
+            .lines()
+            .find_map(|line| line.strip_prefix(&format!("{} ", manifest.index_head)))
+            .context("index bundle doesn't have the commit of the manifest")?;
+        ingest::apply_bundle(INDEX_BUNDLE, rev)?;
+        remove_file(INDEX_BUNDLE)?;
+        store.index_updated();
+        next = entries.next().transpose()?;
+    }
+    let head = Index::head_commit()?;
+    ensure!(
+        head == manifest.index_head,
+        "index is at {head}, but the bundle is for {}",
+        manifest.index_head
+    );
+
+    println!("Loading index...");
+    let index = Index::read_cached()?;
+    let mut seen = HashSet::new();
+    let mut n = 0;
+    while let Some(mut entry) = next {
+        let path = entry
+            .path()?
+            .to_str()
+            .context("invalid utf-8 file name")?
+            .to_string();
+        let Some(bundle_cksum) = manifest.crates.get(&path) else {
+            bail!("{path:?} in bundle isn't in the manifest");
+        };
+        // Only accept exactly the file names that the index refers to.
+        let cksum = (|| {
+            let (name, file) = path.strip_prefix("crates/")?.split_once('/')?;
+            let version = file
+                .strip_prefix(name)?
+                .strip_prefix('-')?
+                .strip_suffix(".crate")?;
+            Some(&index.crates.get(name)?.get(version)?.cksum)
+        })()
+        .with_context(|| format!("{path:?} in bundle isn't in the index"))?;
+        ensure!(
+            cksum == bundle_cksum,
+            "checksum of {path:?} in the manifest doesn't match the index"
+        );
+        ensure!(seen.insert(path.clone()), "{path:?} is in the bundle twice");
+        if !Path::new(&path).exists() {
+            create_dir_all(Path::new(&path).parent().unwrap())?;
+            let partial_file = format!("{path}.partial");
+            let mut f = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&partial_file)?;
+            let mut hasher = Hasher::default();
+            store.copy(&mut entry, &mut f, Some(&mut hasher))?;
+            let hashes = match check_checksum(hasher, &path, cksum) {
+                Ok(hashes) => hashes,
+                Err(e) => {
+                    drop(f);
+                    let _ = remove_file(&partial_file);
+                    return Err(e);
+                }
+            };
+            drop(f);
+            store.commit(&partial_file, &path, &hashes)?;
+            n += 1;
+        }
+        next = entries.next().transpose()?;
+    }
+
+    ensure!(
+        seen.len() == manifest.crates.len(),
+        "bundle is missing {} of the crate files in its manifest",
+        manifest.crates.len() - seen.len()
+    );
+    println!(
+        "Imported {n} crate files ({} were already there)",
+        seen.len() - n
+    );
+    Ok(())
+}
+
+/// The crate files in the index that this mirror has, but the one with `state` doesn't.
+fn existing(state: &State) -> Result<BTreeMap<String, String>> {
+    println!("Loading index...");
+    let index = Index::read_cached()?;
+    let mut crates = BTreeMap::new();
+    for (name, versions) in &index.crates {
+        for (version, data) in versions {
+            let path = format!("crates/{name}/{name}-{version}.crate");
+            if !state.crates.contains(&path) && cold::exists(&path) {
+                crates.insert(path, data.cksum.clone());
+            }
+        }
+    }
+    Ok(crates)
+}
+
+/// The crate files (and their checksums) of the versions added to the index between two commits.
+fn published(from: &str, to: &str) -> Result<BTreeMap<String, String>> {
+    let mut crates = BTreeMap::new();
+    for file in git_output(&["diff", "--name-only", "--no-renames", from, to])?.lines() {
+        if file.starts_with('.') || file == "config.json" {
+            continue;
+        }
+        let old = versions_at(from, file)?;
+        for (version, line) in versions_at(to, file)? {
+            if !old.contains_key(&version) {
+                let name = &line.name;
+                crates.insert(format!("crates/{name}/{name}-{version}.crate"), line.cksum);
+            }
+        }
+    }
+    Ok(crates)
+}
+
+/// The versions in an index file at a commit, which are none if the file doesn't exist there.
+fn versions_at(commit: &str, file: &str) -> Result<HashMap<String, IndexLine>> {
+    let output = Command::new("git")
+        .args(["-C", "crates.io-index", "show", &format!("{commit}:{file}")])
+        .output()?;
+    if !output.status.success() {
+        return Ok(HashMap::new());
+    }
+    String::from_utf8(output.stdout)?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let line: IndexLine = serde_json::from_str(line)
+                .with_context(|| format!("unable to parse {file} at {commit}"))?;
+            Ok((line.vers.clone(), line))
+        })
+        .collect()
+}
+
+fn is_ancestor(ancestor: &str, commit: &str) -> bool {
+    Command::new("git")
+        .args(["-C", "crates.io-index", "merge-base", "--is-ancestor"])
+        .args([ancestor, commit])
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+fn sha256(mut reader: impl Read) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(base16ct::lower::encode_string(&hasher.finalize()))
+}
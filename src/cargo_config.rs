@@ -0,0 +1,183 @@
+//! The cargo configuration for using the mirror instead of crates.io, for `cargo-config`.
+//!
+//! This replaces the crates-io source with one named `cratesync`, which is
+//! either the sparse or git index that `serve` serves, or the local registry of
+//! `--layout local-registry`.
+//!
+//! It also reads the existing cargo configuration, for `cargo cratesync` to
+//! find out what a project uses instead of crates.io.
+
+use crate::local_registry;
+use anyhow::{bail, ensure, Context, Result};
+use std::{
+    env,
+    fs::{read_to_string, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+use toml::Table;
+
+/// The source that `cargo-config` adds.
+pub const SOURCE: &str = "cratesync";
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Mode {
+    /// The sparse index of `serve`, at {url}/index/.
+    Sparse,
+    /// The git index of `serve`, at {url}/git/index.
+    Git,
+    /// The local registry of `--layout local-registry`, without a server.
+    LocalRegistry,
+}
+
+/// The `[source]` tables that replace crates.io by the mirror.
+fn config(mode: Mode, url: Option<&str>, local_registry_dir: &Path) -> Result<String> {
+    let source = match mode {
+        Mode::Sparse | Mode::Git => {
+            let url = url
+                .context("--url is needed for the sparse and git modes")?
+                .trim_end_matches('/');
+            ensure!(
+                url.starts_with("http://") || url.starts_with("https://"),
+                "--url must be an http or https URL, like https://mirror.example.com"
+            );
+            match mode {
+                Mode::Sparse => format!("registry = \"sparse+{url}/index/\""),
+                _ => format!("registry = \"{url}/git/index\""),
+            }
+        }
+        Mode::LocalRegistry => {
+            let dir = std::path::absolute(local_registry_dir)?;
+            if !dir.join("index").exists() {
+                // Not on stdout, which might be redirected to a configuration file.
+                eprintln!(
+                    "warning: there is no local registry at {} yet, see `sync --layout local-registry`",
+                    dir.display()
+                );
+            }
+            let dir = dir.to_str().context("invalid utf-8 in path")?;
+            format!("local-registry = {}", toml::Value::from(dir))
+        }
+    };
+    Ok(format!(
+        "[source.crates-io]\n\
+         replace-with = \"{SOURCE}\"\n\
+         \n\
+         [source.{SOURCE}]\n\
+         {source}\n"
+    ))
+}
+
+/// Print the configuration, or add it to the cargo configuration file `write`.
+pub fn run(
+    mode: Mode,
+    url: Option<&str>,
+    local_registry_dir: Option<&Path>,
+    write: Option<&Path>,
+) -> Result<()> {
+    let config = config(
+        mode,
+        url,
+        local_registry_dir.unwrap_or(Path::new(local_registry::DIR)),
+    )?;
+    let Some(file) = write else {
+        print!("{config}");
+        return Ok(());
+    };
+    let existing = match read_to_string(file) {
+        Ok(s) => s,
+        Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("unable to read {}", file.display())),
+    };
+    let table: Table =
+        toml::from_str(&existing).with_context(|| format!("unable to parse {}", file.display()))?;
+    for name in ["crates-io", SOURCE] {
+        if table.get("source").and_then(|s| s.get(name)).is_some() {
+            bail!(
+                "{} already has a [source.{name}], remove it first",
+                file.display()
+            );
+        }
+    }
+    // Appended, rather than rewriting the file, to keep its formatting and comments.
+    let mut f = OpenOptions::new().create(true).append(true).open(file)?;
+    if !existing.is_empty() {
+        f.write_all(if existing.ends_with('\n') {
+            b"\n"
+        } else {
+            b"\n\n"
+        })?;
+    }
+    f.write_all(config.as_bytes())?;
+    println!("Added the source replacement to {}", file.display());
+    Ok(())
+}
+
+/// The cargo configuration file of `dir`, which is .cargo/config.toml,
+/// or .cargo/config if that older name is used there.
+pub fn file(dir: &Path) -> PathBuf {
+    file_in(&dir.join(".cargo"))
+}
+
+/// The config.toml (or config) in a .cargo directory or $CARGO_HOME.
+fn file_in(cargo_dir: &Path) -> PathBuf {
+    let old = cargo_dir.join("config");
+    if old.exists() {
+        old
+    } else {
+        cargo_dir.join("config.toml")
+    }
+}
+
+/// The cargo configuration files that apply in `dir`, most specific first, like cargo finds them:
+/// in `dir` and all its parents, and then in $CARGO_HOME.
+fn files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = dir.ancestors().map(file).collect();
+    let cargo_home = env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| Some(PathBuf::from(env::var_os("HOME")?).join(".cargo")));
+    if let Some(home) = cargo_home {
+        let config = file_in(&home);
+        // Unless it's one of the parents.
+        if !files.contains(&config) {
+            files.push(config);
+        }
+    }
+    files.retain(|f| f.exists());
+    files
+}
+
+/// What replaces crates.io in the cargo configuration that applies in `dir`, if anything.
+///
+/// This is the `[source]` (or `[registries]`) table at the end of the `replace-with` chain,
+/// and its name.
+pub fn crates_io_replacement(dir: &Path) -> Result<Option<(String, Table)>> {
+    // Like cargo, a table in a more specific file takes precedence.
+    let mut sources = Table::new();
+    let mut registries = Table::new();
+    for file in files(dir).into_iter().rev() {
+        let table: Table = toml::from_str(&read_to_string(&file)?)
+            .with_context(|| format!("unable to parse {}", file.display()))?;
+        for (tables, key) in [(&mut sources, "source"), (&mut registries, "registries")] {
+            if let Some(t) = table.get(key).and_then(|t| t.as_table()) {
+                tables.extend(t.clone());
+            }
+        }
+    }
+    let mut name = "crates-io";
+    let mut replacement = None;
+    for _ in 0..sources.len() {
+        let Some(next) = sources
+            .get(name)
+            .and_then(|s| s.get("replace-with"))
+            .and_then(|r| r.as_str())
+        else {
+            break;
+        };
+        name = next;
+        replacement = sources.get(name).or_else(|| registries.get(name));
+    }
+    Ok(replacement
+        .and_then(|t| t.as_table())
+        .map(|t| (name.to_string(), t.clone())))
+}
@@ -0,0 +1,238 @@
+//! The `cargo cratesync` subcommand, for mirroring the dependencies of the current project.
+//!
+//! ```text
+//! cargo cratesync sync /srv/crates --sparse-index
+//! cargo cratesync vendor vendor
+//! ```
+//!
+//! `sync` mirrors the crate versions in the Cargo.lock of the workspace
+//! (like `--lockfile`). `vendor` does the same with `--layout local-registry`,
+//! and adds the source replacement for that local registry to the cargo
+//! configuration of the workspace, such that the project builds without
+//! network access, like with `cargo vendor`.
+//!
+//! Any other options are passed on to cratesync, both those of cratesync
+//! itself (like `--sparse-index`) and those of `sync` (like `--watch`).
+//!
+//! If the cargo configuration replaces crates.io by another git index, like
+//! that of a company mirror, that index is mirrored, unless `--index-url` is given.
+
+use crate::{cargo_config, local_registry, run_from, Args};
+use anyhow::{anyhow, ensure, Context, Result};
+use clap::{CommandFactory, Parser};
+use std::{
+    env,
+    ffi::OsString,
+    fs::{create_dir_all, read_to_string},
+    path::{Path, PathBuf},
+    process::Command,
+};
+use toml::Table;
+
+#[derive(Parser)]
+#[clap(name = "cargo", bin_name = "cargo")]
+enum Cargo {
+    /// Mirror the dependencies of the current cargo workspace with cratesync.
+    Cratesync {
+        /// The Cargo.toml of the project, instead of that of the current directory.
+        #[clap(long, value_name = "PATH")]
+        manifest_path: Option<PathBuf>,
+
+        #[clap(subcommand)]
+        command: Subcommand,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum Subcommand {
+    /// Sync the crate versions in the Cargo.lock of the workspace into a mirror.
+    #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+    Sync {
+        /// The mirror directory.
+        dir: PathBuf,
+
+        /// Options for cratesync and its `sync`.
+        #[clap(value_name = "OPTIONS", multiple_values = true)]
+        options: Vec<OsString>,
+    },
+
+    /// Sync the crate versions in the Cargo.lock of the workspace into a local registry,
+    /// and make the workspace use that instead of crates.io.
+    #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
+    Vendor {
+        /// The mirror directory, with the local registry in local-registry/.
+        dir: PathBuf,
+
+        /// Options for cratesync and its `sync`.
+        #[clap(value_name = "OPTIONS", multiple_values = true)]
+        options: Vec<OsString>,
+    },
+}
+
+/// Run `cargo cratesync` with the command line arguments of this process, like the binary does.
+pub fn run() -> Result<()> {
+    let Cargo::Cratesync {
+        manifest_path,
+        command,
+    } = Cargo::parse();
+
+    let workspace = workspace_root(manifest_path.as_deref())?;
+    let lockfile = workspace.join("Cargo.lock");
+    ensure!(
+        lockfile.exists(),
+        "{} has no Cargo.lock, run `cargo generate-lockfile` first",
+        workspace.display()
+    );
+
+    let (dir, options, vendor) = match command {
+        Subcommand::Sync { dir, options } => (dir, options, false),
+        Subcommand::Vendor { dir, options } => (dir, options, true),
+    };
+    let dir = std::path::absolute(dir)?;
+    let (mut global, sync) = split_options(&options);
+    if !global
+        .iter()
+        .any(|o| o.to_string_lossy().starts_with("--index-url"))
+    {
+        global.extend(
+            replaced_index_url()?
+                .into_iter()
+                .flat_map(|url| ["--index-url".into(), url.into()]),
+        );
+    }
+
+    let mut argv = vec![OsString::from("cratesync")];
+    argv.extend(global);
+    argv.push(dir.clone().into());
+    argv.push("sync".into());
+    argv.extend(sync);
+    argv.push("--lockfile".into());
+    argv.push(lockfile.into());
+    if vendor {
+        argv.push("--layout".into());
+        argv.push("local-registry".into());
+    }
+    run_from(argv)?;
+
+    if vendor {
+        let config = cargo_config::file(&workspace);
+        let configured = match read_to_string(&config) {
+            Ok(s) => {
+                let table: Table = toml::from_str(&s)
+                    .with_context(|| format!("unable to parse {}", config.display()))?;
+                table
+                    .get("source")
+                    .and_then(|s| s.get(cargo_config::SOURCE))
+                    .is_some()
+            }
+            Err(_) => false,
+        };
+        if configured {
+            println!(
+                "{} already has a [source.{}]",
+                config.display(),
+                cargo_config::SOURCE
+            );
+        } else {
+            create_dir_all(workspace.join(".cargo"))?;
+            cargo_config::run(
+                cargo_config::Mode::LocalRegistry,
+                None,
+                Some(&dir.canonicalize()?.join(local_registry::DIR)),
+                Some(&config),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// The root directory of the workspace, as cargo finds it.
+fn workspace_root(manifest_path: Option<&Path>) -> Result<PathBuf> {
+    // Set by cargo when it runs a subcommand.
+    let mut cargo = Command::new(env::var_os("CARGO").unwrap_or_else(|| "cargo".into()));
+    cargo.args(["locate-project", "--workspace", "--message-format", "plain"]);
+    if let Some(path) = manifest_path {
+        cargo.arg("--manifest-path").arg(path);
+    }
+    let output = cargo.output().context("unable to run cargo")?;
+    output.status.exit_ok().map_err(|e| {
+        anyhow!(
+            "cargo locate-project: {e}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+    })?;
+    let manifest = PathBuf::from(String::from_utf8(output.stdout)?.trim());
+    Ok(manifest
+        .parent()
+        .context("workspace manifest has no parent directory")?
+        .to_path_buf())
+}
+
+/// The git index that replaces crates.io in the cargo configuration, if any, to mirror instead.
+///
+/// Like cargo, this uses the configuration of the current directory. The local registry added by
+/// `vendor` and other local sources don't count, as those can't be mirrored.
+fn replaced_index_url() -> Result<Option<String>> {
+    let Some((name, source)) = cargo_config::crates_io_replacement(&env::current_dir()?)? else {
+        return Ok(None);
+    };
+    if name == cargo_config::SOURCE {
+        return Ok(None);
+    }
+    let Some(url) = source
+        .get("registry")
+        .or_else(|| source.get("index"))
+        .and_then(|r| r.as_str())
+    else {
+        return Ok(None);
+    };
+    if url.starts_with("sparse+") {
+        println!(
+            "warning: crates.io is replaced by the sparse index {url} in the cargo configuration, \
+             but only git indexes can be mirrored, so crates.io is mirrored instead"
+        );
+        return Ok(None);
+    }
+    println!("Mirroring {url}, which replaces crates.io in the cargo configuration");
+    Ok(Some(url.to_string()))
+}
+
+/// Split `options` into those of cratesync itself, which go before the mirror directory,
+/// and those of `sync`, which go after it.
+fn split_options(options: &[OsString]) -> (Vec<OsString>, Vec<OsString>) {
+    let command = Args::command();
+    let mut global = Vec::new();
+    let mut sync = Vec::new();
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        let s = option.to_string_lossy();
+        // Like `--name value`, `--name=value`, `-n value` or `-nvalue`.
+        let (arg, has_value) = if let Some(long) = s.strip_prefix("--") {
+            let (name, value) = match long.split_once('=') {
+                Some((name, _)) => (name, true),
+                None => (long, false),
+            };
+            let arg = command.get_arguments().find(|a| a.get_long() == Some(name));
+            (arg, value)
+        } else if let Some(short) = s.strip_prefix('-') {
+            let mut chars = short.chars();
+            let name = chars.next();
+            let arg = command
+                .get_arguments()
+                .find(|a| name.is_some() && a.get_short() == name);
+            (arg, !chars.as_str().is_empty())
+        } else {
+            (None, false)
+        };
+        match arg {
+            Some(arg) => {
+                global.push(option.clone());
+                if arg.is_takes_value_set() && !has_value {
+                    global.extend(options.next().cloned());
+                }
+            }
+            None => sync.push(option.clone()),
+        }
+    }
+    (global, sync)
+}
@@ -0,0 +1,113 @@
+//! Storing every distinct crate file only once, with `--cas`.
+//!
+//! The crate files in crates/ are hard links to objects in the CAS directory,
+//! which are named after their SHA-256 as `{first two hex digits}/{sha256}`.
+//! Identical files, like the same crate in the mirrors of several registries,
+//! then take space only once.
+//!
+//! The link count of an object is its reference count: an object that only
+//! links to itself isn't used by any mirror anymore, and is removed by `gc`.
+
+use anyhow::{ensure, Result};
+use std::{
+    fs::{create_dir_all, hard_link, metadata, read_dir, remove_dir, remove_file, rename},
+    io::ErrorKind,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+pub struct Cas {
+    dir: PathBuf,
+}
+
+impl Cas {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    fn object(&self, sha256: &str) -> PathBuf {
+        self.dir.join(&sha256[..2]).join(sha256)
+    }
+
+    /// Make the (verified) crate `file` with this checksum a link to its object,
+    /// which is added if there is none yet.
+    ///
+    /// Returns whether there already was one, such that `file` no longer takes space of its own.
+    pub fn add(&self, file: &str, sha256: &str) -> Result<bool> {
+        let object = self.object(sha256);
+        let e = match hard_link(file, &object) {
+            Ok(()) => return Ok(false),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                create_dir_all(object.parent().unwrap())?;
+                match hard_link(file, &object) {
+                    Ok(()) => return Ok(false),
+                    Err(e) => e,
+                }
+            }
+            Err(e) => e,
+        };
+        if e.kind() != ErrorKind::AlreadyExists {
+            return Err(e.into());
+        }
+        let (file_meta, object_meta) = (metadata(file)?, metadata(&object)?);
+        if file_meta.dev() == object_meta.dev() && file_meta.ino() == object_meta.ino() {
+            return Ok(false);
+        }
+        ensure!(
+            file_meta.len() == object_meta.len(),
+            "{} has a different size than {file:?}, with the same checksum",
+            object.display()
+        );
+        let partial = format!("{file}.partial");
+        let _ = remove_file(&partial);
+        hard_link(&object, &partial)?;
+        rename(&partial, file)?;
+        Ok(true)
+    }
+
+    /// Whether `file` is a link to an object of the store.
+    pub fn contains(&self, file: &str, sha256: &str) -> Result<bool> {
+        let Ok(object_meta) = metadata(self.object(sha256)) else {
+            return Ok(false);
+        };
+        let file_meta = metadata(file)?;
+        Ok(file_meta.dev() == object_meta.dev() && file_meta.ino() == object_meta.ino())
+    }
+
+    /// The objects that no crate file links to anymore, and their size.
+    ///
+    /// With `remove`, they are removed, as well as the directories that become empty.
+    pub fn gc(&self, remove: bool) -> Result<(usize, u64)> {
+        let (mut n, mut bytes) = (0, 0);
+        if !self.dir.exists() {
+            return Ok((n, bytes));
+        }
+        for dir in read_dir(&self.dir)? {
+            let dir = dir?;
+            if !dir.file_type()?.is_dir() {
+                continue;
+            }
+            let mut empty = true;
+            for object in read_dir(dir.path())? {
+                let object = object?;
+                let meta = object.metadata()?;
+                if meta.nlink() == 1 {
+                    println!("{} (unused object)", object.path().display());
+                    n += 1;
+                    bytes += meta.len();
+                    if remove {
+                        remove_file(object.path())?;
+                        continue;
+                    }
+                }
+                empty = false;
+            }
+            if empty && remove {
+                remove_dir(dir.path())?;
+            }
+        }
+        Ok((n, bytes))
+    }
+}
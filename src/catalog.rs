@@ -0,0 +1,340 @@
+//! The metadata of every crate file in the mirror, in the SQLite database catalog.sqlite.
+//!
+//! `catalog` reads the Cargo.toml of the crate files that aren't in the
+//! catalog yet, and stores its package metadata (like the license, edition,
+//! rust-version and features) in the `crates` table, and its dependencies in
+//! the `dependencies` table. The features and other lists are stored as JSON.
+//! Crate files that were removed from the mirror are removed from the catalog.
+//!
+//! For example, to find the crates with a build script that depend on `cc`:
+//!
+//! ```sql
+//! SELECT c.name, c.version FROM crates c JOIN dependencies d USING (file)
+//! WHERE d.crate = 'cc' AND d.kind = 'build';
+//! ```
+
+use crate::{
+    cold,
+    filter::Filter,
+    shutdown,
+    state::{parse_file, State},
+};
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use rusqlite::{params, Connection, Transaction};
+use std::{
+    collections::HashSet,
+    io::Read,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        mpsc,
+    },
+    thread,
+};
+use toml::{Table, Value};
+
+pub const FILE: &str = "catalog.sqlite";
+
+/// The version of the schema, in `PRAGMA user_version`. Zero is a new database.
+const VERSION: u32 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE crates (
+        file TEXT PRIMARY KEY NOT NULL,
+        name TEXT NOT NULL,
+        version TEXT NOT NULL,
+        edition TEXT,
+        rust_version TEXT,
+        license TEXT,
+        license_file TEXT,
+        description TEXT,
+        homepage TEXT,
+        repository TEXT,
+        documentation TEXT,
+        links TEXT,
+        -- JSON arrays.
+        authors TEXT,
+        keywords TEXT,
+        categories TEXT,
+        -- A JSON object with the features and what they enable.
+        features TEXT,
+        -- Why the Cargo.toml couldn't be read, in which case the above are NULL.
+        error TEXT
+    ) WITHOUT ROWID;
+    CREATE INDEX crates_name ON crates (name);
+    CREATE TABLE dependencies (
+        -- The crate file of the dependent.
+        file TEXT NOT NULL,
+        -- The name of the crate that is depended on.
+        crate TEXT NOT NULL,
+        -- The name it is used under, if it's renamed.
+        rename TEXT,
+        req TEXT,
+        -- normal, build or dev.
+        kind TEXT NOT NULL,
+        target TEXT,
+        optional INTEGER NOT NULL,
+        default_features INTEGER NOT NULL,
+        -- A JSON array.
+        features TEXT,
+        registry TEXT
+    );
+    CREATE INDEX dependencies_file ON dependencies (file);
+    CREATE INDEX dependencies_crate ON dependencies (crate);
+";
+
+/// The dependency tables of a Cargo.toml, with their kind.
+const DEPENDENCY_TABLES: [(&str, &str); 5] = [
+    ("dependencies", "normal"),
+    ("build-dependencies", "build"),
+    ("build_dependencies", "build"),
+    ("dev-dependencies", "dev"),
+    ("dev_dependencies", "dev"),
+];
+
+/// The number of rows to insert in one transaction.
+const BATCH_SIZE: usize = 1000;
+
+struct Crate {
+    file: String,
+    manifest: Result<Table>,
+}
+
+/// Add the crate files (matching `filter`) that aren't in the catalog yet to it.
+///
+/// With `rebuild`, the catalog is emptied first, such that all files are read again.
+pub fn update(filter: &Filter, rebuild: bool) -> Result<()> {
+    let mut db = open()?;
+    if rebuild {
+        db.execute_batch("DELETE FROM crates; DELETE FROM dependencies;")?;
+    }
+
+    let present = State::open()?.present()?;
+    let known: HashSet<String> = {
+        let mut statement = db.prepare("SELECT file FROM crates")?;
+        let files = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        files
+    };
+
+    let tx = db.transaction()?;
+    let mut n_removed = 0;
+    for file in known.difference(&present) {
+        remove(&tx, file)?;
+        n_removed += 1;
+    }
+    tx.commit()?;
+    if n_removed > 0 {
+        println!("Removed {n_removed} crate files that are no longer in the mirror");
+    }
+
+    let mut queue: Vec<&String> = present
+        .iter()
+        .filter(|file| {
+            !known.contains(*file) && parse_file(file).is_some_and(|(name, _)| filter.matches(name))
+        })
+        .collect();
+    queue.sort_unstable();
+    println!("Reading {} crate files...", queue.len());
+
+    let next = AtomicUsize::new(0);
+    let n_threads = thread::available_parallelism().map_or(4, |n| n.get());
+    let (sender, receiver) = mpsc::sync_channel(BATCH_SIZE);
+    let (n_added, n_errors) = thread::scope(|s| -> Result<_> {
+        for _ in 0..n_threads {
+            let sender = sender.clone();
+            let (queue, next) = (&queue, &next);
+            s.spawn(move || {
+                while !shutdown::requested() {
+                    let Some(file) = queue.get(next.fetch_add(1, Relaxed)) else {
+                        break;
+                    };
+                    let manifest = read_manifest(file);
+                    let file = file.to_string();
+                    if sender.send(Crate { file, manifest }).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        let (mut n_added, mut n_errors) = (0, 0);
+        let mut tx = db.transaction()?;
+        for krate in receiver {
+            if let Err(e) = &krate.manifest {
+                println!("error: unable to read {:?}: {e:#}", krate.file);
+                n_errors += 1;
+            }
+            insert(&tx, &krate)?;
+            n_added += 1;
+            if n_added % BATCH_SIZE == 0 {
+                tx.commit()?;
+                tx = db.transaction()?;
+            }
+        }
+        tx.commit()?;
+        Ok((n_added, n_errors))
+    })?;
+
+    println!("Added {n_added} crate files to {FILE}");
+    if n_errors > 0 {
+        println!("{n_errors} of them have no readable Cargo.toml (see the error column)");
+    }
+    if n_added < queue.len() {
+        println!(
+            "Interrupted, {} crate files remain for the next run",
+            queue.len() - n_added
+        );
+    }
+    Ok(())
+}
+
+fn open() -> Result<Connection> {
+    let mut db = Connection::open(FILE).with_context(|| format!("unable to open {FILE}"))?;
+    db.pragma_update(None, "journal_mode", "WAL")?;
+    let tx = db.transaction()?;
+    let version: u32 = tx.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    match version {
+        0 => {
+            tx.execute_batch(SCHEMA)?;
+            tx.pragma_update(None, "user_version", VERSION)?;
+        }
+        VERSION => {}
+        _ => bail!("{FILE} is from a newer version of cratesync"),
+    }
+    tx.commit()?;
+    Ok(db)
+}
+
+/// The Cargo.toml of a crate file.
+fn read_manifest(file: &str) -> Result<Table> {
+    let (name, version) = parse_file(file).context("invalid crate file name")?;
+    let manifest_path = format!("{name}-{version}/Cargo.toml");
+    let mut archive = tar::Archive::new(GzDecoder::new(cold::open(file)?));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.to_str() == Some(&manifest_path) {
+            let mut manifest = String::new();
+            entry.read_to_string(&mut manifest)?;
+            return toml::from_str(&manifest).context("unable to parse Cargo.toml");
+        }
+    }
+    bail!("no Cargo.toml in crate file")
+}
+
+fn remove(tx: &Transaction, file: &str) -> Result<()> {
+    tx.execute("DELETE FROM crates WHERE file = ?1", [file])?;
+    tx.execute("DELETE FROM dependencies WHERE file = ?1", [file])?;
+    Ok(())
+}
+
+fn insert(tx: &Transaction, krate: &Crate) -> Result<()> {
+    let (name, version) = parse_file(&krate.file).unwrap();
+    let manifest = match &krate.manifest {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            tx.execute(
+                "INSERT OR REPLACE INTO crates (file, name, version, error) VALUES (?1, ?2, ?3, ?4)",
+                params![krate.file, name, version, format!("{e:#}")],
+            )?;
+            return Ok(());
+        }
+    };
+    let package = manifest
+        .get("package")
+        .or_else(|| manifest.get("project"))
+        .and_then(Value::as_table);
+    let string = |key: &str| {
+        package
+            .and_then(|p| p.get(key))
+            .and_then(Value::as_str)
+            .map(str::to_string)
+    };
+    let json = |value: Option<&Value>| value.map(serde_json::to_string).transpose();
+    tx.execute(
+        "INSERT OR REPLACE INTO crates (file, name, version, edition, rust_version, license,
+            license_file, description, homepage, repository, documentation, links, authors,
+            keywords, categories, features)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+        params![
+            krate.file,
+            name,
+            version,
+            string("edition"),
+            string("rust-version").or_else(|| string("rust_version")),
+            string("license"),
+            string("license-file").or_else(|| string("license_file")),
+            string("description"),
+            string("homepage"),
+            string("repository"),
+            string("documentation"),
+            string("links"),
+            json(package.and_then(|p| p.get("authors")))?,
+            json(package.and_then(|p| p.get("keywords")))?,
+            json(package.and_then(|p| p.get("categories")))?,
+            json(manifest.get("features"))?,
+        ],
+    )?;
+
+    tx.execute("DELETE FROM dependencies WHERE file = ?1", [&krate.file])?;
+    let targets = manifest.get("target").and_then(Value::as_table);
+    let tables = std::iter::once((None, manifest)).chain(
+        targets
+            .into_iter()
+            .flatten()
+            .filter_map(|(target, table)| Some((Some(target), table.as_table()?))),
+    );
+    for (target, table) in tables {
+        for (key, kind) in DEPENDENCY_TABLES {
+            let Some(deps) = table.get(key).and_then(Value::as_table) else {
+                continue;
+            };
+            for (dep_name, dep) in deps {
+                insert_dependency(tx, &krate.file, dep_name, dep, kind, target)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn insert_dependency(
+    tx: &Transaction,
+    file: &str,
+    name: &str,
+    dep: &Value,
+    kind: &str,
+    target: Option<&String>,
+) -> Result<()> {
+    let table = dep.as_table();
+    let get = |key: &str| table.and_then(|t| t.get(key));
+    let string = |key: &str| get(key).and_then(Value::as_str);
+    let package = string("package");
+    let req = match dep {
+        Value::String(req) => Some(req.as_str()),
+        _ => string("version"),
+    };
+    let default_features = get("default-features")
+        .or_else(|| get("default_features"))
+        .and_then(Value::as_bool)
+        .unwrap_or(true);
+    tx.execute(
+        "INSERT INTO dependencies (file, crate, rename, req, kind, target, optional,
+            default_features, features, registry)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+        params![
+            file,
+            package.unwrap_or(name),
+            package.map(|_| name),
+            req,
+            kind,
+            target,
+            get("optional").and_then(Value::as_bool).unwrap_or(false),
+            default_features,
+            get("features").map(serde_json::to_string).transpose()?,
+            string("registry").or_else(|| string("registry-index")),
+        ],
+    )?;
+    Ok(())
+}
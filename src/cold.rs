@@ -0,0 +1,435 @@
+//! Storing crate files compressed with zstd instead of gzip, with `cold`.
+//!
+//! A crate file is a tar archive compressed with gzip. `cold` stores the
+//! archive compressed with zstd instead, as `{file}.zst`, which takes less
+//! space. Everything that reads crate files (`serve`, `verify`, the exports)
+//! compresses it with gzip again, which gives exactly the original file, with
+//! the same SHA-256.
+//!
+//! That only works if the archive is compressed exactly like cargo did when the
+//! crate was published. Cargo has used zlib, and newer versions use zlib-rs,
+//! both at the best compression level, which covers nearly all crate files.
+//! Files that can't be reproduced (or wouldn't get smaller) are left alone.
+//!
+//! A `.zst` file starts with a zstd skippable frame with what is needed to
+//! restore the gzip file (which of the two was used, the size of the original
+//! file, its SHA-256, and its gzip header), followed by the archive as a normal
+//! zstd frame. A restored file is checked against that SHA-256, such that a
+//! damaged `.zst` file is never served as the original.
+
+use crate::{cas::Cas, filter::Filter, immutable, index::Index, shutdown};
+use anyhow::{bail, ensure, Context, Result};
+use flate2::{read::GzDecoder, write::DeflateEncoder, Compression, Crc};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs::{metadata, read, remove_file, rename, write, File},
+    io::{Cursor, ErrorKind, Read, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed},
+        Mutex,
+    },
+    thread,
+    time::{Duration, UNIX_EPOCH},
+};
+
+/// The suffix of a crate file that is stored with zstd.
+pub const SUFFIX: &str = ".crate.zst";
+
+/// The magic number of the first zstd skippable frame.
+const SKIPPABLE_FRAME: u32 = 0x184D2A50;
+
+/// How the archive in a crate file was compressed.
+#[derive(Clone, Copy)]
+enum Deflate {
+    Zlib = 1,
+    ZlibRs = 2,
+}
+
+impl Deflate {
+    /// The ones to try, the most common (for recent crates) first.
+    const ALL: [Deflate; 2] = [Deflate::ZlibRs, Deflate::Zlib];
+
+    fn from_u8(n: u8) -> Option<Self> {
+        Self::ALL.into_iter().find(|d| *d as u8 == n)
+    }
+
+    /// The raw deflate stream of `data`.
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Deflate::Zlib => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), Compression::best());
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+            Deflate::ZlibRs => {
+                let mut output = vec![0; zlib_rs::compress_bound(data.len())];
+                let config = zlib_rs::DeflateConfig {
+                    // Negative for a raw deflate stream, without zlib header.
+                    window_bits: -15,
+                    ..zlib_rs::DeflateConfig::best_compression()
+                };
+                let (compressed, code) = zlib_rs::compress_slice(&mut output, data, config);
+                ensure!(code == zlib_rs::ReturnCode::Ok, "zlib-rs: {code:?}");
+                let n = compressed.len();
+                output.truncate(n);
+                Ok(output)
+            }
+        }
+    }
+}
+
+/// The path of the zstd file of the crate `file`.
+pub fn path(file: &str) -> String {
+    format!("{file}.zst")
+}
+
+/// Whether the mirror has the crate `file`, either as is or stored with zstd.
+pub fn exists(file: &str) -> bool {
+    Path::new(file).exists() || Path::new(&path(file)).exists()
+}
+
+/// A crate file opened with [`open`].
+pub enum CrateFile {
+    File(File),
+    /// Restored from its zstd file.
+    Restored(Cursor<Vec<u8>>),
+}
+
+impl CrateFile {
+    pub fn size(&self) -> Result<u64> {
+        Ok(match self {
+            CrateFile::File(f) => f.metadata()?.len(),
+            CrateFile::Restored(data) => data.get_ref().len() as u64,
+        })
+    }
+}
+
+impl Read for CrateFile {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            CrateFile::File(f) => f.read(buf),
+            CrateFile::Restored(data) => data.read(buf),
+        }
+    }
+}
+
+/// Open the crate `file`, restoring it if it's stored with zstd.
+///
+/// If neither exists, this fails with the [`std::io::Error`] of opening `file`.
+pub fn open(file: &str) -> Result<CrateFile> {
+    match File::open(file) {
+        Ok(f) => Ok(CrateFile::File(f)),
+        Err(e) if e.kind() == ErrorKind::NotFound => match read(path(file)) {
+            Ok(data) => Ok(CrateFile::Restored(Cursor::new(
+                restore(&data).with_context(|| format!("unable to restore {file:?}"))?,
+            ))),
+            Err(e2) if e2.kind() == ErrorKind::NotFound => Err(e.into()),
+            Err(e2) => Err(e2.into()),
+        },
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The size of the crate `file`, without restoring it if it's stored with zstd.
+pub fn size(file: &str) -> Result<u64> {
+    match metadata(file) {
+        Ok(m) => Ok(m.len()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let mut info = [0; 17];
+            File::open(path(file))?.read_exact(&mut info)?;
+            ensure!(
+                info[..4] == SKIPPABLE_FRAME.to_le_bytes(),
+                "{file:?}.zst is not a cratesync zstd file"
+            );
+            Ok(u64::from_le_bytes(info[9..].try_into().unwrap()))
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Add the crate `file` to a tar archive, restoring it if it's stored with zstd.
+pub fn append(builder: &mut tar::Builder<impl Write>, file: &str) -> Result<()> {
+    match open(file)? {
+        CrateFile::File(mut f) => builder.append_file(file, &mut f)?,
+        CrateFile::Restored(data) => {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.get_ref().len() as u64);
+            header.set_mode(0o644);
+            let modified = metadata(path(file))?.modified()?;
+            header.set_mtime(modified.duration_since(UNIX_EPOCH)?.as_secs());
+            header.set_cksum();
+            builder.append_data(&mut header, file, data)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read the crate `file`, restoring it if it's stored with zstd.
+pub fn read_file(file: &str) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    open(file)?.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// The length of the gzip header at the start of `data`.
+fn header_len(data: &[u8]) -> Option<usize> {
+    if data.get(..3)? != [0x1f, 0x8b, 8] {
+        return None;
+    }
+    let flags = data[3];
+    let mut len = 10;
+    if flags & 4 != 0 {
+        // FEXTRA
+        len += 2 + u16::from_le_bytes(data.get(len..len + 2)?.try_into().unwrap()) as usize;
+    }
+    for flag in [8, 16] {
+        // FNAME and FCOMMENT, zero terminated.
+        if flags & flag != 0 {
+            len += data.get(len..)?.iter().position(|&b| b == 0)? + 1;
+        }
+    }
+    if flags & 2 != 0 {
+        // FHCRC
+        len += 2;
+    }
+    (len <= data.len()).then_some(len)
+}
+
+/// The gzip file with this `header`, with `archive` compressed with `deflate`.
+fn gzip(header: &[u8], deflate: Deflate, archive: &[u8]) -> Result<Vec<u8>> {
+    let mut data = header.to_vec();
+    data.extend(deflate.compress(archive)?);
+    let mut crc = Crc::new();
+    crc.update(archive);
+    data.extend(crc.sum().to_le_bytes());
+    data.extend((archive.len() as u32).to_le_bytes());
+    Ok(data)
+}
+
+/// The zstd file for the crate file `data`, if it can be restored exactly.
+fn compress(data: &[u8], level: i32) -> Result<Option<Vec<u8>>> {
+    let Some(header_len) = header_len(data) else {
+        return Ok(None);
+    };
+    let mut archive = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut archive)?;
+    let mut found = None;
+    for deflate in Deflate::ALL {
+        if gzip(&data[..header_len], deflate, &archive)? == data {
+            found = Some(deflate);
+            break;
+        }
+    }
+    let Some(deflate) = found else {
+        return Ok(None);
+    };
+    let mut zst = Vec::new();
+    zst.extend(SKIPPABLE_FRAME.to_le_bytes());
+    zst.extend((header_len as u32 + 41).to_le_bytes());
+    zst.push(deflate as u8);
+    zst.extend((data.len() as u64).to_le_bytes());
+    zst.extend(Sha256::digest(data));
+    zst.extend(&data[..header_len]);
+    let frame = zstd::encode_all(&archive[..], level)?;
+    ensure!(
+        zstd::decode_all(&frame[..])? == archive,
+        "zstd round trip failed"
+    );
+    zst.extend(frame);
+    Ok(Some(zst))
+}
+
+/// The original crate file of the zstd file `data`.
+fn restore(data: &[u8]) -> Result<Vec<u8>> {
+    let word = |i: usize| -> Option<u32> {
+        Some(u32::from_le_bytes(data.get(i..i + 4)?.try_into().unwrap()))
+    };
+    ensure!(
+        word(0) == Some(SKIPPABLE_FRAME),
+        "not a cratesync zstd file"
+    );
+    let len = word(4).context("truncated")? as usize;
+    let info = data.get(8..8 + len).context("truncated")?;
+    let (&deflate, info) = info.split_first().context("truncated")?;
+    let deflate = Deflate::from_u8(deflate).context("unknown compression")?;
+    let sha256 = info.get(8..40).context("truncated")?;
+    let header = info.get(40..).context("truncated")?;
+    let archive = zstd::decode_all(&data[8 + len..])?;
+    let restored = gzip(header, deflate, &archive)?;
+    ensure!(
+        Sha256::digest(&restored)[..] == *sha256,
+        "checksum of the restored file doesn't match"
+    );
+    Ok(restored)
+}
+
+/// What happened to a crate file.
+enum Outcome {
+    /// Stored the other way, with its sizes before and after.
+    Done(u64, u64),
+    /// Left as is, for this reason.
+    Skipped(&'static str),
+}
+
+/// Store the crate files (matching `filter`) with zstd, or, with `undo`, as gzip again.
+///
+/// The `requested` files (recently downloaded from `serve`) are skipped.
+/// Files are checked against the index first, such that only valid files are stored this way.
+pub fn run(
+    index: &Index,
+    filter: &Filter,
+    requested: &HashMap<String, Duration>,
+    level: i32,
+    undo: bool,
+    immutable_files: bool,
+    cas: Option<&Cas>,
+) -> Result<()> {
+    let mut queue = Vec::new();
+    for (name, versions) in &index.crates {
+        if !filter.matches(name) {
+            continue;
+        }
+        for (version, data) in versions {
+            let file = format!("crates/{name}/{name}-{version}.crate");
+            let from = if undo { path(&file) } else { file.clone() };
+            if !requested.contains_key(&file) && Path::new(&from).exists() {
+                queue.push((file, data.cksum.as_str()));
+            }
+        }
+    }
+    queue.sort_unstable();
+    println!(
+        "{} {} crate files...",
+        if undo { "Restoring" } else { "Compressing" },
+        queue.len()
+    );
+
+    let next = AtomicUsize::new(0);
+    let (n_done, n_errors) = (AtomicUsize::new(0), AtomicUsize::new(0));
+    let (bytes_before, bytes_after) = (AtomicU64::new(0), AtomicU64::new(0));
+    let skipped = Mutex::new(Vec::new());
+    let n_threads = thread::available_parallelism().map_or(4, |n| n.get());
+    thread::scope(|s| {
+        for _ in 0..n_threads {
+            s.spawn(|| {
+                while !shutdown::requested() {
+                    let Some((file, cksum)) = queue.get(next.fetch_add(1, Relaxed)) else {
+                        break;
+                    };
+                    let result = if undo {
+                        restore_file(file, cksum, immutable_files, cas)
+                    } else {
+                        compress_file(file, cksum, level, immutable_files)
+                    };
+                    match result {
+                        Ok(Outcome::Done(before, after)) => {
+                            n_done.fetch_add(1, Relaxed);
+                            bytes_before.fetch_add(before, Relaxed);
+                            bytes_after.fetch_add(after, Relaxed);
+                        }
+                        Ok(Outcome::Skipped(reason)) => {
+                            skipped.lock().unwrap().push((file.clone(), reason));
+                        }
+                        Err(e) => {
+                            println!("error: {file:?}: {e:#}");
+                            n_errors.fetch_add(1, Relaxed);
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    let (before, after) = (bytes_before.into_inner(), bytes_after.into_inner());
+    let n_done = n_done.into_inner();
+    if undo {
+        println!(
+            "Restored {n_done} crate files ({} MiB to {} MiB)",
+            before >> 20,
+            after >> 20
+        );
+    } else {
+        println!(
+            "Compressed {n_done} crate files ({} MiB to {} MiB, saving {:.1}%)",
+            before >> 20,
+            after >> 20,
+            match before {
+                0 => 0.0,
+                _ => 100.0 - after as f64 * 100.0 / before as f64,
+            }
+        );
+        let mut skipped = skipped.into_inner().unwrap();
+        skipped.sort_unstable();
+        for (file, reason) in &skipped {
+            println!("{file} ({reason}, left as is)");
+        }
+        if !skipped.is_empty() {
+            println!("Left {} crate files as is", skipped.len());
+        }
+    }
+    let n_errors = n_errors.into_inner();
+    if next.into_inner() < queue.len() && shutdown::requested() {
+        println!("Interrupted, the remaining crate files are left for the next run");
+    }
+    if n_errors > 0 {
+        bail!("{n_errors} crate files failed");
+    }
+    Ok(())
+}
+
+/// Store `file` with zstd, if it can be restored exactly and gets smaller.
+fn compress_file(file: &str, cksum: &str, level: i32, immutable_files: bool) -> Result<Outcome> {
+    let data = read(file)?;
+    let actual = base16ct::lower::encode_string(&Sha256::digest(&data));
+    ensure!(
+        actual == cksum,
+        "checksum {actual} doesn't match the index ({cksum}), see `verify`"
+    );
+    let Some(zst) = compress(&data, level)? else {
+        return Ok(Outcome::Skipped("can't be reproduced"));
+    };
+    if zst.len() >= data.len() {
+        return Ok(Outcome::Skipped("wouldn't be smaller"));
+    }
+    let zst_file = path(file);
+    let partial = format!("{zst_file}.partial");
+    write(&partial, &zst)?;
+    rename(&partial, &zst_file)?;
+    if immutable_files {
+        immutable::set(&zst_file)?;
+    }
+    immutable::clear(file)?;
+    remove_file(file)?;
+    Ok(Outcome::Done(data.len() as u64, zst.len() as u64))
+}
+
+/// Store `file` as gzip again.
+fn restore_file(
+    file: &str,
+    cksum: &str,
+    immutable_files: bool,
+    cas: Option<&Cas>,
+) -> Result<Outcome> {
+    let zst_file = path(file);
+    let zst = read(&zst_file)?;
+    let data = restore(&zst)?;
+    let actual = base16ct::lower::encode_string(&Sha256::digest(&data));
+    ensure!(
+        actual == cksum,
+        "checksum {actual} of the restored file doesn't match the index ({cksum})"
+    );
+    let partial = format!("{file}.partial");
+    write(&partial, &data)?;
+    rename(&partial, file)?;
+    if let Some(cas) = cas {
+        cas.add(file, cksum)?;
+    }
+    if immutable_files {
+        immutable::set(file)?;
+    }
+    immutable::clear(&zst_file)?;
+    remove_file(&zst_file)?;
+    Ok(Outcome::Done(zst.len() as u64, data.len() as u64))
+}
@@ -0,0 +1,232 @@
+//! Reading options from cratesync.toml, for running from systemd or cron without a dozen flags.
+//!
+//! The file is read from the mirror directory, or from `--config`. Its keys are
+//! the long command line options without the dashes, and the options of a
+//! subcommand go in a table named after it. The `sync` table also applies to
+//! `watch`, `retry-errors` and `recheck-forbidden`. For example:
+//!
+//! ```toml
+//! connections = 50
+//! exclude = ["windows-*"]
+//!
+//! [sync]
+//! skip-yanked = true
+//! max-msrv = "1.70"
+//!
+//! [serve]
+//! listen = "127.0.0.1:8080"
+//! ```
+//!
+//! Options given on the command line take precedence over the file.
+//! Options that can be given multiple times are combined.
+//!
+//! To mirror several registries, give each a `registries` table with the
+//! (global) options that differ, like:
+//!
+//! ```toml
+//! [registries.crates-io]
+//!
+//! [registries.internal]
+//! index-url = "https://git.example.com/cargo-index"
+//! token-command = "vault read -field=token secret/cargo"
+//! ```
+//!
+//! Every registry is then mirrored into a subdirectory with its name, one
+//! after the other, such that they share the connections and bandwidth.
+
+use crate::Args;
+use anyhow::{bail, ensure, Context, Result};
+use clap::{CommandFactory, FromArgMatches};
+use std::{
+    ffi::OsString,
+    fs::read_to_string,
+    path::{Path, PathBuf},
+};
+use toml::{Table, Value};
+
+pub const FILE: &str = "cratesync.toml";
+
+/// Options that apply to the whole process, so can't differ per registry.
+const PROCESS_OPTIONS: [&str; 7] = [
+    "proxy",
+    "ca-file",
+    "output",
+    "idle",
+    "metrics-listen",
+    "config",
+    "dir",
+];
+
+/// Parse the command line `argv`, with the options from the configuration file (if any) added.
+///
+/// Also returns the arguments for every registry in the file, if any.
+pub fn parse(argv: Vec<OsString>) -> Result<(Args, Vec<(String, Args)>)> {
+    // Such that options from the command line can override those from the file.
+    let command = Args::command().args_override_self(true);
+    let strict = command.clone().try_get_matches_from(&argv);
+    // Required options might be in the file, so first only find the file and the subcommand.
+    let matches = command.clone().ignore_errors(true).get_matches_from(&argv);
+
+    let path = match matches.value_of("config") {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let path = Path::new(matches.value_of("dir").unwrap_or_default()).join(FILE);
+            if !path.exists() {
+                let matches = strict.unwrap_or_else(|e| e.exit());
+                return Ok((Args::from_arg_matches(&matches)?, Vec::new()));
+            }
+            path
+        }
+    };
+    let config: Table = toml::from_str(
+        &read_to_string(&path).with_context(|| format!("unable to read {}", path.display()))?,
+    )
+    .with_context(|| format!("unable to parse {}", path.display()))?;
+
+    let mut global = Vec::new();
+    let mut tables = Vec::new();
+    let mut registries = Vec::new();
+    for (key, value) in &config {
+        match value {
+            Value::Table(table) if key == "registries" => {
+                for (name, value) in table {
+                    let registry = registry_options(name, value).with_context(|| {
+                        format!("{}: invalid registry {name:?}", path.display())
+                    })?;
+                    registries.push((name.clone(), registry));
+                }
+            }
+            Value::Table(table) => {
+                if command.find_subcommand(key).is_none() {
+                    bail!("{}: no subcommand named {key:?}", path.display());
+                }
+                tables.push((key.as_str(), table));
+            }
+            value => add_option(&mut global, key, value)
+                .with_context(|| format!("{}: invalid value for {key:?}", path.display()))?,
+        }
+    }
+
+    let subcommand = matches.subcommand_name();
+    let mut local = Vec::new();
+    for (name, table) in tables {
+        let applies = match subcommand {
+            // Without a subcommand, this runs `sync`.
+            None => name == "sync",
+            Some(sub @ ("watch" | "retry-errors" | "recheck-forbidden" | "doctor")) => {
+                name == sub || name == "sync"
+            }
+            Some(sub) => name == sub,
+        };
+        if !applies {
+            continue;
+        }
+        for (key, value) in table {
+            add_option(&mut local, key, value)
+                .with_context(|| format!("{}: invalid value for {name}.{key}", path.display()))?;
+        }
+    }
+
+    // Put the options from the file before those on the command line, at the same level.
+    let mut new_argv = vec![argv[0].clone()];
+    new_argv.extend(global);
+    let n_global = new_argv.len();
+    match subcommand {
+        Some(name) => {
+            let dir_index = matches.index_of("dir").unwrap_or(0);
+            // The subcommand might be given by one of its aliases, like `retry`.
+            let sub = command
+                .find_subcommand(name)
+                .with_context(|| format!("unknown subcommand {name}"))?;
+            let sub_index = (dir_index + 1..argv.len())
+                .find(|&i| {
+                    argv[i].to_str().is_some_and(|arg| {
+                        arg == sub.get_name() || sub.get_all_aliases().any(|alias| alias == arg)
+                    })
+                })
+                .with_context(|| format!("unable to find subcommand {name} in the arguments"))?;
+            new_argv.extend_from_slice(&argv[1..=sub_index]);
+            new_argv.extend(local);
+            new_argv.extend_from_slice(&argv[sub_index + 1..]);
+        }
+        None => {
+            new_argv.extend_from_slice(&argv[1..]);
+            if !local.is_empty() {
+                new_argv.push("sync".into());
+                new_argv.extend(local);
+            }
+        }
+    }
+
+    let matches = command
+        .clone()
+        .try_get_matches_from(&new_argv)
+        .unwrap_or_else(|e| {
+            if strict.is_ok() {
+                eprintln!("In {}:", path.display());
+            }
+            e.exit()
+        });
+    let args = Args::from_arg_matches(&matches)?;
+
+    let registries = registries
+        .into_iter()
+        .map(|(name, options)| {
+            // After the global options of the file, before those of the command line.
+            let mut argv = new_argv[..n_global].to_vec();
+            argv.extend(options);
+            argv.extend_from_slice(&new_argv[n_global..]);
+            let matches = command
+                .clone()
+                .try_get_matches_from(argv)
+                .unwrap_or_else(|e| {
+                    eprintln!("In [registries.{name}] of {}:", path.display());
+                    e.exit()
+                });
+            let mut args = Args::from_arg_matches(&matches)?;
+            args.dir.push(&name);
+            Ok((name, args))
+        })
+        .collect::<Result<_>>()?;
+
+    Ok((args, registries))
+}
+
+/// The command line arguments for the options of a registry.
+fn registry_options(name: &str, value: &Value) -> Result<Vec<OsString>> {
+    ensure!(
+        !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\']),
+        "the name must be usable as a directory name"
+    );
+    let Value::Table(table) = value else {
+        bail!("expected a table");
+    };
+    let mut argv = Vec::new();
+    for (key, value) in table {
+        let key = key.replace('_', "-");
+        ensure!(
+            !PROCESS_OPTIONS.contains(&key.as_str()),
+            "{key} applies to all registries, so can't be set for one"
+        );
+        add_option(&mut argv, &key, value).with_context(|| format!("invalid value for {key:?}"))?;
+    }
+    Ok(argv)
+}
+
+/// Add the command line arguments for option `key` with this `value`.
+fn add_option(argv: &mut Vec<OsString>, key: &str, value: &Value) -> Result<()> {
+    let flag = format!("--{}", key.replace('_', "-"));
+    match value {
+        Value::Boolean(true) => argv.push(flag.into()),
+        Value::Boolean(false) => {}
+        Value::String(s) => argv.extend([flag.into(), s.into()]),
+        Value::Integer(_) | Value::Float(_) => argv.extend([flag.into(), value.to_string().into()]),
+        Value::Array(values) => {
+            for value in values {
+                add_option(argv, key, value)?;
+            }
+        }
+        _ => bail!("expected a string, number, boolean, or array"),
+    }
+    Ok(())
+}
@@ -0,0 +1,297 @@
+//! The crates.io database dump.
+//!
+//! See <https://crates.io/data-access#database-dumps>.
+//!
+//! `cratesync db-dump` keeps older dumps in db-dumps/, named after the
+//! directory in the archive, which is the time the dump was made.
+
+use anyhow::{ensure, Context, Result};
+use flate2::read::GzDecoder;
+use reqwest::{
+    blocking::{RequestBuilder, Response},
+    header::{HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{
+        copy, create_dir_all, hard_link, read_dir, read_to_string, remove_file, rename, write, File,
+    },
+    io,
+    path::Path,
+};
+
+pub const URL: &str = "https://static.crates.io/db-dump.tar.gz";
+pub const FILE: &str = "db-dump.tar.gz";
+/// The directory with the older dumps.
+const HISTORY_DIR: &str = "db-dumps";
+
+#[derive(Default)]
+pub struct DbDump {
+    /// name -> version -> info
+    versions: HashMap<String, HashMap<String, VersionInfo>>,
+    /// name -> total downloads of all versions
+    downloads: HashMap<String, u64>,
+    /// name -> description, for the crates that have one
+    descriptions: HashMap<String, String>,
+}
+
+/// What the dump knows about a version.
+pub struct VersionInfo {
+    pub checksum: String,
+    pub downloads: u64,
+    /// The size of the crate file, which isn't known for some old versions.
+    pub crate_size: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct CrateRow {
+    id: u64,
+    name: String,
+    downloads: u64,
+    description: String,
+}
+
+#[derive(Deserialize)]
+struct VersionRow {
+    crate_id: u64,
+    num: String,
+    checksum: String,
+    downloads: u64,
+    crate_size: Option<u64>,
+}
+
+impl DbDump {
+    /// Read the relevant tables from the dump.
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut names = HashMap::new();
+        let mut downloads = HashMap::new();
+        let mut descriptions = HashMap::new();
+        let mut versions = Vec::new();
+        let mut archive = tar::Archive::new(GzDecoder::new(File::open(path)?));
+        for entry in archive.entries()? {
+            let entry = entry?;
+            let entry_path = entry.path()?.into_owned();
+            if entry_path.ends_with("data/crates.csv") {
+                for row in csv::Reader::from_reader(entry).into_deserialize() {
+                    let row: CrateRow = row.with_context(|| format!("unable to parse {path:?}"))?;
+                    downloads.insert(row.name.clone(), row.downloads);
+                    if !row.description.is_empty() {
+                        descriptions.insert(row.name.clone(), row.description);
+                    }
+                    names.insert(row.id, row.name);
+                }
+            } else if entry_path.ends_with("data/versions.csv") {
+                for row in csv::Reader::from_reader(entry).into_deserialize() {
+                    let row: VersionRow =
+                        row.with_context(|| format!("unable to parse {path:?}"))?;
+                    versions.push(row);
+                }
+            }
+        }
+
+        let mut dump = DbDump {
+            downloads,
+            descriptions,
+            ..DbDump::default()
+        };
+        for v in versions {
+            let name = names
+                .get(&v.crate_id)
+                .with_context(|| format!("unknown crate id {} in db dump", v.crate_id))?;
+            let info = VersionInfo {
+                checksum: v.checksum,
+                downloads: v.downloads,
+                crate_size: v.crate_size,
+            };
+            dump.versions
+                .entry(name.clone())
+                .or_default()
+                .insert(v.num, info);
+        }
+        Ok(dump)
+    }
+
+    /// The names of all crates with at least one version.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.versions.keys().map(|n| n.as_str())
+    }
+
+    /// The total number of downloads of a crate, if the dump knows about it.
+    pub fn downloads(&self, name: &str) -> Option<u64> {
+        self.downloads.get(name).copied()
+    }
+
+    /// The description of a crate, if it has one.
+    pub fn description(&self, name: &str) -> Option<&str> {
+        self.descriptions.get(name).map(|d| d.as_str())
+    }
+
+    /// What the dump knows about a version, if anything.
+    pub fn version(&self, name: &str, version: &str) -> Option<&VersionInfo> {
+        self.versions.get(name)?.get(version)
+    }
+
+    /// The checksum of a crate file, if the dump knows about it.
+    pub fn checksum(&self, name: &str, version: &str) -> Option<&str> {
+        Some(&self.version(name, version)?.checksum)
+    }
+}
+
+/// The validators of a previously downloaded file, for conditional requests.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl Validators {
+    pub fn of(response: &Response) -> Self {
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .map(String::from)
+        };
+        Self {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+
+    /// Make `request` conditional, such that it results in 304 Not Modified if nothing changed.
+    pub fn apply(&self, mut request: RequestBuilder) -> RequestBuilder {
+        if let Some(etag) = &self.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &self.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        request
+    }
+}
+
+/// Update the dump for `cratesync db-dump`, and keep the last `keep` ones in db-dumps/.
+pub fn sync(client: &reqwest::blocking::Client, keep: usize) -> Result<()> {
+    println!("Updating db dump...");
+    let mut name = None;
+    let changed = fetch_if_changed_verified(client, URL, FILE, |path| {
+        name = Some(check(path)?);
+        Ok(())
+    })?;
+    let name = match name {
+        Some(name) => name,
+        None => check(Path::new(FILE))?,
+    };
+    if changed {
+        println!("Downloaded db dump {name}");
+    } else {
+        println!("Db dump {name} is up to date");
+    }
+
+    if keep == 0 {
+        return Ok(());
+    }
+    create_dir_all(HISTORY_DIR)?;
+    let copy_path = Path::new(HISTORY_DIR).join(format!("{name}.tar.gz"));
+    if !copy_path.exists() {
+        let partial = copy_path.with_extension("partial");
+        if hard_link(FILE, &partial).is_err() {
+            copy(FILE, &partial)?;
+        }
+        rename(partial, copy_path)?;
+    }
+
+    let mut copies = read_dir(HISTORY_DIR)?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<io::Result<Vec<_>>>()?;
+    copies.retain(|name| name.ends_with(".tar.gz"));
+    // The names start with the date and time, so sort in chronological order.
+    copies.sort();
+    let n_remove = copies.len().saturating_sub(keep);
+    for name in &copies[..n_remove] {
+        println!("Removing old db dump {name}");
+        remove_file(Path::new(HISTORY_DIR).join(name))?;
+    }
+    Ok(())
+}
+
+/// Check that a dump is complete and has the tables we use, and return its name.
+fn check(path: &Path) -> Result<String> {
+    let mut name = None;
+    let mut tables = 0;
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(path)?));
+    for entry in archive
+        .entries()
+        .with_context(|| format!("corrupt db dump {path:?}"))?
+    {
+        let mut entry = entry.with_context(|| format!("corrupt db dump {path:?}"))?;
+        let entry_path = entry.path()?.into_owned();
+        if name.is_none() {
+            name = entry_path
+                .components()
+                .next()
+                .map(|c| c.as_os_str().to_string_lossy().into_owned());
+        }
+        if entry_path.ends_with("data/crates.csv") || entry_path.ends_with("data/versions.csv") {
+            tables += 1;
+        }
+        // Read everything, such that the gzip checksum at the end is checked.
+        io::copy(&mut entry, &mut io::sink())
+            .with_context(|| format!("corrupt db dump {path:?}"))?;
+    }
+    ensure!(
+        tables == 2,
+        "db dump {path:?} is missing crates.csv or versions.csv"
+    );
+    let name = name.context("empty db dump")?;
+    ensure!(
+        !name.is_empty() && !name.starts_with('.') && !name.contains(['/', '\\']),
+        "unexpected directory {name:?} in db dump"
+    );
+    Ok(name)
+}
+
+/// Download `url` to `file`, unless the server says our copy is still up to date.
+///
+/// Returns whether the file was (re)downloaded.
+pub fn fetch_if_changed(client: &reqwest::blocking::Client, url: &str, file: &str) -> Result<bool> {
+    fetch_if_changed_verified(client, url, file, |_| Ok(()))
+}
+
+/// Like [`fetch_if_changed`], but only replaces `file` if `verify` accepts the new download.
+fn fetch_if_changed_verified(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    file: &str,
+    verify: impl FnOnce(&Path) -> Result<()>,
+) -> Result<bool> {
+    let validators_file = format!("{file}.validators");
+    let old: Validators = if Path::new(file).exists() {
+        read_to_string(&validators_file)
+            .ok()
+            .and_then(|v| serde_json::from_str(&v).ok())
+            .unwrap_or_default()
+    } else {
+        Validators::default()
+    };
+
+    let response = old.apply(client.get(url)).send()?;
+    if response.status() == StatusCode::NOT_MODIFIED {
+        return Ok(false);
+    }
+    let mut response = response.error_for_status()?;
+    let new = Validators::of(&response);
+    let partial_file = format!("{file}.partial");
+    response.copy_to(&mut File::create(&partial_file)?)?;
+    if let Err(e) = verify(Path::new(&partial_file)) {
+        let _ = remove_file(&partial_file);
+        return Err(e);
+    }
+    rename(partial_file, file)?;
+    write(validators_file, serde_json::to_string(&new)?)?;
+    Ok(true)
+}
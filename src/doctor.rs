@@ -0,0 +1,224 @@
+//! Checking for common problems before a long sync.
+
+use crate::{cold, http_client, index::Index, registry::Registry, sparse_index, Args, SyncArgs};
+use anyhow::{anyhow, bail, Context, Result};
+use reqwest::{header::DATE, Url};
+use std::{
+    ffi::CString,
+    fs::{remove_file, write},
+    mem::MaybeUninit,
+    path::Path,
+    process::Command,
+    time::{Duration, SystemTime},
+};
+
+/// Report the result of a check, returning whether it passed.
+fn check(name: &str, result: Result<String>) -> bool {
+    match result {
+        Ok(info) => {
+            println!("ok       {name}: {info}");
+            true
+        }
+        Err(e) => {
+            println!("PROBLEM  {name}: {e:#}");
+            false
+        }
+    }
+}
+
+pub fn doctor(args: &Args, opts: &SyncArgs) -> Result<()> {
+    let mut ok = true;
+
+    // The index doesn't need git, only a few other features do.
+    let needs_git = [
+        (args.verify_index_signatures, "--verify-index-signatures"),
+        (!opts.push_to.is_empty(), "--push-to"),
+    ]
+    .into_iter()
+    .filter_map(|(used, feature)| used.then_some(feature))
+    .collect::<Vec<_>>();
+    let git = match Command::new("git").arg("--version").output() {
+        Ok(output) if output.status.success() => {
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        }
+        _ if needs_git.is_empty() => Ok("not found, which is only a problem for \
+              --verify-index-signatures, bundles, push, ingest and serving the index over git"
+            .to_string()),
+        _ => Err(anyhow!(
+            "not found, but needed for {}",
+            needs_git.join(" and ")
+        )),
+    };
+    ok &= check("git", git);
+
+    ok &= check(
+        "index",
+        || -> Result<String> {
+            if !Path::new("crates.io-index").exists() {
+                return Ok("not cloned yet, will be cloned by the first sync".to_string());
+            }
+            if args.no_index_update {
+                return Ok(format!("at {}, not updated by cratesync", Index::head()?));
+            }
+            if args.sparse_index {
+                http_client(args)
+                    .timeout(Duration::from_secs(30))
+                    .build()?
+                    .get(format!("{}/config.json", sparse_index::URL))
+                    .send()?
+                    .error_for_status()
+                    .context("unable to reach the sparse index")?;
+                return Ok(format!("{} is reachable", sparse_index::URL));
+            }
+            let client = http_client(args).timeout(Duration::from_secs(30)).build()?;
+            Index::check_remote(&client).context(
+                "unable to reach the index remote; check the network and the remote of crates.io-index",
+            )?;
+            Ok(format!("at {}, remote is reachable", Index::head()?))
+        }(),
+    );
+
+    // Where the crate files are downloaded from, as configured in the index.
+    let dl_url = Registry::read()?.download_url("anyhow", "1.0.0", "");
+    let dl_host = Url::parse(&dl_url)
+        .ok()
+        .and_then(|url| Some(url.host_str()?.to_string()))
+        .unwrap_or_else(|| dl_url.clone());
+    let mut server_date = None;
+    ok &= check(
+        &dl_host,
+        || -> Result<String> {
+            let client = http_client(args).timeout(Duration::from_secs(30)).build()?;
+            let response = client
+            .head(&dl_url)
+            .send()
+            .context("unable to connect; check DNS, the firewall, proxy settings (HTTPS_PROXY) and CA certificates")?;
+            server_date = response
+                .headers()
+                .get(DATE)
+                .and_then(|d| httpdate::parse_http_date(d.to_str().ok()?).ok());
+            Ok(format!("reachable (HTTP {})", response.status()))
+        }(),
+    );
+
+    if let Some(server_date) = server_date {
+        ok &= check(
+            "clock",
+            || -> Result<String> {
+                let now = SystemTime::now();
+                let skew = now
+                    .duration_since(server_date)
+                    .or_else(|_| server_date.duration_since(now))
+                    .unwrap();
+                if skew > Duration::from_secs(5 * 60) {
+                    bail!(
+                    "local clock differs {}s from {dl_host}; fix the system time (e.g. enable NTP)",
+                    skew.as_secs()
+                );
+                }
+                Ok(format!("within {}s of {dl_host}", skew.as_secs()))
+            }(),
+        );
+    }
+
+    ok &= check(
+        "permissions",
+        || -> Result<String> {
+            write(".cratesync-doctor", "").context(
+                "unable to write to the mirror directory; check its owner and permissions",
+            )?;
+            remove_file(".cratesync-doctor")?;
+            Ok("mirror directory is writable".to_string())
+        }(),
+    );
+
+    ok &= check(
+        "disk space",
+        || -> Result<String> {
+            let free = free_space(".")?;
+            let needed = needed_space()?;
+            if free < needed {
+                bail!(
+                "{} GiB free, but about {} GiB is needed for the remaining crate files; free up or add space",
+                free >> 30,
+                needed >> 30
+            );
+            }
+            Ok(format!(
+                "{} GiB free, about {} GiB needed for the remaining crate files",
+                free >> 30,
+                needed >> 30
+            ))
+        }(),
+    );
+
+    ok &= check(
+        "file descriptors",
+        || -> Result<String> {
+            let limit = fd_limit()?;
+            // Every connection needs a socket and a file, plus some for everything else.
+            let needed = args.connections as u64 * 2 + 64;
+            if limit < needed {
+                bail!(
+                    "limit is {limit}, but {needed} are needed for {} connections; \
+                 raise it with `ulimit -n {needed}`, or use fewer --connections",
+                    args.connections
+                );
+            }
+            Ok(format!("limit is {limit}"))
+        }(),
+    );
+
+    if !ok {
+        bail!("some checks failed");
+    }
+    println!("All checks passed");
+    Ok(())
+}
+
+// The types of these fields differ between platforms.
+#[allow(clippy::unnecessary_cast)]
+fn free_space(path: &str) -> Result<u64> {
+    let path = CString::new(path)?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("statvfs failed");
+    }
+    let stat = unsafe { stat.assume_init() };
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[allow(clippy::unnecessary_cast)]
+fn fd_limit() -> Result<u64> {
+    let mut limit = MaybeUninit::<libc::rlimit>::uninit();
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, limit.as_mut_ptr()) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("getrlimit failed");
+    }
+    Ok(unsafe { limit.assume_init() }.rlim_cur as u64)
+}
+
+/// An estimate of the space needed for the crate files that aren't downloaded yet,
+/// based on the average size of the ones that are.
+fn needed_space() -> Result<u64> {
+    if !Path::new("crates.io-index").exists() {
+        return Ok(0);
+    }
+    let index = Index::read_cached()?;
+    let mut n_present = 0;
+    let mut n_missing = 0;
+    let mut bytes = 0;
+    for (name, versions) in &index.crates {
+        for version in versions.keys() {
+            match cold::size(&format!("crates/{name}/{name}-{version}.crate")) {
+                Ok(size) => {
+                    n_present += 1;
+                    bytes += size;
+                }
+                Err(_) => n_missing += 1,
+            }
+        }
+    }
+    // Without any files yet, assume crate files are about 100 KiB on average.
+    let average = bytes.checked_div(n_present).unwrap_or(100 << 10);
+    Ok(n_missing * average)
+}
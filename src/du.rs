@@ -0,0 +1,300 @@
+//! The disk usage of the mirror, for `du`.
+//!
+//! The sizes come from the state database, rather than from the file system,
+//! as walking a full mirror takes hours. Every run records the usage of each
+//! crate in du.json, such that the next run can report the growth since.
+//!
+//! Besides the totals, this lists the largest crates, the largest crate files,
+//! and the crates that grew the most recently (by the files added to the
+//! mirror in that time), to help decide what to filter on a constrained mirror.
+
+use crate::{
+    filter::Filter,
+    quarantine::now,
+    state::{parse_file, State},
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    cmp::Reverse,
+    collections::BTreeMap,
+    fs::{read_to_string, rename, write},
+    io::ErrorKind,
+    time::Duration,
+};
+
+pub const FILE: &str = "du.json";
+
+#[derive(Default, Serialize, Deserialize)]
+struct Snapshot {
+    /// Unix timestamp.
+    time: u64,
+    crates: BTreeMap<String, Usage>,
+}
+
+#[derive(Default, Clone, Copy, Serialize, Deserialize)]
+struct Usage {
+    files: u64,
+    bytes: u64,
+}
+
+impl Snapshot {
+    fn read() -> Result<Option<Self>> {
+        match read_to_string(FILE) {
+            Ok(s) => serde_json::from_str(&s)
+                .map(Some)
+                .with_context(|| format!("unable to parse {FILE}")),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self) -> Result<()> {
+        write(format!("{FILE}.partial"), serde_json::to_string(self)?)?;
+        rename(format!("{FILE}.partial"), FILE)?;
+        Ok(())
+    }
+
+    /// The total usage of the crates that match `filter`.
+    fn total(&self, filter: &Filter) -> Usage {
+        let mut total = Usage::default();
+        for (name, usage) in &self.crates {
+            if filter.matches(name) {
+                total.files += usage.files;
+                total.bytes += usage.bytes;
+            }
+        }
+        total
+    }
+}
+
+/// The usage of each crate that matches `filter`.
+fn by_crate(files: &[(String, u64)], filter: &Filter) -> BTreeMap<String, Usage> {
+    let mut crates = BTreeMap::<String, Usage>::new();
+    for (file, size) in files {
+        let Some((name, _)) = parse_file(file) else {
+            continue;
+        };
+        if filter.matches(name) {
+            let usage = crates.entry(name.to_string()).or_default();
+            usage.files += 1;
+            usage.bytes += size;
+        }
+    }
+    crates
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Format {
+    Table,
+    Json,
+    Csv,
+    Tsv,
+}
+
+/// What `du` reports, with the field names of the json output.
+#[derive(Serialize)]
+struct Report {
+    files: u64,
+    crates: usize,
+    bytes: u64,
+    /// The change since the last run, if there was one.
+    since_last_run: Option<Change>,
+    largest_crates: Vec<Entry>,
+    largest_files: Vec<Entry>,
+    /// The growth in the last `growth_days`, in total and of the crates that grew the most.
+    growth_days: f64,
+    growth: Usage,
+    growing_crates: Vec<Entry>,
+}
+
+#[derive(Serialize)]
+struct Change {
+    days: f64,
+    files: i64,
+    bytes: i64,
+}
+
+/// A crate, or a crate file (with `files` being 1).
+#[derive(Serialize)]
+struct Entry {
+    name: String,
+    files: u64,
+    bytes: u64,
+}
+
+/// The `top` crates with the most bytes.
+fn top_crates(crates: &BTreeMap<String, Usage>, top: usize) -> Vec<Entry> {
+    let mut crates: Vec<_> = crates.iter().collect();
+    crates.sort_by_key(|(_, usage)| Reverse(usage.bytes));
+    crates
+        .into_iter()
+        .take(top)
+        .map(|(name, usage)| Entry {
+            name: name.clone(),
+            files: usage.files,
+            bytes: usage.bytes,
+        })
+        .collect()
+}
+
+/// Print the crates as a table.
+fn print_top(crates: &[Entry]) {
+    for Entry { name, files, bytes } in crates {
+        println!("  {name:<32} {:>10}  ({files} files)", human(*bytes));
+    }
+}
+
+/// Report the size of the crate files (matching `filter`), and the `top` largest crates and
+/// crate files, and the `top` crates that grew the most in the last `growth_period`.
+///
+/// Without a filter, the usage is recorded for the next run to compare with.
+pub fn report(filter: &Filter, top: usize, growth_period: Duration, format: Format) -> Result<()> {
+    let state = State::open()?;
+    let mut files = state.sizes()?;
+    let snapshot = Snapshot {
+        time: now(),
+        crates: by_crate(&files, filter),
+    };
+
+    let total = snapshot.total(filter);
+    let since_last_run = Snapshot::read()?.map(|last| {
+        let before = last.total(filter);
+        Change {
+            days: now().saturating_sub(last.time) as f64 / (24 * 60 * 60) as f64,
+            files: total.files as i64 - before.files as i64,
+            bytes: total.bytes as i64 - before.bytes as i64,
+        }
+    });
+
+    files.retain(|(file, _)| parse_file(file).is_some_and(|(name, _)| filter.matches(name)));
+    files.sort_by_key(|(_, size)| Reverse(*size));
+    let largest_files = files
+        .into_iter()
+        .take(top)
+        .map(|(name, bytes)| Entry {
+            name,
+            files: 1,
+            bytes,
+        })
+        .collect();
+
+    let since = now().saturating_sub(growth_period.as_secs());
+    let added = by_crate(&state.added_since(since)?, filter);
+    let mut growth = Usage::default();
+    for usage in added.values() {
+        growth.files += usage.files;
+        growth.bytes += usage.bytes;
+    }
+
+    let report = Report {
+        files: total.files,
+        crates: snapshot.crates.len(),
+        bytes: total.bytes,
+        since_last_run,
+        largest_crates: top_crates(&snapshot.crates, top),
+        largest_files,
+        growth_days: growth_period.as_secs() as f64 / (24 * 60 * 60) as f64,
+        growth,
+        growing_crates: top_crates(&added, top),
+    };
+    match format {
+        Format::Table => print_table(&report, top),
+        Format::Json => println!("{}", serde_json::to_string_pretty(&report)?),
+        Format::Csv => print_rows(&report, b',')?,
+        Format::Tsv => print_rows(&report, b'\t')?,
+    }
+
+    if filter.is_empty() {
+        snapshot.write()?;
+    }
+    Ok(())
+}
+
+fn print_table(report: &Report, top: usize) {
+    println!(
+        "{} crate files of {} crates: {}",
+        report.files,
+        report.crates,
+        human(report.bytes)
+    );
+
+    match &report.since_last_run {
+        Some(Change { days, files, bytes }) => println!(
+            "Since the last run ({days:.1} days ago): {files:+} crate files, {}{}",
+            if *bytes < 0 { "-" } else { "+" },
+            human(bytes.unsigned_abs())
+        ),
+        None => println!("No earlier run to compare with"),
+    }
+
+    if top > 0 {
+        println!();
+        println!("Largest crates:");
+        print_top(&report.largest_crates);
+
+        println!();
+        println!("Largest crate files:");
+        for Entry { name, bytes, .. } in &report.largest_files {
+            println!("  {name:<64} {:>10}", human(*bytes));
+        }
+
+        println!();
+        let days = report.growth_days;
+        if report.growing_crates.is_empty() {
+            println!("No crate files were added in the last {days} days");
+        } else {
+            println!(
+                "Most growth in the last {days} days ({} in total):",
+                human(report.growth.bytes)
+            );
+            print_top(&report.growing_crates);
+        }
+    }
+}
+
+/// Print the report as rows of `kind,name,files,bytes`, where the kind is `total`, `change`
+/// (since the last run), `growth`, `largest_crate`, `largest_file` or `growing_crate`.
+///
+/// The first three have no name.
+fn print_rows(report: &Report, delimiter: u8) -> Result<()> {
+    let mut out = csv::WriterBuilder::new()
+        .delimiter(delimiter)
+        .from_writer(std::io::stdout().lock());
+    out.write_record(["kind", "name", "files", "bytes"])?;
+    let mut row = |kind: &str, name: &str, files: i64, bytes: i64| {
+        out.write_record([kind, name, &files.to_string(), &bytes.to_string()])
+    };
+    row("total", "", report.files as i64, report.bytes as i64)?;
+    if let Some(change) = &report.since_last_run {
+        row("change", "", change.files, change.bytes)?;
+    }
+    let growth = &report.growth;
+    row("growth", "", growth.files as i64, growth.bytes as i64)?;
+    for (kind, entries) in [
+        ("largest_crate", &report.largest_crates),
+        ("largest_file", &report.largest_files),
+        ("growing_crate", &report.growing_crates),
+    ] {
+        for Entry { name, files, bytes } in entries {
+            row(kind, name, *files as i64, *bytes as i64)?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// A size with a binary unit, like `12.3 GiB`.
+pub fn human(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if size < 1024.0 {
+            return match unit {
+                "B" => format!("{bytes} B"),
+                _ => format!("{size:.1} {unit}"),
+            };
+        }
+        size /= 1024.0;
+    }
+    format!("{size:.1} TiB")
+}
@@ -0,0 +1,84 @@
+//! Emailing a summary after a sync, for setups that alert on email from scheduled jobs.
+
+use crate::{index::Index, Summary, SyncArgs};
+use anyhow::Result;
+use lettre::{Message, SmtpTransport, Transport};
+use std::{env::current_dir, fmt::Write};
+
+/// The maximum number of errors to list in the email.
+const MAX_ERRORS: usize = 50;
+
+pub fn send_summary(opts: &SyncArgs, result: &Result<Summary>) -> Result<()> {
+    let dir = current_dir()?;
+    let mut body = String::new();
+    let subject = match result {
+        Ok(summary) => {
+            writeln!(body, "Sync of {} finished.", dir.display())?;
+            writeln!(body)?;
+            if let Ok(head) = Index::head() {
+                writeln!(body, "Index: {head}")?;
+            }
+            writeln!(body, "Crate files: {}", summary.n_total)?;
+            writeln!(body, "Downloaded: {}", summary.n_downloaded)?;
+            writeln!(body, "Forbidden (403): {}", summary.n_403)?;
+            writeln!(body, "Gone (404 or 410): {}", summary.n_gone)?;
+            writeln!(body, "Throttled: {}", summary.n_throttled)?;
+            writeln!(body, "Retried: {}", summary.n_retried)?;
+            if summary.n_remaining > 0 {
+                writeln!(body, "Remaining for the next run: {}", summary.n_remaining)?;
+            }
+            writeln!(body, "Errors: {}", summary.errors.len())?;
+            if !summary.alerts.is_empty() {
+                writeln!(body)?;
+                for a in &summary.alerts {
+                    writeln!(body, "alert: {a}")?;
+                }
+            }
+            if !summary.errors.is_empty() {
+                writeln!(body)?;
+                for e in summary.errors.iter().take(MAX_ERRORS) {
+                    writeln!(body, "error: {e}")?;
+                }
+                if summary.errors.len() > MAX_ERRORS {
+                    writeln!(body, "... and {} more", summary.errors.len() - MAX_ERRORS)?;
+                }
+                format!(
+                    "cratesync: sync finished with {} errors",
+                    summary.errors.len()
+                )
+            } else if !summary.alerts.is_empty() {
+                format!(
+                    "cratesync: sync finished with {} alerts",
+                    summary.alerts.len()
+                )
+            } else {
+                format!(
+                    "cratesync: sync finished, {} new crate files",
+                    summary.n_downloaded
+                )
+            }
+        }
+        Err(e) => {
+            writeln!(body, "Sync of {} failed:", dir.display())?;
+            writeln!(body)?;
+            writeln!(body, "error: {e:#}")?;
+            "cratesync: sync failed".to_string()
+        }
+    };
+
+    let mut message = Message::builder()
+        .from(opts.email_from.parse()?)
+        .subject(subject);
+    for to in &opts.email_to {
+        message = message.to(to.parse()?);
+    }
+    let message = message.body(body)?;
+
+    SmtpTransport::from_url(&opts.smtp_url)?
+        .build()
+        .send(&message)?;
+
+    println!("Sent summary email to {}", opts.email_to.join(", "));
+
+    Ok(())
+}
@@ -0,0 +1,66 @@
+//! Stopping the downloads early when too many fail, for --max-errors and --max-error-rate.
+//!
+//! When something between us and crates.io starts returning garbage (like a
+//! captive portal, or a proxy whose credentials expired), every download
+//! fails, and without a limit a sync would go through the entire queue.
+
+use std::{collections::VecDeque, sync::Mutex};
+
+/// The number of most recent downloads that --max-error-rate applies to.
+pub const WINDOW: usize = 100;
+
+pub struct ErrorLimit {
+    max_errors: Option<u64>,
+    /// Percentage of the last [`WINDOW`] downloads.
+    max_rate: Option<u8>,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    n_errors: u64,
+    /// Whether each of the last [`WINDOW`] downloads failed.
+    recent: VecDeque<bool>,
+    /// Why the downloads stopped, once a limit was reached.
+    reason: Option<String>,
+}
+
+impl ErrorLimit {
+    pub fn new(max_errors: Option<u64>, max_rate: Option<u8>) -> Self {
+        Self {
+            max_errors,
+            max_rate,
+            inner: Mutex::default(),
+        }
+    }
+
+    /// Record the outcome of a download, which stops the downloads if that reaches a limit.
+    pub fn record(&self, failed: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.n_errors += failed as u64;
+        if inner.recent.len() == WINDOW {
+            inner.recent.pop_front();
+        }
+        inner.recent.push_back(failed);
+        let n_recent = inner.recent.iter().filter(|&&f| f).count();
+        if inner.reason.is_none() {
+            if self.max_errors.is_some_and(|max| inner.n_errors >= max) {
+                inner.reason = Some(format!(
+                    "stopped after {} failed downloads (--max-errors)",
+                    inner.n_errors
+                ));
+            } else if self.max_rate.is_some_and(|rate| {
+                inner.recent.len() == WINDOW && n_recent * 100 >= rate as usize * WINDOW
+            }) {
+                inner.reason = Some(format!(
+                    "stopped because {n_recent} of the last {WINDOW} downloads failed (--max-error-rate)"
+                ));
+            }
+        }
+    }
+
+    /// Why the downloads stopped, if a limit was reached.
+    pub fn reason(&self) -> Option<String> {
+        self.inner.lock().unwrap().reason.clone()
+    }
+}
@@ -0,0 +1,81 @@
+//! The crate files that failed to download in a run, for other tools and `retry --from`.
+//!
+//! Every run in which downloads failed writes them to errors-<timestamp>.json
+//! in the mirror (with a Unix timestamp), rather than only printing them
+//! between the progress lines. Unlike the state database, which only keeps
+//! the latest failure of each crate file, these files are kept until removed.
+
+use crate::{
+    failures::Failure,
+    index::{CrateData, Index},
+    quarantine::now,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{read_to_string, rename, write},
+    path::PathBuf,
+};
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub name: String,
+    pub version: String,
+    pub cksum: String,
+    pub url: String,
+    /// Like the category of a [`Failure`](crate::failures::Failure).
+    pub category: String,
+    pub error: String,
+    /// The number of times it was tried in this run.
+    pub attempts: u32,
+}
+
+impl Entry {
+    pub fn new(failure: &Failure, url: &str, attempts: u32) -> Self {
+        Self {
+            name: failure.name.clone(),
+            version: failure.version.clone(),
+            cksum: failure.cksum.clone(),
+            url: url.to_string(),
+            category: failure.category.clone(),
+            error: failure.error.clone(),
+            attempts,
+        }
+    }
+}
+
+/// Write the report of a run, and return its file name.
+pub fn write_report(entries: &[Entry]) -> Result<String> {
+    let file = format!("errors-{}.json", now());
+    write(
+        format!("{file}.partial"),
+        serde_json::to_string_pretty(entries)?,
+    )?;
+    rename(format!("{file}.partial"), &file)?;
+    Ok(file)
+}
+
+/// An index of the crate files in the given reports.
+pub fn index(files: &[PathBuf]) -> Result<Index> {
+    let mut crates = BTreeMap::<String, BTreeMap<String, CrateData>>::new();
+    for file in files {
+        let entries: Vec<Entry> = serde_json::from_str(
+            &read_to_string(file).with_context(|| format!("unable to read {}", file.display()))?,
+        )
+        .with_context(|| format!("unable to parse {}", file.display()))?;
+        for e in entries {
+            crates.entry(e.name).or_default().insert(
+                e.version,
+                CrateData {
+                    cksum: e.cksum,
+                    yanked: false,
+                    rust_version: None,
+                    pubtime: None,
+                    details: (),
+                },
+            );
+        }
+    }
+    Ok(Index { crates })
+}
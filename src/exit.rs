@@ -0,0 +1,36 @@
+//! The exit codes of `cratesync`, such that cron and systemd can tell failures apart.
+//!
+//! Besides 0 for success, 1 for other errors, and 2 for invalid arguments,
+//! these are the codes for the failures that are usually worth an alert.
+
+use std::fmt;
+
+/// Exit code when updating the index failed.
+pub const INDEX_UPDATE_FAILED: u8 = 3;
+/// Exit code when crate files failed to download, with --fail-on-errors or --max-errors.
+pub const DOWNLOADS_FAILED: u8 = 4;
+
+/// The context of an error that gets its own exit code, see [`exit_code`].
+#[derive(Debug)]
+pub enum Failed {
+    IndexUpdate,
+    Downloads,
+}
+
+impl fmt::Display for Failed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::IndexUpdate => "unable to update the index",
+            Self::Downloads => "unable to download all crate files",
+        })
+    }
+}
+
+/// The exit code for an error returned by [`run`](crate::run).
+pub fn exit_code(error: &anyhow::Error) -> u8 {
+    match error.downcast_ref::<Failed>() {
+        Some(Failed::IndexUpdate) => INDEX_UPDATE_FAILED,
+        Some(Failed::Downloads) => DOWNLOADS_FAILED,
+        None => 1,
+    }
+}
@@ -0,0 +1,138 @@
+//! Crate files that failed to download, kept in the state database (see [`state`](crate::state))
+//! so they can be retried with `retry-errors` without going through the whole index again.
+//!
+//! These used to be kept in errors.jsonl, with a line of JSON per file.
+
+use crate::{
+    index::{CrateData, Index},
+    state::State,
+    validate::InvalidArchive,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::{read_to_string, remove_file},
+    io::{self, ErrorKind},
+    path::Path,
+};
+
+/// The file these used to be stored in.
+const LEGACY_FILE: &str = "errors.jsonl";
+
+#[derive(Serialize, Deserialize)]
+pub struct Failure {
+    pub name: String,
+    pub version: String,
+    pub cksum: String,
+    /// What kind of error it was: http, network, checksum, io, or other.
+    pub category: String,
+    pub error: String,
+    /// The number of (consecutive) syncs in which this file failed to download.
+    pub attempts: u32,
+}
+
+/// The downloaded file didn't match the checksum in the index.
+#[derive(Debug)]
+pub struct ChecksumMismatch {
+    pub file: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "invalid checksum on {:?}: should be {}, but is {}",
+            self.file, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatch {}
+
+impl Failure {
+    pub fn new(name: &str, version: &str, cksum: &str, error: &anyhow::Error) -> Self {
+        Self {
+            name: name.to_string(),
+            version: version.to_string(),
+            cksum: cksum.to_string(),
+            category: category(error).to_string(),
+            error: format!("{error:#}"),
+            attempts: 1,
+        }
+    }
+
+    pub fn file(&self) -> String {
+        format!("crates/{0}/{0}-{1}.crate", self.name, self.version)
+    }
+}
+
+fn category(error: &anyhow::Error) -> &'static str {
+    for e in error.chain() {
+        if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+            return if e.is_status() { "http" } else { "network" };
+        } else if e.is::<ChecksumMismatch>() {
+            return "checksum";
+        } else if e.is::<InvalidArchive>() {
+            return "archive";
+        } else if e.is::<io::Error>() {
+            return "io";
+        }
+    }
+    "other"
+}
+
+/// Whether a download that failed with this error might succeed when tried again,
+/// like after a network error or a 5xx response.
+pub fn is_transient(error: &anyhow::Error) -> bool {
+    for e in error.chain() {
+        if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+            return e.status().is_none_or(|s| s.is_server_error());
+        } else if e.is::<io::Error>() {
+            return true;
+        }
+    }
+    false
+}
+
+/// Read the failures from the file these used to be stored in, to move them into the state database.
+pub fn read_legacy() -> Result<Vec<Failure>> {
+    if !Path::new(LEGACY_FILE).exists() {
+        return Ok(Vec::new());
+    }
+    read_to_string(LEGACY_FILE)?
+        .lines()
+        .map(|line| {
+            serde_json::from_str(line).with_context(|| format!("unable to parse {LEGACY_FILE}"))
+        })
+        .collect()
+}
+
+/// Remove the file of [`read_legacy`].
+pub fn remove_legacy() -> Result<()> {
+    match remove_file(LEGACY_FILE) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// An index of only the crate files that failed before.
+pub fn index(state: &State) -> Result<Index> {
+    let mut crates = BTreeMap::<String, BTreeMap<String, CrateData>>::new();
+    for f in state.failures()? {
+        crates.entry(f.name).or_default().insert(
+            f.version,
+            CrateData {
+                cksum: f.cksum,
+                yanked: false,
+                rust_version: None,
+                pubtime: None,
+                details: (),
+            },
+        );
+    }
+    Ok(Index { crates })
+}
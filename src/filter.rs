@@ -0,0 +1,112 @@
+//! Restricting the mirror to the crates matching `--include`, and not `--exclude`.
+//!
+//! Patterns are globs matching the whole crate name, like `windows-*`, or
+//! regular expressions between slashes, like `/^(tokio|mio)$/`. Crate names are
+//! matched case-insensitively, like crates.io does.
+//!
+//! A filter file has one pattern per line, each prefixed with `include` or
+//! `exclude`. Empty lines and lines starting with `#` are ignored.
+
+use crate::{index::Index, Args};
+use anyhow::{bail, Context, Result};
+use regex::{Regex, RegexBuilder};
+use std::fs::read_to_string;
+
+pub struct Filter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl Filter {
+    pub fn new(args: &Args) -> Result<Self> {
+        let mut filter = Self {
+            include: Vec::new(),
+            exclude: Vec::new(),
+        };
+        if let Some(path) = &args.filter_file {
+            let file = read_to_string(path)
+                .with_context(|| format!("unable to read filter file {}", path.display()))?;
+            for (i, line) in file.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let (kind, pattern) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+                let list = match kind {
+                    "include" => &mut filter.include,
+                    "exclude" => &mut filter.exclude,
+                    _ => bail!(
+                        "{}:{}: expected `include PATTERN` or `exclude PATTERN`",
+                        path.display(),
+                        i + 1
+                    ),
+                };
+                list.push(
+                    parse(pattern.trim())
+                        .with_context(|| format!("{}:{}", path.display(), i + 1))?,
+                );
+            }
+        }
+        for pattern in &args.include {
+            filter.include.push(parse(pattern)?);
+        }
+        for pattern in &args.exclude {
+            filter.exclude.push(parse(pattern)?);
+        }
+        Ok(filter)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether the crate with this name should be mirrored.
+    pub fn matches(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.is_match(name)))
+            && !self.exclude.iter().any(|p| p.is_match(name))
+    }
+
+    /// The part of `index` with the matching crates, or `None` if there is nothing to filter.
+    pub fn apply(&self, index: &Index) -> Option<Index> {
+        if self.is_empty() {
+            return None;
+        }
+        let filtered = Index {
+            crates: index
+                .crates
+                .iter()
+                .filter(|(name, _)| self.matches(name))
+                .map(|(name, versions)| (name.clone(), versions.clone()))
+                .collect(),
+        };
+        println!(
+            "Filtered to {} of {} crates",
+            filtered.crates.len(),
+            index.crates.len()
+        );
+        Some(filtered)
+    }
+}
+
+/// Parse a glob, or a regular expression between slashes.
+fn parse(pattern: &str) -> Result<Regex> {
+    let regex = if let Some(regex) = pattern.strip_prefix('/').and_then(|p| p.strip_suffix('/')) {
+        regex.to_string()
+    } else {
+        let mut regex = String::from("^");
+        for c in pattern.chars() {
+            match c {
+                '*' => regex.push_str(".*"),
+                '?' => regex.push('.'),
+                c if c.is_ascii_alphanumeric() || c == '-' || c == '_' => regex.push(c),
+                c => bail!("invalid character {c:?} in crate name pattern {pattern:?}"),
+            }
+        }
+        regex.push('$');
+        regex
+    };
+    RegexBuilder::new(&regex)
+        .case_insensitive(true)
+        .build()
+        .with_context(|| format!("invalid pattern {pattern:?}"))
+}
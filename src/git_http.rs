@@ -0,0 +1,261 @@
+//! The HTTP transport for cloning and fetching the index with gitoxide.
+//!
+//! gitoxide's own reqwest transport builds its HTTP client itself, which
+//! wouldn't get `--ca-file`, `--tls-backend`, `--no-system-roots` and
+//! `--resolve`. This one uses the client of [`crate::http_client`] instead.
+//!
+//! The git protocol over HTTP writes the body of a request before reading
+//! its response, so a request is only sent once its response is first read.
+//! The bodies that are posted are small (the refs we want and have), so they
+//! are kept in memory until then.
+
+use gix::{
+    protocol::transport::client::{
+        blocking_io::http::{self, GetResponse, Http, PostBodyDataKind, PostResponse},
+        AuthenticationRequired,
+    },
+    remote::{Connection, Direction},
+    url::Scheme,
+};
+use reqwest::{
+    blocking::{Client, RequestBuilder, Response},
+    header::{HeaderMap, HeaderName, HeaderValue, WWW_AUTHENTICATE},
+    StatusCode,
+};
+use std::{
+    any::Any,
+    io::{self, BufRead, BufReader, Cursor, Read, Write},
+    mem,
+    sync::{Arc, Mutex},
+};
+
+/// Any gitoxide transport, as used by [`gix::Remote::connect`].
+pub type Boxed = Box<dyn gix::protocol::transport::client::blocking_io::Transport + Send>;
+
+/// Connect to `remote` for fetching, through `client` if that's over HTTP.
+pub fn connect<'a, 'repo>(
+    remote: &'a gix::Remote<'repo>,
+    client: &Client,
+) -> gix::Result<Connection<'a, 'static, 'repo, Boxed>> {
+    match for_remote(remote, client)? {
+        Some(transport) => Ok(remote.to_connection_with_transport(transport)),
+        None => remote.connect(Direction::Fetch),
+    }
+}
+
+/// The transport for fetching from `remote` through `client`, or `None` if that's not over HTTP.
+pub fn for_remote(remote: &gix::Remote, client: &Client) -> gix::Result<Option<Boxed>> {
+    let (url, version) = remote.sanitized_url_and_version(Direction::Fetch)?;
+    if !matches!(url.scheme, Scheme::Http | Scheme::Https) {
+        return Ok(None);
+    }
+    let transport = http::connect_http(Transport::new(client.clone()), url, version, false);
+    Ok(Some(Box::new(transport)))
+}
+
+pub struct Transport {
+    client: Client,
+    /// From `http.extraHeader`, like the Authorization header of --token.
+    extra_headers: Vec<String>,
+}
+
+impl Transport {
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            extra_headers: Vec::new(),
+        }
+    }
+
+    fn request(
+        &self,
+        request: RequestBuilder,
+        headers: impl IntoIterator<Item = impl AsRef<str>>,
+        post: bool,
+    ) -> (Lazy, Lazy, PostBody) {
+        let mut map = HeaderMap::new();
+        for line in headers
+            .into_iter()
+            .map(|h| h.as_ref().to_string())
+            .chain(self.extra_headers.iter().cloned())
+        {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::try_from(name),
+                HeaderValue::try_from(value.trim()),
+            ) {
+                map.append(name, value);
+            }
+        }
+        let pending = Arc::new(Mutex::new(Pending {
+            request: Some(request.headers(map)),
+            post,
+            body: Vec::new(),
+            response: None,
+        }));
+        (
+            Lazy::Headers(pending.clone()),
+            Lazy::Body(pending.clone()),
+            PostBody(pending),
+        )
+    }
+}
+
+impl Http for Transport {
+    type Headers = Lazy;
+    type ResponseBody = Lazy;
+    type PostBody = PostBody;
+
+    fn get(
+        &mut self,
+        url: &str,
+        _base_url: &str,
+        headers: impl IntoIterator<Item = impl AsRef<str>>,
+    ) -> gix::Result<GetResponse<Lazy, Lazy>> {
+        let (headers, body, _) = self.request(self.client.get(url), headers, false);
+        Ok(GetResponse { headers, body })
+    }
+
+    fn post(
+        &mut self,
+        url: &str,
+        _base_url: &str,
+        headers: impl IntoIterator<Item = impl AsRef<str>>,
+        _body: PostBodyDataKind,
+    ) -> gix::Result<PostResponse<Lazy, Lazy, PostBody>> {
+        let (headers, body, post_body) = self.request(self.client.post(url), headers, true);
+        Ok(PostResponse {
+            post_body,
+            headers,
+            body,
+        })
+    }
+
+    fn configure(&mut self, config: &dyn Any) -> gix::Result {
+        if let Some(options) = config.downcast_ref::<http::Options>() {
+            self.extra_headers = options.extra_headers.clone();
+        }
+        Ok(())
+    }
+}
+
+/// A request that wasn't sent yet, or its response.
+pub struct Pending {
+    request: Option<RequestBuilder>,
+    post: bool,
+    /// What was written to the [`PostBody`] so far.
+    body: Vec<u8>,
+    response: Option<io::Result<Response>>,
+}
+
+impl Pending {
+    /// Send the request, unless that already happened.
+    fn send(&mut self) {
+        if let Some(mut request) = self.request.take() {
+            if self.post {
+                request = request.body(mem::take(&mut self.body));
+            }
+            self.response = Some(send(request));
+        }
+    }
+}
+
+/// Send a request, turning HTTP errors into the I/O errors that gitoxide expects.
+fn send(request: RequestBuilder) -> io::Result<Response> {
+    let response = request.send().map_err(io::Error::other)?;
+    if response.status() == StatusCode::UNAUTHORIZED {
+        let www_authenticate = response
+            .headers()
+            .get_all(WWW_AUTHENTICATE)
+            .iter()
+            .map(|value| value.as_bytes().into())
+            .collect();
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            AuthenticationRequired { www_authenticate },
+        ));
+    }
+    response.error_for_status().map_err(io::Error::other)
+}
+
+/// The headers or body of a response, which sends the request when first read.
+pub enum Lazy {
+    Headers(Arc<Mutex<Pending>>),
+    Body(Arc<Mutex<Pending>>),
+    Ready(Box<dyn BufRead + Send>),
+}
+
+impl Lazy {
+    fn ready(&mut self) -> io::Result<&mut Box<dyn BufRead + Send>> {
+        let reader: Box<dyn BufRead + Send> = match self {
+            Lazy::Ready(reader) => return Ok(reader),
+            Lazy::Headers(pending) => {
+                let mut pending = pending.lock().unwrap();
+                pending.send();
+                let mut lines = Vec::new();
+                match &pending.response {
+                    Some(Ok(response)) => {
+                        for (name, value) in response.headers() {
+                            lines.extend(name.as_str().as_bytes());
+                            lines.push(b':');
+                            lines.extend(value.as_bytes());
+                            lines.push(b'\n');
+                        }
+                    }
+                    // The headers are read first, so they get the error.
+                    Some(Err(_)) => return Err(pending.response.take().unwrap().unwrap_err()),
+                    None => {}
+                }
+                Box::new(Cursor::new(lines))
+            }
+            Lazy::Body(pending) => {
+                let mut pending = pending.lock().unwrap();
+                pending.send();
+                match pending.response.take() {
+                    Some(Ok(response)) => Box::new(BufReader::new(response)),
+                    Some(Err(e)) => return Err(e),
+                    None => Box::new(io::empty()),
+                }
+            }
+        };
+        *self = Lazy::Ready(reader);
+        let Lazy::Ready(reader) = self else {
+            unreachable!()
+        };
+        Ok(reader)
+    }
+}
+
+impl Read for Lazy {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.ready()?.read(buf)
+    }
+}
+
+impl BufRead for Lazy {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.ready()?.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if let Lazy::Ready(reader) = self {
+            reader.consume(amt);
+        }
+    }
+}
+
+/// The body of a request to post, which is sent once the response is read.
+pub struct PostBody(Arc<Mutex<Pending>>);
+
+impl Write for PostBody {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().body.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+//! Exporting the dependency graph of the index.
+//!
+//! Nodes are crate versions, and every dependency is an edge to the highest
+//! version that matches its requirement (preferring versions that aren't yanked).
+//! This is not what cargo would resolve for a specific project, as there is no
+//! unification of versions, but it's a good approximation of the ecosystem's graph.
+
+use crate::index::{Dependency, DependencyKind, Details, Index};
+use anyhow::{ensure, Context, Result};
+use semver::{Version, VersionReq};
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    io::{self, BufWriter, Write},
+};
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+pub enum Format {
+    Dot,
+    Json,
+    Graphml,
+}
+
+#[derive(Serialize)]
+struct Node<'a> {
+    id: String,
+    name: &'a str,
+    version: &'a str,
+}
+
+#[derive(Serialize)]
+struct Edge<'a> {
+    from: String,
+    to: String,
+    req: &'a str,
+    kind: &'static str,
+    optional: bool,
+    target: Option<&'a str>,
+}
+
+/// Finds the best matching version of a crate for a dependency.
+pub struct Resolver<'a> {
+    /// name -> versions, from new to old.
+    versions: HashMap<&'a str, Vec<(Version, &'a str, bool)>>,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new<D>(index: &'a Index<D>) -> Self {
+        let versions = index
+            .crates
+            .iter()
+            .map(|(name, versions)| {
+                let mut v: Vec<_> = versions
+                    .iter()
+                    .filter_map(|(v, data)| {
+                        Some((Version::parse(v).ok()?, v.as_str(), data.yanked))
+                    })
+                    .collect();
+                v.sort_by(|a, b| b.0.cmp(&a.0));
+                (name.as_str(), v)
+            })
+            .collect();
+        Self { versions }
+    }
+
+    /// The highest version of `name` matching `req`, preferring versions that aren't yanked.
+    pub fn resolve(&self, name: &str, req: &VersionReq) -> Option<&'a str> {
+        let versions = self.versions.get(name)?;
+        let mut matching = versions.iter().filter(|(v, _, _)| req.matches(v));
+        let first = matching.clone().next()?;
+        Some(matching.find(|(_, _, yanked)| !yanked).unwrap_or(first).1)
+    }
+
+    /// The latest version of `name` that isn't yanked, if any.
+    pub fn latest(&self, name: &str) -> Option<&'a str> {
+        let versions = self.versions.get(name)?;
+        versions.iter().find(|(_, _, yanked)| !yanked).map(|v| v.1)
+    }
+}
+
+/// Write the graph of the given crates (`name` or `name@version`) and their
+/// dependencies, or of the whole index if `roots` is empty.
+pub fn export(index: &Index<Details>, roots: &[String], format: Format, dev: bool) -> Result<()> {
+    let resolver = Resolver::new(index);
+
+    let follow =
+        |dep: &Dependency| dep.registry.is_none() && (dev || dep.kind != DependencyKind::Dev);
+    let resolve = |dep: &Dependency| {
+        let req = VersionReq::parse(&dep.req).ok()?;
+        resolver.resolve(dep.crate_name(), &req)
+    };
+
+    // Determine the set of nodes in the graph.
+    let mut nodes = BTreeSet::new();
+    if roots.is_empty() {
+        for (name, versions) in &index.crates {
+            for version in versions.keys() {
+                nodes.insert((name.as_str(), version.as_str()));
+            }
+        }
+    } else {
+        let mut queue = VecDeque::new();
+        for root in roots {
+            let (name, version) = match root.split_once('@') {
+                Some((name, version)) => (name, Some(version)),
+                None => (root.as_str(), None),
+            };
+            let (name, versions) = index
+                .crates
+                .get_key_value(name)
+                .with_context(|| format!("crate {name:?} not found in index"))?;
+            let version = match version {
+                Some(version) => {
+                    versions
+                        .get_key_value(version)
+                        .with_context(|| format!("version {version} of {name} not found in index"))?
+                        .0
+                }
+                None => resolver
+                    .latest(name)
+                    .with_context(|| format!("{name} has no versions that aren't yanked"))?,
+            };
+            queue.push_back((name.as_str(), version));
+        }
+        while let Some(node) = queue.pop_front() {
+            if nodes.insert(node) {
+                for dep in &index.crates[node.0][node.1].details.deps {
+                    if !follow(dep) {
+                        continue;
+                    }
+                    if let Some(version) = resolve(dep) {
+                        let name = index.crates.get_key_value(dep.crate_name()).unwrap().0;
+                        queue.push_back((name, version));
+                    }
+                }
+            }
+        }
+    }
+    ensure!(!nodes.is_empty(), "empty graph");
+
+    let id = |name: &str, version: &str| format!("{name}@{version}");
+    let mut edges = Vec::new();
+    for &(name, version) in &nodes {
+        for dep in &index.crates[name][version].details.deps {
+            if !follow(dep) {
+                continue;
+            }
+            if let Some(to) = resolve(dep) {
+                edges.push(Edge {
+                    from: id(name, version),
+                    to: id(dep.crate_name(), to),
+                    req: &dep.req,
+                    kind: match dep.kind {
+                        DependencyKind::Normal => "normal",
+                        DependencyKind::Build => "build",
+                        DependencyKind::Dev => "dev",
+                    },
+                    optional: dep.optional,
+                    target: dep.target.as_deref(),
+                });
+            }
+        }
+    }
+    let nodes = nodes.into_iter().map(|(name, version)| Node {
+        id: id(name, version),
+        name,
+        version,
+    });
+
+    let mut out = BufWriter::new(io::stdout().lock());
+    match format {
+        Format::Dot => {
+            writeln!(out, "digraph crates {{")?;
+            for node in nodes {
+                writeln!(out, "  {:?};", node.id)?;
+            }
+            for edge in &edges {
+                let style = if edge.optional { "dashed" } else { "solid" };
+                writeln!(
+                    out,
+                    "  {:?} -> {:?} [label={:?}, style={style}];",
+                    edge.from, edge.to, edge.req
+                )?;
+            }
+            writeln!(out, "}}")?;
+        }
+        Format::Json => {
+            #[derive(Serialize)]
+            struct Graph<'a> {
+                nodes: Vec<Node<'a>>,
+                edges: Vec<Edge<'a>>,
+            }
+            let graph = Graph {
+                nodes: nodes.collect(),
+                edges,
+            };
+            serde_json::to_writer(&mut out, &graph)?;
+            writeln!(out)?;
+        }
+        Format::Graphml => {
+            writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+            writeln!(
+                out,
+                r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#
+            )?;
+            for (key, target, name, ty) in [
+                ("name", "node", "name", "string"),
+                ("version", "node", "version", "string"),
+                ("req", "edge", "req", "string"),
+                ("kind", "edge", "kind", "string"),
+                ("optional", "edge", "optional", "boolean"),
+                ("target", "edge", "target", "string"),
+            ] {
+                writeln!(
+                    out,
+                    r#"  <key id="{key}" for="{target}" attr.name="{name}" attr.type="{ty}"/>"#
+                )?;
+            }
+            writeln!(out, r#"  <graph edgedefault="directed">"#)?;
+            for node in nodes {
+                writeln!(
+                    out,
+                    r#"    <node id="{}"><data key="name">{}</data><data key="version">{}</data></node>"#,
+                    xml_escape(&node.id),
+                    xml_escape(node.name),
+                    xml_escape(node.version),
+                )?;
+            }
+            for edge in &edges {
+                write!(
+                    out,
+                    r#"    <edge source="{}" target="{}"><data key="req">{}</data><data key="kind">{}</data><data key="optional">{}</data>"#,
+                    xml_escape(&edge.from),
+                    xml_escape(&edge.to),
+                    xml_escape(edge.req),
+                    edge.kind,
+                    edge.optional,
+                )?;
+                if let Some(target) = edge.target {
+                    write!(out, r#"<data key="target">{}</data>"#, xml_escape(target))?;
+                }
+                writeln!(out, "</edge>")?;
+            }
+            writeln!(out, "  </graph>")?;
+            writeln!(out, "</graphml>")?;
+        }
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
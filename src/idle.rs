@@ -0,0 +1,48 @@
+//! Staying out of the way of everything else on the machine, for `--idle`.
+//!
+//! This lowers the CPU priority (like `nice -n 19`) and, on Linux, puts the
+//! process in the idle I/O scheduling class (like `ionice -c 3`), such that
+//! the CPU and disk are only used when nothing else needs them.
+
+/// The maximum number of parallel connections in idle mode.
+pub const MAX_CONNECTIONS: usize = 4;
+
+#[cfg(unix)]
+fn nice() -> std::io::Result<()> {
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn nice() -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+fn ionice() -> std::io::Result<()> {
+    /// `IOPRIO_WHO_PROCESS` from linux/ioprio.h.
+    const WHO_PROCESS: libc::c_long = 1;
+    /// `IOPRIO_CLASS_IDLE` from linux/ioprio.h, shifted into place.
+    const CLASS_IDLE: libc::c_long = 3 << 13;
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, WHO_PROCESS, 0, CLASS_IDLE) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn ionice() -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Lower the CPU and I/O priority of this process (and the threads and processes it starts).
+pub fn enter() {
+    if let Err(e) = nice() {
+        println!("warning: unable to lower CPU priority: {e}");
+    }
+    if let Err(e) = ionice() {
+        println!("warning: unable to lower I/O priority: {e}");
+    }
+}
@@ -0,0 +1,71 @@
+//! Protecting verified crate files against modification.
+//!
+//! On Linux, this sets the immutable attribute (like `chattr +i`), which
+//! needs CAP_LINUX_IMMUTABLE. Where that isn't possible, the file is only
+//! made read-only. The attribute is cleared only when a file is deliberately
+//! removed, by `prune`.
+
+use anyhow::Result;
+use std::{fs, path::Path};
+
+#[cfg(target_os = "linux")]
+mod attr {
+    use std::{fs::File, os::fd::AsRawFd, path::Path};
+
+    /// `FS_IMMUTABLE_FL` from linux/fs.h.
+    const IMMUTABLE: libc::c_int = 0x10;
+
+    fn update(path: &Path, f: impl FnOnce(libc::c_int) -> libc::c_int) -> std::io::Result<()> {
+        let file = File::open(path)?;
+        let mut flags: libc::c_int = 0;
+        if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        let new_flags = f(flags);
+        if new_flags != flags
+            && unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &new_flags) } != 0
+        {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn set(path: &Path) -> std::io::Result<()> {
+        update(path, |flags| flags | IMMUTABLE)
+    }
+
+    pub fn clear(path: &Path) -> std::io::Result<()> {
+        update(path, |flags| flags & !IMMUTABLE)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod attr {
+    use std::{io, path::Path};
+
+    pub fn set(_: &Path) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn clear(_: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Make a file immutable, or at least read-only.
+pub fn set(path: impl AsRef<Path>) -> Result<()> {
+    let path = path.as_ref();
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(path, permissions)?;
+    // Being read-only is the best we can do if this isn't supported or allowed.
+    let _ = attr::set(path);
+    Ok(())
+}
+
+/// Allow a file to be removed again.
+pub fn clear(path: impl AsRef<Path>) -> Result<()> {
+    // Fails if the file system doesn't support attributes, in which case there's nothing to clear.
+    let _ = attr::clear(path.as_ref());
+    Ok(())
+}
@@ -0,0 +1,714 @@
+//! The local clone of the crates.io index.
+//!
+//! See <https://doc.rust-lang.org/cargo/reference/registry-index.html>.
+
+// The types here mirror the index format, which has fields that not every command uses.
+#![allow(dead_code)]
+
+use crate::{git_http, output, sparse_index};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use gix::{
+    index::{entry::Stat, fs::Metadata as FileMetadata},
+    object::tree::diff::ChangeDetached,
+    progress::{prodash::progress::Task, tree, Discard},
+    refs::transaction::PreviousValue,
+    remote::fetch::Shallow,
+};
+use reqwest::blocking::Client;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    fs::{create_dir_all, read_to_string, remove_dir, remove_dir_all, remove_file, rename, File},
+    io::{BufWriter, Write},
+    num::NonZeroU32,
+    path::Path,
+    process::Command,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering::Relaxed},
+        mpsc::{self, RecvTimeoutError},
+    },
+    thread,
+    time::Duration,
+};
+
+/// The parsed index as of a commit, see [`Index::read_cached`].
+const CACHE_FILE: &str = "index-cache.tsv";
+
+/// The parsed index.
+///
+/// By default, only the information needed for syncing is kept.
+/// Use `Index<Details>` to also load dependencies, features, etc.
+#[derive(Default)]
+pub struct Index<D = ()> {
+    /// name -> version -> CrateData
+    pub crates: BTreeMap<String, BTreeMap<String, CrateData<D>>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CrateData<D = ()> {
+    pub cksum: String,
+    pub yanked: bool,
+    /// The minimum supported Rust version, if specified.
+    pub rust_version: Option<String>,
+    /// When the version was published, like `2025-01-31T12:00:00Z`. Missing for older versions.
+    pub pubtime: Option<String>,
+    #[serde(flatten)]
+    pub details: D,
+}
+
+/// The information from the index that isn't needed for syncing.
+#[derive(Debug, Deserialize)]
+pub struct Details {
+    pub deps: Vec<Dependency>,
+    pub features: BTreeMap<String, Vec<String>>,
+    /// Features using the newer `dep:` or `?` syntax, which are kept
+    /// separately in the index for compatibility with older cargo versions.
+    #[serde(default)]
+    pub features2: BTreeMap<String, Vec<String>>,
+    pub links: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Dependency {
+    /// The name of the dependency as used in the code.
+    ///
+    /// If it was renamed, `package` contains the original name.
+    pub name: String,
+    pub req: String,
+    pub features: Vec<String>,
+    pub optional: bool,
+    pub default_features: bool,
+    pub target: Option<String>,
+    #[serde(default)]
+    pub kind: DependencyKind,
+    /// The registry of the dependency, if it's not the same registry.
+    pub registry: Option<String>,
+    pub package: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DependencyKind {
+    #[default]
+    Normal,
+    Dev,
+    Build,
+}
+
+/// A Rust version as used for `rust-version`, like `1.70` or `1.56.1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RustVersion(pub u64, pub u64, pub u64);
+
+impl FromStr for RustVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.trim().splitn(3, '.').map(|p| p.parse::<u64>());
+        let mut next = || parts.next().transpose();
+        let v = || -> Result<Self> {
+            Ok(RustVersion(
+                next()?.context("empty")?,
+                next()?.unwrap_or(0),
+                next()?.unwrap_or(0),
+            ))
+        }();
+        v.with_context(|| format!("invalid Rust version {s:?}"))
+    }
+}
+
+impl fmt::Display for RustVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+impl<D> CrateData<D> {
+    /// The parsed `rust_version`, if specified and valid.
+    pub fn msrv(&self) -> Option<RustVersion> {
+        self.rust_version.as_deref()?.parse().ok()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Metadata<D> {
+    name: String,
+    vers: String,
+    #[serde(flatten)]
+    data: CrateData<D>,
+}
+
+impl Dependency {
+    /// The name of the crate this depends on.
+    pub fn crate_name(&self) -> &str {
+        self.package.as_deref().unwrap_or(&self.name)
+    }
+}
+
+impl<D: DeserializeOwned> Index<D> {
+    fn add_dir(&mut self, dir: impl AsRef<Path>) -> Result<()> {
+        for e in std::fs::read_dir(dir.as_ref())? {
+            let e = e?;
+            let name = e.file_name();
+            let name = name.to_str().context("invalid utf-8 file name in index")?;
+            if name.starts_with('.') {
+                // Ignore hidden directories like .git and .github.
+            } else if e.file_type()?.is_dir() {
+                self.add_dir(e.path())?;
+            } else if name != "config.json" && !name.ends_with(".partial") {
+                self.add_file(&e.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn add_file(&mut self, path: &Path) -> Result<()> {
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .context("invalid utf-8 file name in index")?;
+        let content =
+            std::fs::read_to_string(path).with_context(|| format!("unable to read {path:?}"))?;
+        let mut entry = BTreeMap::default();
+        let mut crate_name = None;
+        for line in content.lines() {
+            let metadata = serde_json::from_str::<Metadata<D>>(line)
+                .with_context(|| format!("unable to parse {path:?}"))?;
+            assert!(
+                name.eq_ignore_ascii_case(&metadata.name),
+                "{path:?} contains unexpected crate name {:?}",
+                metadata.name,
+            );
+            crate_name = Some(metadata.name);
+            entry.insert(metadata.vers, metadata.data);
+        }
+        if let Some(crate_name) = crate_name {
+            self.crates
+                .try_insert(crate_name, entry)
+                .map_err(|e| anyhow!("duplicate crate {:?} in index", e.entry.key()))?;
+        }
+        Ok(())
+    }
+
+    /// Read only the given files (relative to the index root), such as the ones that changed.
+    ///
+    /// Files that don't exist (anymore) are skipped.
+    pub fn read_files(files: impl IntoIterator<Item = impl AsRef<str>>) -> Result<Self> {
+        let mut index = Index {
+            crates: BTreeMap::new(),
+        };
+        for file in files {
+            let file = file.as_ref();
+            let path = Path::new("crates.io-index").join(file);
+            if !file.starts_with('.') && file != "config.json" && path.exists() {
+                index.add_file(&path)?;
+            }
+        }
+        Ok(index)
+    }
+
+    pub fn read() -> Result<Self> {
+        let mut index = Index {
+            crates: BTreeMap::new(),
+        };
+
+        index.add_dir("crates.io-index")?;
+
+        Ok(index)
+    }
+}
+
+impl Index {
+    /// Like [`Index::read`], but only parses the files that changed since the last time.
+    ///
+    /// The previous result is kept in index-cache.tsv in the mirror, with the
+    /// commit it is for. Without git history (like with the sparse index),
+    /// or if that commit is gone, this reads everything.
+    pub fn read_cached() -> Result<Self> {
+        if !Path::new("crates.io-index/.git").exists() {
+            return Self::read();
+        }
+        let repo = open()?;
+        // Local changes would be missed when they are undone, so only use the cache with a clean checkout.
+        if !is_clean(&repo)? {
+            return Self::read();
+        }
+        let head = repo.head_id()?.to_string();
+        let cached = match Self::read_cache() {
+            Ok(cached) => cached,
+            Err(e) => {
+                println!("warning: ignoring {CACHE_FILE}: {e:#}");
+                None
+            }
+        };
+        let index = match cached {
+            Some((commit, mut index)) => match changed_files(&repo, &commit, &head) {
+                Ok(files) if files.is_empty() => return Ok(index),
+                Ok(files) => {
+                    index.update_files(&files)?;
+                    index
+                }
+                Err(_) => Self::read()?,
+            },
+            None => Self::read()?,
+        };
+        if let Err(e) = index.write_cache(&head) {
+            println!("warning: unable to write {CACHE_FILE}: {e:#}");
+        }
+        Ok(index)
+    }
+
+    /// Re-read the given files (relative to the index root), replacing what was read before.
+    fn update_files(&mut self, files: &[String]) -> Result<()> {
+        let names: HashMap<String, String> = self
+            .crates
+            .keys()
+            .map(|name| (name.to_ascii_lowercase(), name.clone()))
+            .collect();
+        for file in files {
+            let path = Path::new("crates.io-index").join(file);
+            let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            if file.starts_with('.') || file == "config.json" {
+                continue;
+            }
+            if let Some(name) = names.get(&name.to_ascii_lowercase()) {
+                self.crates.remove(name);
+            }
+            if path.is_file() {
+                self.add_file(&path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The cached index and the commit it is for, if there is a cache.
+    fn read_cache() -> Result<Option<(String, Self)>> {
+        let content = match read_to_string(CACHE_FILE) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = content.lines();
+        let commit = lines.next().context("empty file")?.to_string();
+        let mut index = Index::default();
+        for line in lines {
+            let mut fields = line.split('\t');
+            let mut next = || fields.next().context("truncated line");
+            let (name, version, cksum, yanked, rust_version, pubtime) =
+                (next()?, next()?, next()?, next()?, next()?, next()?);
+            index.crates.entry(name.to_string()).or_default().insert(
+                version.to_string(),
+                CrateData {
+                    cksum: cksum.to_string(),
+                    yanked: yanked == "1",
+                    rust_version: (!rust_version.is_empty()).then(|| rust_version.to_string()),
+                    pubtime: (!pubtime.is_empty()).then(|| pubtime.to_string()),
+                    details: (),
+                },
+            );
+        }
+        Ok(Some((commit, index)))
+    }
+
+    fn write_cache(&self, commit: &str) -> Result<()> {
+        let partial = format!("{CACHE_FILE}.partial");
+        let mut out = BufWriter::new(File::create(&partial)?);
+        writeln!(out, "{commit}")?;
+        for (name, versions) in &self.crates {
+            for (version, data) in versions {
+                writeln!(
+                    out,
+                    "{name}\t{version}\t{}\t{}\t{}\t{}",
+                    data.cksum,
+                    data.yanked as u8,
+                    data.rust_version.as_deref().unwrap_or_default(),
+                    data.pubtime.as_deref().unwrap_or_default()
+                )?;
+            }
+        }
+        out.into_inner()?.sync_all()?;
+        rename(partial, CACHE_FILE)?;
+        Ok(())
+    }
+
+    /// Fetch the index from `url` through `client` and reset to the latest commit.
+    ///
+    /// With `verify`, the latest commit must have a valid signature, otherwise the index is left as is.
+    ///
+    /// With `shallow`, only the latest commit is fetched, and an existing full clone is
+    /// replaced by a shallow one.
+    pub fn update(
+        client: &Client,
+        url: &str,
+        shallow: bool,
+        verify: bool,
+        allowed_signers: Option<&Path>,
+    ) -> Result<()> {
+        if !Path::new("crates.io-index").exists() {
+            println!("Cloning {url}...");
+            clone(client, url, "crates.io-index", shallow)?;
+        }
+
+        let mut repo = open()?;
+        let remote = repo
+            .find_remote("origin")
+            .context("unable to read index remote")?;
+        let origin = remote
+            .url(gix::remote::Direction::Fetch)
+            .context("index remote has no url")?
+            .to_bstring()
+            .to_string();
+        ensure!(
+            origin == url,
+            "crates.io-index is a clone of {origin}, not of {url} (see --index-url)"
+        );
+
+        if shallow && !repo.is_shallow()? {
+            println!("Replacing the index by a shallow clone...");
+            let _ = remove_dir_all("crates.io-index.shallow");
+            clone(client, url, "crates.io-index.shallow", true)?;
+            rename("crates.io-index", "crates.io-index.full")?;
+            rename("crates.io-index.shallow", "crates.io-index")?;
+            remove_dir_all("crates.io-index.full")
+                .context("unable to remove the full clone of the index")?;
+            repo = open()?;
+        }
+
+        let commit = fetch(client, &repo, shallow)?;
+
+        // The default branch of the remote, which is master for crates.io.
+        if verify {
+            Self::verify_commit(&commit, allowed_signers)?;
+        }
+
+        reset(&repo, &commit)
+    }
+
+    /// Fetch the index, and return the latest commit (HEAD) of the remote, without checking it out.
+    pub fn fetch(client: &Client, shallow: bool) -> Result<String> {
+        fetch(client, &open()?, shallow)
+    }
+
+    /// Check out `commit`, like `git reset --hard`.
+    pub fn reset(commit: &str) -> Result<()> {
+        reset(&open()?, commit)
+    }
+
+    /// Check that the remote of the index can be reached, like `git ls-remote`.
+    pub fn check_remote(client: &Client) -> Result<()> {
+        let repo = open()?;
+        let remote = repo
+            .find_remote("origin")
+            .context("unable to read index remote")?;
+        git_http::connect(&remote, client)?.ref_map(Discard, Default::default())?;
+        Ok(())
+    }
+
+    /// Check that `git` is available for `feature`, before doing anything else.
+    ///
+    /// The index itself doesn't need it, but [`Index::verify_commit`], bundles,
+    /// push and ingest do.
+    pub fn check_git(feature: &str) -> Result<()> {
+        Command::new("git")
+            .arg("--version")
+            .output()
+            .with_context(|| format!("{feature} needs git, which is not installed"))?;
+        Ok(())
+    }
+
+    /// Check the signature of a commit in the index with `git verify-commit`.
+    pub fn verify_commit(rev: &str, allowed_signers: Option<&Path>) -> Result<()> {
+        let mut git = Command::new("git");
+        git.args(["-C", "crates.io-index"]);
+        if let Some(file) = allowed_signers {
+            git.arg("-c")
+                .arg(format!("gpg.ssh.allowedSignersFile={}", file.display()));
+        }
+        let output = git.args(["verify-commit", rev]).output()?;
+        output.status.exit_ok().with_context(|| {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let reason = match stderr.trim() {
+                "" => "no signature",
+                s => s,
+            };
+            format!("refusing to use unverified index commit {rev}: {reason}")
+        })?;
+        Ok(())
+    }
+
+    /// The hash of the commit the index is at.
+    pub fn head_commit() -> Result<String> {
+        let repo = open()?;
+        let head = repo.head_id().context("unable to read index commit")?;
+        Ok(head.to_string())
+    }
+
+    /// A description of the commit the index is at, such as `abc123 (2022-07-01 12:34:56 +0000)`.
+    pub fn head() -> Result<String> {
+        if !Path::new("crates.io-index/.git").exists() {
+            return sparse_index::head();
+        }
+        let repo = open()?;
+        let commit = repo.head_commit().context("unable to read index commit")?;
+        let time = commit
+            .time()?
+            .format_or_unix(gix::date::time::format::ISO8601);
+        Ok(format!("{} ({time})", commit.id))
+    }
+
+    /// The files that changed between two commits of the index, and the number of
+    /// lines added minus the number removed, which is the number of new versions.
+    pub fn changes(from: &str, to: &str) -> Result<(Vec<String>, i64)> {
+        let repo = open()?;
+        let mut files = Vec::new();
+        let mut n_lines = 0;
+        for change in diff(&repo, from, to)? {
+            let lines = |id: gix::ObjectId| -> Result<i64> {
+                let blob = repo.find_blob(id)?;
+                Ok(blob.data.iter().filter(|&&b| b == b'\n').count() as i64)
+            };
+            match &change {
+                ChangeDetached::Addition { id, .. } => n_lines += lines(*id)?,
+                ChangeDetached::Deletion { id, .. } => n_lines -= lines(*id)?,
+                ChangeDetached::Modification {
+                    previous_id, id, ..
+                } => n_lines += lines(*id)? - lines(*previous_id)?,
+                ChangeDetached::Rewrite { .. } => {}
+            }
+            files.push(change.location().to_string());
+        }
+        Ok((files, n_lines))
+    }
+}
+
+/// The changes between the trees of two commits, without looking for renames.
+fn diff(repo: &gix::Repository, from: &str, to: &str) -> Result<Vec<ChangeDetached>> {
+    let tree = |commit: &str| -> Result<gix::Tree<'_>> {
+        let id = gix::ObjectId::from_hex(commit.as_bytes())?;
+        Ok(repo.find_commit(id)?.tree()?)
+    };
+    let (old, new) = (tree(from)?, tree(to)?);
+    Ok(repo.diff_tree_to_tree(&old, &new, gix::diff::Options::default())?)
+}
+
+/// The index files that differ between two commits.
+fn changed_files(repo: &gix::Repository, from: &str, to: &str) -> Result<Vec<String>> {
+    Ok(diff(repo, from, to)?
+        .iter()
+        .map(|change| change.location().to_string())
+        .collect())
+}
+
+/// Whether the checkout of the index has no changes, including untracked files, like `git status`.
+fn is_clean(repo: &gix::Repository) -> Result<bool> {
+    with_progress(|progress| {
+        let mut status = repo
+            .status(progress)?
+            .untracked_files(gix::status::UntrackedFiles::Files)
+            .into_iter(Vec::new())?;
+        match status.next() {
+            None => Ok(true),
+            Some(item) => {
+                item?;
+                Ok(false)
+            }
+        }
+    })
+}
+
+fn open() -> Result<gix::Repository> {
+    let mut repo = gix::open("crates.io-index")?;
+    // For the reflog, like git does when there is no user configured.
+    repo.committer_or_set_generic_fallback()?;
+    Ok(repo)
+}
+
+/// Clone the index into `dir`, with only the latest commit if `shallow`.
+fn clone(client: &Client, url: &str, dir: &str, shallow: bool) -> Result<()> {
+    let interrupt = AtomicBool::new(false);
+    let client = client.clone();
+    let (mut checkout, _) = with_progress(|progress| {
+        gix::prepare_clone(url, dir)?
+            .with_shallow(depth(shallow))
+            .configure_connection(move |connection| {
+                if let Some(transport) = git_http::for_remote(connection.remote(), &client)? {
+                    *connection.transport_mut() = transport;
+                }
+                Ok(())
+            })
+            .fetch_then_checkout(progress, &interrupt)
+            .context("unable to clone the index")
+    })?;
+    let (_, outcome) = with_progress(|progress| {
+        checkout
+            .main_worktree(progress, &interrupt)
+            .context("unable to check out the index")
+    })?;
+    println!("Checked out {} index files", outcome.files_updated);
+    Ok(())
+}
+
+/// Run `f` with a progress tree for gitoxide, and report its progress every second,
+/// like the downloads do: on a line that is updated, or as `progress` events with `--output json`.
+fn with_progress<T>(f: impl FnOnce(tree::Item) -> Result<T>) -> Result<T> {
+    let root = tree::Root::new();
+    let progress = root.add_child("index");
+    let (done, wait) = mpsc::channel::<()>();
+    thread::scope(|s| {
+        let root = &root;
+        s.spawn(move || {
+            let mut tasks = Vec::new();
+            let mut shown = false;
+            // Until `done` is dropped.
+            while wait.recv_timeout(Duration::from_secs(1)) == Err(RecvTimeoutError::Timeout) {
+                root.sorted_snapshot(&mut tasks);
+                report(tasks.iter().map(|(_, task)| task), &mut shown);
+            }
+        });
+        let result = f(progress);
+        drop(done);
+        result
+    })
+}
+
+/// Show the unfinished tasks that made progress, like `Resolving: 1234/5678`.
+fn report<'a>(tasks: impl Iterator<Item = &'a Task>, shown: &mut bool) {
+    let tasks: Vec<_> = tasks
+        .filter_map(|task| {
+            let value = task.progress.as_ref()?;
+            let step = value.step.load(Relaxed);
+            let finished = value.done_at.is_some_and(|max| step >= max);
+            (step > 0 && !finished).then_some((task.name.as_str(), step, value.done_at))
+        })
+        .collect();
+    if output::is_json() {
+        for (name, step, max) in tasks {
+            output::event(
+                "progress",
+                serde_json::json!({ "task": name, "done": step, "total": max }),
+            );
+        }
+    } else if !tasks.is_empty() {
+        let line = tasks
+            .iter()
+            .map(|(name, step, max)| match max {
+                Some(max) => format!("{name}: {step}/{max}"),
+                None => format!("{name}: {step}"),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        // Replace the previous line, if this printed one.
+        let up = if *shown { "\x1b[A" } else { "" };
+        println!("{up}{line}\x1b[J");
+        *shown = true;
+    }
+}
+
+fn depth(shallow: bool) -> Shallow {
+    match shallow {
+        true => Shallow::DepthAtRemote(NonZeroU32::MIN),
+        false => Shallow::NoChange,
+    }
+}
+
+fn fetch(client: &Client, repo: &gix::Repository, shallow: bool) -> Result<String> {
+    let remote = repo
+        .find_remote("origin")
+        .context("unable to read index remote")?;
+    with_progress(|mut progress| {
+        git_http::connect(&remote, client)?
+            .prepare_fetch(progress.add_child("negotiate"), Default::default())?
+            .with_shallow(depth(shallow))
+            .receive(progress, &AtomicBool::new(false))
+            .context("unable to fetch the index")
+    })?;
+    // The remote branch that the checked out branch follows, as set up by the clone.
+    let head = repo.head_name()?.context("the index has a detached HEAD")?;
+    let tracking = repo
+        .branch_remote_tracking_ref_name(head.as_ref(), gix::remote::Direction::Fetch)
+        .context("the index branch has no upstream")?
+        .context("unable to read the upstream of the index branch")?;
+    let commit = repo.find_reference(&tracking)?.peel_to_id()?;
+    Ok(commit.to_string())
+}
+
+/// Write the files that differ between the checkout and `commit`, and move the branch to it.
+fn reset(repo: &gix::Repository, commit: &str) -> Result<()> {
+    let id = gix::ObjectId::from_hex(commit.as_bytes())?;
+    let tree = repo.find_commit(id)?.tree_id()?;
+    let old = repo.index_or_empty()?;
+    let mut new = repo.index_from_tree(&tree)?;
+    let workdir = repo.workdir().context("index has no work tree")?.to_owned();
+
+    let mut n_changed = 0;
+    for (entry, path) in new.entries_mut_with_paths() {
+        let file = workdir.join(gix::path::from_bstr(path)?);
+        // Like `git reset --hard`, also restore files that were changed locally.
+        let unchanged = match (
+            old.entry_by_path(path),
+            FileMetadata::from_path_no_follow(&file),
+        ) {
+            (Some(old_entry), Ok(metadata)) if old_entry.id == entry.id => {
+                let stat = Stat::from_fs(&metadata)?;
+                // Like git, the file is only read if its stat changed, or if it
+                // might have changed in the second the index was written.
+                let same = if stat == old_entry.stat
+                    && !stat.is_racy(old.timestamp(), Default::default())
+                {
+                    true
+                } else {
+                    std::fs::read(&file)? == repo.find_blob(entry.id)?.data
+                };
+                same.then_some(stat)
+            }
+            _ => None,
+        };
+        match unchanged {
+            Some(stat) => entry.stat = stat,
+            None => {
+                if let Some(dir) = file.parent() {
+                    create_dir_all(dir)?;
+                }
+                // Renamed into place, as the static index and the local registry
+                // hard link these files, so they must never be changed in place.
+                let mut partial = file.clone().into_os_string();
+                partial.push(".partial");
+                std::fs::write(&partial, repo.find_blob(entry.id)?.data.as_slice())?;
+                rename(&partial, &file)?;
+                entry.stat = Stat::from_fs(&FileMetadata::from_path_no_follow(&file)?)?;
+                n_changed += 1;
+            }
+        }
+    }
+    let mut n_removed = 0;
+    for entry in old.entries() {
+        let path = entry.path(&old);
+        if new.entry_by_path(path).is_none() {
+            let file = workdir.join(gix::path::from_bstr(path)?);
+            let _ = remove_file(&file);
+            // Like git, don't leave empty directories behind.
+            for dir in file.ancestors().skip(1).take_while(|dir| *dir != workdir) {
+                if remove_dir(dir).is_err() {
+                    break;
+                }
+            }
+            n_removed += 1;
+        }
+    }
+    new.write(Default::default())?;
+
+    let message = format!("reset: moving to {commit}");
+    match repo.head_name()? {
+        Some(branch) => {
+            repo.reference(branch, id, PreviousValue::Any, message)?;
+        }
+        None => bail!("the index has a detached HEAD"),
+    }
+    if n_changed + n_removed > 0 {
+        println!("Index at {commit}: {n_changed} files changed, {n_removed} removed");
+    }
+    Ok(())
+}
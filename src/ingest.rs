@@ -0,0 +1,188 @@
+//! The receiving side of `--push-to`: an authenticated endpoint that accepts
+//! index updates and crate files from an upstream cratesync.
+//!
+//! Endpoints:
+//!
+//!  - `GET /index/head`: the commit the local index is at (empty if none).
+//!  - `PUT /index/bundle`: a git bundle to fast-forward (or replace) the index with.
+//!  - `GET /missing`: all crate files referenced by the index that we don't have yet.
+//!  - `PUT /crates/{name}/{name}-{version}.crate`: a crate file, verified against the index.
+
+use crate::{
+    check_checksum, cold,
+    index::Index,
+    store::{Hasher, Store},
+};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fs::{create_dir_all, remove_file, File},
+    hint::black_box,
+    io,
+    path::Path,
+    process::Command,
+    sync::{Mutex, RwLock},
+    thread,
+};
+use tiny_http::{Method, Request, Response, Server};
+
+pub fn serve(listen: &str, token: &str, store: &Store) -> Result<()> {
+    let index = if Path::new("crates.io-index").exists() {
+        println!("Loading index...");
+        Index::read()?
+    } else {
+        Index::default()
+    };
+    let index = RwLock::new(index);
+    let index_update = Mutex::new(());
+
+    let server = Server::http(listen).map_err(|e| anyhow!("unable to listen on {listen}: {e}"))?;
+    println!("Listening for pushes on {listen}");
+
+    thread::scope(|s| {
+        for _ in 0..16 {
+            s.spawn(|| {
+                for mut request in server.incoming_requests() {
+                    let expected = format!("Bearer {token}");
+                    let authorized = request.headers().iter().any(|h| {
+                        h.field.equiv("Authorization")
+                            && constant_time_eq(h.value.as_str().as_bytes(), expected.as_bytes())
+                    });
+                    let response = if !authorized {
+                        Response::from_string("unauthorized").with_status_code(401)
+                    } else {
+                        match handle(&mut request, &index, &index_update, store) {
+                            Ok(r) => r,
+                            Err(e) => {
+                                println!("error: {e:#}");
+                                Response::from_string(format!("{e:#}")).with_status_code(500)
+                            }
+                        }
+                    };
+                    let _ = request.respond(response);
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn handle(
+    request: &mut Request,
+    index: &RwLock<Index>,
+    index_update: &Mutex<()>,
+    store: &Store,
+) -> Result<Response<io::Cursor<Vec<u8>>>> {
+    let url = request.url().to_string();
+    Ok(match (request.method(), url.as_str()) {
+        (Method::Get, "/index/head") => {
+            let head = if Path::new("crates.io-index").exists() {
+                let repo = gix::open("crates.io-index")?;
+                // An empty repository has no HEAD yet.
+                repo.head()?.id().map_or(String::new(), |id| id.to_string())
+            } else {
+                String::new()
+            };
+            Response::from_string(head)
+        }
+        (Method::Put, "/index/bundle") => {
+            let _guard = index_update.lock().unwrap();
+            io::copy(request.as_reader(), &mut File::create("index.bundle")?)?;
+            apply_bundle("index.bundle", "HEAD")?;
+            remove_file("index.bundle")?;
+            println!("Received index update, reloading index...");
+            let new_index = Index::read()?;
+            *index.write().unwrap() = new_index;
+            store.index_updated();
+            println!("Index updated");
+            Response::from_string("ok")
+        }
+        (Method::Get, "/missing") => {
+            let mut missing = String::new();
+            for (name, versions) in &index.read().unwrap().crates {
+                for version in versions.keys() {
+                    let file = format!("crates/{name}/{name}-{version}.crate");
+                    if !cold::exists(&file) {
+                        missing += &file;
+                        missing += "\n";
+                    }
+                }
+            }
+            Response::from_string(missing)
+        }
+        (Method::Put, path) if path.starts_with("/crates/") => {
+            // Only accept exactly the file names that the index refers to.
+            let cksum = || -> Option<String> {
+                let (name, file) = path.strip_prefix("/crates/")?.split_once('/')?;
+                let version = file
+                    .strip_prefix(name)?
+                    .strip_prefix('-')?
+                    .strip_suffix(".crate")?;
+                let index = index.read().unwrap();
+                Some(index.crates.get(name)?.get(version)?.cksum.clone())
+            }();
+            let Some(cksum) = cksum else {
+                return Ok(Response::from_string("not in index").with_status_code(404));
+            };
+            let file = &path[1..];
+            // The same file might be pushed twice at the same time, by another thread.
+            let partial_file = format!("{file}.{:?}.partial", thread::current().id());
+            create_dir_all(Path::new(file).parent().unwrap())?;
+            let mut f = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&partial_file)?;
+            let mut hasher = Hasher::default();
+            store.copy(request.as_reader(), &mut f, Some(&mut hasher))?;
+            let hashes = match check_checksum(hasher, file, &cksum) {
+                Ok(hashes) => hashes,
+                Err(e) => return Ok(Response::from_string(format!("{e:#}")).with_status_code(422)),
+            };
+            drop(f);
+            store.commit(&partial_file, file, &hashes)?;
+            Response::from_string("ok")
+        }
+        _ => Response::from_string("not found").with_status_code(404),
+    })
+}
+
+/// Compare two byte strings in time that doesn't depend on where they differ,
+/// such that the token can't be guessed byte by byte from the response times.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let diff = a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y));
+    a.len() == b.len() && black_box(diff) == 0
+}
+
+/// Fast-forward (or replace) the index with `rev` of a git bundle.
+pub fn apply_bundle(bundle: &str, rev: &str) -> Result<()> {
+    if !Path::new("crates.io-index").exists() {
+        git(&["init", "--quiet", "crates.io-index"])?;
+    }
+    git(&[
+        "-C",
+        "crates.io-index",
+        "fetch",
+        "--quiet",
+        &format!("../{bundle}"),
+        rev,
+    ])?;
+    git(&[
+        "-C",
+        "crates.io-index",
+        "reset",
+        "--quiet",
+        "--hard",
+        "FETCH_HEAD",
+    ])
+}
+
+pub fn git(args: &[&str]) -> Result<()> {
+    Command::new("git")
+        .args(args)
+        .spawn()?
+        .wait()?
+        .exit_ok()
+        .with_context(|| format!("git {} failed", args.join(" ")))
+}
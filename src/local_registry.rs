@@ -0,0 +1,178 @@
+//! Laying out the mirror as a cargo local registry, with `--layout local-registry`.
+//!
+//! A local registry is a directory with the crate files as `{name}-{version}.crate`
+//! and the index in `index/`, which cargo uses without any server:
+//!
+//! ```toml
+//! [source.crates-io]
+//! replace-with = "mirror"
+//!
+//! [source.mirror]
+//! local-registry = "/path/to/mirror/local-registry"
+//! ```
+//!
+//! The crate files are hard links to the files in crates/ where possible, so
+//! they don't take extra space. The index only has the versions whose crate
+//! file is in the mirror, such that cargo doesn't pick versions it can't get.
+
+use crate::{
+    cold,
+    state::{parse_file, State},
+};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::HashSet,
+    fs::{copy, create_dir_all, hard_link, metadata, read, read_dir, remove_file, rename, write},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+/// The directory (in the mirror) of the local registry, unless --local-registry-dir says otherwise.
+pub const DIR: &str = "local-registry";
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Layout {
+    /// Only the mirror itself, with the index in crates.io-index/ and the crate files in crates/.
+    Mirror,
+    /// Also a cargo local registry in --local-registry-dir, with hard links to the files of the mirror.
+    LocalRegistry,
+}
+
+#[derive(Deserialize)]
+struct IndexLine {
+    name: String,
+    vers: String,
+}
+
+/// Make `dir` a local registry with the crate files of the mirror.
+pub fn export(dir: &Path) -> Result<()> {
+    let present = State::open()?.present()?;
+
+    // The crate files, first, such that the index never lists a file that isn't there yet.
+    create_dir_all(dir)?;
+    let mut wanted = HashSet::new();
+    let mut n_added = 0;
+    for file in &present {
+        let Some((name, version)) = parse_file(file) else {
+            continue;
+        };
+        let target = dir.join(format!("{name}-{version}.crate"));
+        if !target.exists() {
+            if Path::new(file).exists() {
+                link(Path::new(file), &target)?;
+            } else {
+                // Stored with zstd (see `cold`), so it has to be restored instead.
+                let partial = target.with_extension("partial");
+                write(&partial, cold::read_file(file)?)?;
+                rename(&partial, &target)?;
+            }
+            n_added += 1;
+        }
+        wanted.insert(target);
+    }
+    let mut n_removed = 0;
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|e| e == "crate") && !wanted.contains(&path) {
+            remove_file(&path)?;
+            n_removed += 1;
+        }
+    }
+
+    let index_dir = dir.join("index");
+    let mut files = Vec::new();
+    add_files(Path::new("crates.io-index"), Path::new(""), &mut files)?;
+    let mut n_updated = 0;
+    let mut kept = HashSet::new();
+    for file in &files {
+        let source = Path::new("crates.io-index").join(file);
+        let target = index_dir.join(file);
+        let contents = read(&source)?;
+        let mut filtered = Vec::new();
+        let mut complete = true;
+        for line in contents.split(|&b| b == b'\n').filter(|l| !l.is_empty()) {
+            let IndexLine { name, vers } = serde_json::from_slice(line)
+                .with_context(|| format!("unable to parse {}", source.display()))?;
+            if present.contains(&format!("crates/{name}/{name}-{vers}.crate")) {
+                filtered.extend_from_slice(line);
+                filtered.push(b'\n');
+            } else {
+                complete = false;
+            }
+        }
+        if filtered.is_empty() {
+            continue;
+        }
+        kept.insert(target.clone());
+        let source_meta = metadata(&source)?;
+        let target_meta = metadata(&target).ok();
+        if complete {
+            if target_meta
+                .is_some_and(|t| t.dev() == source_meta.dev() && t.ino() == source_meta.ino())
+            {
+                continue;
+            }
+            create_dir_all(target.parent().unwrap())?;
+            link(&source, &target)?;
+        } else {
+            if target_meta.is_some_and(|t| t.ino() != source_meta.ino())
+                && read(&target)? == filtered
+            {
+                continue;
+            }
+            create_dir_all(target.parent().unwrap())?;
+            let partial = target.with_extension("partial");
+            write(&partial, filtered)?;
+            rename(partial, &target)?;
+        }
+        n_updated += 1;
+    }
+    let mut existing = Vec::new();
+    if index_dir.exists() {
+        add_files(&index_dir, Path::new(""), &mut existing)?;
+    }
+    for file in existing {
+        let path = index_dir.join(file);
+        if !kept.contains(&path) {
+            remove_file(path)?;
+        }
+    }
+
+    println!(
+        "Local registry at {}: {n_added} crate files added, {n_removed} removed, {n_updated} index files updated",
+        dir.display()
+    );
+    Ok(())
+}
+
+/// Make `target` a hard link to `source`, or a copy if that isn't possible.
+fn link(source: &Path, target: &Path) -> Result<()> {
+    let partial = target.with_extension("partial");
+    let _ = remove_file(&partial);
+    // Fall back to copying if hard links aren't possible, like between file systems.
+    if hard_link(source, &partial).is_err() {
+        copy(source, &partial)?;
+    }
+    rename(&partial, target)?;
+    Ok(())
+}
+
+/// Add the paths (relative to `root`) of the index files in `root`/`dir`.
+fn add_files(root: &Path, dir: &Path, files: &mut Vec<String>) -> Result<()> {
+    for e in read_dir(root.join(dir))? {
+        let e = e?;
+        let name = e.file_name();
+        let name = name.to_str().context("invalid utf-8 file name in index")?;
+        if name.starts_with('.') || name.ends_with(".partial") || name == "config.json" {
+            continue;
+        }
+        let path = dir.join(name);
+        if e.file_type()?.is_dir() {
+            add_files(root, &path, files)?;
+        } else {
+            files.push(path.to_str().unwrap().to_string());
+        }
+    }
+    Ok(())
+}
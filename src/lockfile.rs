@@ -0,0 +1,120 @@
+//! Selecting only the crate versions that some projects use, from their Cargo.lock files.
+//!
+//! This is for mirrors that only need to serve a known set of projects, like
+//! the images of an air-gapped CI. Dependencies from other registries (than
+//! the mirrored one), git and paths are ignored.
+
+use crate::{index::Index, registry};
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs::{read_dir, read_to_string},
+    path::{Path, PathBuf},
+};
+
+/// The ways that crates.io is referred to as `source` in lockfiles.
+const CRATES_IO: [&str; 2] = [
+    "registry+https://github.com/rust-lang/crates.io-index",
+    "sparse+https://index.crates.io/",
+];
+
+#[derive(Deserialize)]
+struct Lockfile {
+    #[serde(default)]
+    package: Vec<Package>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    name: String,
+    version: String,
+    source: Option<String>,
+    /// Not present in the oldest lockfile format, which keeps them in a separate table.
+    checksum: Option<String>,
+}
+
+/// The versions of `index` used by the given lockfiles, or all lockfiles in the given directories.
+///
+/// `index_url` is the git index that is mirrored, for registries other than crates.io.
+pub fn select(index: &Index, index_url: &str, paths: &[PathBuf]) -> Result<Index> {
+    let source = format!("registry+{index_url}");
+    let sources: &[&str] = if index_url == registry::CRATES_IO {
+        &CRATES_IO
+    } else {
+        &[&source]
+    };
+    let mut files = Vec::new();
+    for path in paths {
+        find(path, &mut files)?;
+    }
+
+    let mut selected = Index {
+        crates: BTreeMap::new(),
+    };
+    for file in &files {
+        let lockfile: Lockfile = toml::from_str(&read_to_string(file)?)
+            .with_context(|| format!("unable to parse {}", file.display()))?;
+        for package in lockfile.package {
+            if !package
+                .source
+                .as_deref()
+                .is_some_and(|s| sources.contains(&s))
+            {
+                continue;
+            }
+            let (name, version) = (&package.name, &package.version);
+            let Some(data) = index.crates.get(name).and_then(|c| c.get(version)) else {
+                println!(
+                    "warning: {name} {version} from {} is not in the index",
+                    file.display()
+                );
+                continue;
+            };
+            if let Some(checksum) = &package.checksum {
+                if *checksum != data.cksum {
+                    println!(
+                        "error: checksum of {name} {version} in {} ({checksum}) does not match the index ({})",
+                        file.display(),
+                        data.cksum
+                    );
+                    continue;
+                }
+            }
+            selected
+                .crates
+                .entry(name.clone())
+                .or_default()
+                .insert(version.clone(), data.clone());
+        }
+    }
+
+    println!(
+        "Selected {} versions of {} crates used by {} lockfiles",
+        selected.crates.values().map(|c| c.len()).sum::<usize>(),
+        selected.crates.len(),
+        files.len(),
+    );
+    Ok(selected)
+}
+
+/// Add `path` to `files` if it's a file, or the `.lock` files in it (recursively) if it's a directory.
+fn find(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if !path.is_dir() {
+        files.push(path.to_path_buf());
+        return Ok(());
+    }
+    let mut entries = read_dir(path)
+        .with_context(|| format!("unable to read {}", path.display()))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            find(&path, files)?;
+        } else if path.extension().is_some_and(|e| e == "lock") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,49 @@
+//! Signed manifests of the mirror contents.
+//!
+//! The manifest lists the SHA-256 of every crate file in the mirror, in the
+//! format of `sha256sum`, so it can be checked with `sha256sum -c manifest.sha256`.
+//! It is signed with minisign, with the index commit in the trusted comment.
+
+use crate::{cold, index::Index};
+use anyhow::{Context, Result};
+use std::{
+    fs::{rename, write, File},
+    path::Path,
+};
+
+pub fn write_signed(index: &Index, key: &Path, password: Option<String>) -> Result<()> {
+    let key = minisign::SecretKey::from_file(key, password)
+        .with_context(|| format!("unable to load minisign key {key:?}"))?;
+
+    let mut manifest = String::new();
+    let mut n = 0;
+    for (name, versions) in &index.crates {
+        for (version, data) in versions {
+            // All files present have been verified against the index when they were downloaded.
+            let file = format!("crates/{name}/{name}-{version}.crate");
+            if cold::exists(&file) {
+                manifest += &format!("{}  {file}\n", data.cksum);
+                n += 1;
+            }
+        }
+    }
+    write("manifest.sha256.partial", manifest)?;
+
+    let signature = minisign::sign(
+        None,
+        &key,
+        File::open("manifest.sha256.partial")?,
+        Some(&format!(
+            "cratesync manifest of {n} files, index {}",
+            Index::head()?
+        )),
+        None,
+    )?;
+    write("manifest.sha256.minisig.partial", signature.to_string())?;
+    rename("manifest.sha256.partial", "manifest.sha256")?;
+    rename("manifest.sha256.minisig.partial", "manifest.sha256.minisig")?;
+
+    println!("Wrote signed manifest of {n} crate files");
+
+    Ok(())
+}
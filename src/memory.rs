@@ -0,0 +1,66 @@
+//! Bounding the memory used for response bodies that are in flight, for `--max-memory`.
+
+use std::sync::{Condvar, Mutex};
+use tokio::sync::Notify;
+
+pub struct Budget {
+    total: usize,
+    available: Mutex<usize>,
+    freed: Condvar,
+    /// Like `freed`, but for async downloads.
+    freed_async: Notify,
+}
+
+impl Budget {
+    pub fn new(total: usize) -> Self {
+        Self {
+            total,
+            available: Mutex::new(total),
+            freed: Condvar::new(),
+            freed_async: Notify::new(),
+        }
+    }
+
+    /// Wait until `n` bytes are available, and reserve them until the returned value is dropped.
+    ///
+    /// Asking for more than the total gets the total, to not wait forever.
+    pub fn reserve(&self, n: usize) -> Reservation<'_> {
+        let n = n.min(self.total);
+        let mut available = self
+            .freed
+            .wait_while(self.available.lock().unwrap(), |a| *a < n)
+            .unwrap();
+        *available -= n;
+        Reservation { budget: self, n }
+    }
+
+    /// Like [`Budget::reserve`], but without blocking the thread.
+    pub async fn reserve_async(&self, n: usize) -> Reservation<'_> {
+        let n = n.min(self.total);
+        loop {
+            // Before checking, such that memory freed after the check isn't missed.
+            let freed = self.freed_async.notified();
+            {
+                let mut available = self.available.lock().unwrap();
+                if *available >= n {
+                    *available -= n;
+                    return Reservation { budget: self, n };
+                }
+            }
+            freed.await;
+        }
+    }
+}
+
+pub struct Reservation<'a> {
+    budget: &'a Budget,
+    n: usize,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        *self.budget.available.lock().unwrap() += self.n;
+        self.budget.freed.notify_all();
+        self.budget.freed_async.notify_waiters();
+    }
+}
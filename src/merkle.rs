@@ -0,0 +1,203 @@
+//! A Merkle tree over the crate files in the mirror.
+//!
+//! The tree follows the layout of the index: the root has the first level of
+//! prefixes (like `1`, `3` or `se`) as children, those have the second level
+//! (like `3/a` or `se/rd`) as children, and those have the crates (like
+//! `se/rd/serde`). The hash of a crate covers the versions and checksums of the
+//! crate files that are present. The hash of every other node covers the names
+//! and hashes of its children. Crates without any files present are left out.
+//!
+//! The nodes down to the crates are stored in merkle.txt, as lines of `path hash`.
+//! Two mirrors have the same files if their root hashes are equal, and otherwise
+//! the differing crates can be found by only descending into differing nodes.
+
+use crate::{
+    cold,
+    index::{CrateData, Index},
+    state::State,
+};
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    fs::{metadata, read_to_string, rename, write},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+pub const FILE: &str = "merkle.txt";
+
+#[derive(Default)]
+pub struct Merkle {
+    /// path -> hash, with the root at "".
+    nodes: BTreeMap<String, String>,
+}
+
+/// The path of a crate in the index, like `se/rd/serde`.
+pub fn crate_path(name: &str) -> String {
+    let name = name.to_lowercase();
+    match name.len() {
+        1 => format!("1/{name}"),
+        2 => format!("2/{name}"),
+        3 => format!("3/{}/{name}", &name[..1]),
+        _ => format!("{}/{}/{name}", &name[..2], &name[2..4]),
+    }
+}
+
+fn hash(lines: impl IntoIterator<Item = String>) -> String {
+    let mut hasher = Sha256::new();
+    for line in lines {
+        hasher.update(line);
+        hasher.update("\n");
+    }
+    base16ct::lower::encode_string(&hasher.finalize())
+}
+
+/// The children of a crate: the versions of the crate files present, with their checksums.
+pub fn crate_files<D>(
+    name: &str,
+    versions: &BTreeMap<String, CrateData<D>>,
+    is_present: impl Fn(&str) -> bool,
+) -> Vec<(String, String)> {
+    versions
+        .iter()
+        .filter(|(version, _)| is_present(&format!("crates/{name}/{name}-{version}.crate")))
+        .map(|(version, data)| (version.clone(), data.cksum.clone()))
+        .collect()
+}
+
+impl Merkle {
+    /// Compute the tree for the whole index.
+    pub fn compute(index: &Index) -> Result<Self> {
+        let mut merkle = Merkle::default();
+        merkle.update(index)?;
+        Ok(merkle)
+    }
+
+    /// Update the tree for the crates in `index`, which can be a partial index of only the changed crates.
+    ///
+    /// Which crate files are present comes from the state database, rather than from the file system.
+    pub fn update(&mut self, index: &Index) -> Result<()> {
+        let present = State::open()?.present()?;
+        let mut dirty = BTreeSet::new();
+        for (name, versions) in &index.crates {
+            let path = crate_path(name);
+            let files = crate_files(name, versions, |file| present.contains(file));
+            if files.is_empty() {
+                self.nodes.remove(&path);
+            } else {
+                let hash = hash(files.into_iter().map(|(v, cksum)| format!("{v} {cksum}")));
+                self.nodes.insert(path.clone(), hash);
+            }
+            let mut path = path.as_str();
+            while let Some((parent, _)) = path.rsplit_once('/') {
+                dirty.insert(parent.to_string());
+                path = parent;
+            }
+        }
+        dirty.insert(String::new());
+        // Deepest first, such that children are up to date before their parents.
+        let mut dirty: Vec<String> = dirty.into_iter().collect();
+        dirty.sort_by_key(|p| Reverse(p.matches('/').count() + usize::from(!p.is_empty())));
+        for path in dirty {
+            let children = self.children(&path);
+            if children.is_empty() && !path.is_empty() {
+                self.nodes.remove(&path);
+            } else {
+                let hash = hash(
+                    children
+                        .into_iter()
+                        .map(|(name, hash)| format!("{name} {hash}")),
+                );
+                self.nodes.insert(path, hash);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn root(&self) -> &str {
+        &self.nodes[""]
+    }
+
+    /// The hash of a node.
+    pub fn get(&self, path: &str) -> Option<&str> {
+        self.nodes.get(path).map(|h| h.as_str())
+    }
+
+    /// The names and hashes of the direct children of a node (except for the files of a crate).
+    pub fn children(&self, path: &str) -> Vec<(String, String)> {
+        let prefix = if path.is_empty() {
+            String::new()
+        } else {
+            format!("{path}/")
+        };
+        self.nodes
+            .range(prefix.clone()..)
+            .skip_while(|(p, _)| p.is_empty())
+            .take_while(|(p, _)| p.starts_with(&prefix))
+            .filter(|(p, _)| !p[prefix.len()..].contains('/'))
+            .map(|(p, h)| (p[prefix.len()..].to_string(), h.clone()))
+            .collect()
+    }
+
+    pub fn read() -> Result<Self> {
+        let mut nodes = BTreeMap::new();
+        for line in read_to_string(FILE)?.lines() {
+            let (path, hash) = line
+                .rsplit_once(' ')
+                .with_context(|| format!("invalid line in {FILE}"))?;
+            nodes.insert(path.to_string(), hash.to_string());
+        }
+        Ok(Merkle { nodes })
+    }
+
+    /// Read the tree, reusing the previously read one if the file didn't change.
+    pub fn read_cached() -> Result<Arc<Self>> {
+        static CACHE: Mutex<Option<(SystemTime, Arc<Merkle>)>> = Mutex::new(None);
+        let mtime = metadata(FILE)?.modified()?;
+        let mut cache = CACHE.lock().unwrap();
+        match &*cache {
+            Some((t, merkle)) if *t == mtime => Ok(merkle.clone()),
+            _ => {
+                let merkle = Arc::new(Self::read()?);
+                *cache = Some((mtime, merkle.clone()));
+                Ok(merkle)
+            }
+        }
+    }
+
+    pub fn write(&self) -> Result<()> {
+        let mut content = String::new();
+        for (path, hash) in &self.nodes {
+            content += &format!("{path} {hash}\n");
+        }
+        write(format!("{FILE}.partial"), content)?;
+        rename(format!("{FILE}.partial"), FILE)?;
+        Ok(())
+    }
+}
+
+/// The hash of a node on the first line, followed by `name hash` lines for its children,
+/// or `version cksum` lines for the files of a crate.
+///
+/// This is what the server responds with at `/merkle/{path}`.
+pub fn listing(path: &str) -> Result<Option<String>> {
+    let merkle = Merkle::read_cached()?;
+    let Some(hash) = merkle.get(path) else {
+        return Ok(None);
+    };
+    let mut children = merkle.children(path);
+    if children.is_empty() && !path.is_empty() {
+        // A crate, whose files are listed in its index file.
+        let index: Index = Index::read_files([path])?;
+        for (name, versions) in &index.crates {
+            children = crate_files(name, versions, cold::exists);
+        }
+    }
+    let mut listing = format!("{hash}\n");
+    for (name, hash) in children {
+        listing += &format!("{name} {hash}\n");
+    }
+    Ok(Some(listing))
+}
@@ -0,0 +1,156 @@
+//! Metrics in the Prometheus text format, for alerting when syncs fail or stall.
+//!
+//! They are served at `/metrics` with `--metrics-listen`, and/or written to a
+//! file for the node_exporter textfile collector with `--metrics-file`.
+
+use crate::quarantine::now;
+use anyhow::{anyhow, Result};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    fs::{read_to_string, rename, write},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering::Relaxed},
+        Mutex,
+    },
+    thread,
+};
+use tiny_http::{Header, Response, Server};
+
+pub static DOWNLOADED: AtomicU64 = AtomicU64::new(0);
+pub static BYTES: AtomicU64 = AtomicU64::new(0);
+pub static THROTTLED: AtomicU64 = AtomicU64::new(0);
+pub static RETRIED: AtomicU64 = AtomicU64::new(0);
+/// The number of crate files still to be downloaded in the current sync.
+pub static QUEUED: AtomicU64 = AtomicU64::new(0);
+/// Unix timestamp of the end of the last successful sync.
+static LAST_SUCCESS: AtomicU64 = AtomicU64::new(0);
+static SYNCS: AtomicU64 = AtomicU64::new(0);
+static FAILED_SYNCS: AtomicU64 = AtomicU64::new(0);
+/// The number of failed downloads by category (see [`Failure`](crate::failures::Failure)).
+static ERRORS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+const LAST_SUCCESS_METRIC: &str = "cratesync_last_success_timestamp_seconds";
+
+/// Count a failed download.
+pub fn error(category: &str) {
+    *ERRORS
+        .lock()
+        .unwrap()
+        .entry(category.to_string())
+        .or_default() += 1;
+}
+
+/// Record the end of a sync, and update the `--metrics-file`, if any.
+pub fn sync_finished(success: bool, file: Option<&Path>) -> Result<()> {
+    SYNCS.fetch_add(1, Relaxed);
+    if success {
+        LAST_SUCCESS.store(now(), Relaxed);
+    } else {
+        FAILED_SYNCS.fetch_add(1, Relaxed);
+    }
+    if let Some(file) = file {
+        if LAST_SUCCESS.load(Relaxed) == 0 {
+            // Keep the time of the last success of an earlier run.
+            if let Ok(previous) = read_to_string(file) {
+                let time = previous.lines().find_map(|l| {
+                    l.strip_prefix(LAST_SUCCESS_METRIC)?
+                        .trim()
+                        .parse::<f64>()
+                        .ok()
+                });
+                LAST_SUCCESS.store(time.unwrap_or(0.0) as u64, Relaxed);
+            }
+        }
+        let partial_file = file.with_extension("partial");
+        write(&partial_file, render())?;
+        rename(&partial_file, file)?;
+    }
+    Ok(())
+}
+
+/// Serve the metrics at `/metrics` on a background thread.
+pub fn serve(listen: &str) -> Result<()> {
+    let server = Server::http(listen).map_err(|e| anyhow!("unable to listen on {listen}: {e}"))?;
+    println!("Serving metrics at http://{listen}/metrics");
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = if request.url() == "/metrics" {
+                Response::from_string(render()).with_header(
+                    Header::from_bytes("Content-Type", "text/plain; version=0.0.4").unwrap(),
+                )
+            } else {
+                Response::from_string("not found").with_status_code(404)
+            };
+            let _ = request.respond(response);
+        }
+    });
+    Ok(())
+}
+
+fn render() -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        let _ = writeln!(out, "{name} {value}");
+    };
+    metric(
+        "cratesync_downloaded_crates_total",
+        "counter",
+        "Crate files downloaded.",
+        DOWNLOADED.load(Relaxed),
+    );
+    metric(
+        "cratesync_downloaded_bytes_total",
+        "counter",
+        "Bytes of crate files downloaded.",
+        BYTES.load(Relaxed),
+    );
+    metric(
+        "cratesync_throttled_total",
+        "counter",
+        "Times the registry asked to slow down.",
+        THROTTLED.load(Relaxed),
+    );
+    metric(
+        "cratesync_retries_total",
+        "counter",
+        "Downloads that were tried again.",
+        RETRIED.load(Relaxed),
+    );
+    metric(
+        "cratesync_queue_depth",
+        "gauge",
+        "Crate files waiting to be downloaded.",
+        QUEUED.load(Relaxed),
+    );
+    metric(
+        "cratesync_syncs_total",
+        "counter",
+        "Syncs finished, successful or not.",
+        SYNCS.load(Relaxed),
+    );
+    metric(
+        "cratesync_failed_syncs_total",
+        "counter",
+        "Syncs that failed.",
+        FAILED_SYNCS.load(Relaxed),
+    );
+    metric(
+        LAST_SUCCESS_METRIC,
+        "gauge",
+        "Time of the end of the last successful sync.",
+        LAST_SUCCESS.load(Relaxed),
+    );
+    let _ = writeln!(
+        out,
+        "# HELP cratesync_errors_total Crate files that failed to download, by category."
+    );
+    let _ = writeln!(out, "# TYPE cratesync_errors_total counter");
+    for (category, n) in &*ERRORS.lock().unwrap() {
+        let _ = writeln!(out, "cratesync_errors_total{{category=\"{category}\"}} {n}");
+    }
+    out
+}
@@ -0,0 +1,41 @@
+//! Reporting the minimum supported Rust versions of crates.
+
+use crate::{graph::Resolver, index::Index};
+use anyhow::{Context, Result};
+use semver::Version;
+use std::collections::BTreeMap;
+
+pub fn report(index: &Index, name: Option<&str>) -> Result<()> {
+    if let Some(name) = name {
+        let versions = index
+            .crates
+            .get(name)
+            .with_context(|| format!("crate {name:?} not found in index"))?;
+        let mut versions: Vec<_> = versions.iter().collect();
+        versions.sort_by_cached_key(|(v, _)| Version::parse(v).ok());
+        for (version, data) in versions {
+            let msrv = data.rust_version.as_deref().unwrap_or("unspecified");
+            println!("{name} {version}: {msrv}");
+        }
+        return Ok(());
+    }
+
+    let resolver = Resolver::new(index);
+    let mut counts = BTreeMap::<_, usize>::new();
+    for name in index.crates.keys() {
+        let Some(version) = resolver.latest(name) else {
+            continue;
+        };
+        *counts
+            .entry(index.crates[name][version].msrv())
+            .or_default() += 1;
+    }
+    for (msrv, n) in counts {
+        match msrv {
+            Some(msrv) => println!("{msrv}: {n} crates"),
+            None => println!("unspecified: {n} crates"),
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,120 @@
+//! The order in which missing crate files are downloaded, for `--order`.
+//!
+//! On a fresh mirror, the download takes days, so it matters what arrives
+//! first. The index only has the publication time (`pubtime`) of versions
+//! published in recent years, so for older versions, `newest` goes by the
+//! version number instead: first the latest version of every crate, then
+//! the one before that, and so on.
+//!
+//! A priority list (`--priority-list`) moves the crates on it to the front,
+//! such that a new mirror quickly has the crates that are actually used.
+
+use crate::{db_dump::DbDump, index::Index, Download};
+use anyhow::{Context, Result};
+use semver::Version;
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    fs::read_to_string,
+    hash::BuildHasher,
+    path::Path,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Order {
+    /// By crate name and version.
+    Alphabetical,
+    /// The most recently published versions first.
+    Newest,
+    /// The most downloaded versions first, according to the crates.io database dump.
+    Popular,
+    /// In a random order, which spreads the load over crates.
+    Random,
+}
+
+/// Sort the `queue` of downloads of `index` in the given order.
+///
+/// Without a `db_dump`, `popular` falls back to `newest`.
+pub fn sort(queue: &mut VecDeque<Download>, order: Order, index: &Index, db_dump: Option<&DbDump>) {
+    let queue = queue.make_contiguous();
+    match (order, db_dump) {
+        (Order::Alphabetical, _) => {}
+        (Order::Popular, Some(db_dump)) => queue.sort_by_cached_key(|d| {
+            Reverse((
+                db_dump
+                    .version(&d.name, &d.version)
+                    .map_or(0, |v| v.downloads),
+                db_dump.downloads(&d.name).unwrap_or(0),
+            ))
+        }),
+        (Order::Newest | Order::Popular, _) => {
+            let ranks = ranks(index);
+            queue.sort_by_cached_key(|d| {
+                let pubtime = index.crates[&d.name][&d.version].pubtime.as_deref();
+                // Versions with a publication time come first, as None is the lowest.
+                (
+                    Reverse(pubtime),
+                    ranks
+                        .get(&(d.name.as_str(), d.version.as_str()))
+                        .copied()
+                        .unwrap_or(usize::MAX),
+                )
+            })
+        }
+        (Order::Random, _) => {
+            let random = RandomState::new();
+            queue.sort_by_cached_key(|d| random.hash_one((&d.name, &d.version)));
+        }
+    }
+}
+
+/// Move the downloads of the crates on the priority list at `path` to the front of the `queue`,
+/// in the order of the list. The order of the downloads of each crate is kept.
+///
+/// The list has one crate name per line. Empty lines and lines starting with `#` are ignored.
+pub fn prioritize(queue: &mut VecDeque<Download>, path: &Path) -> Result<()> {
+    let file = read_to_string(path)
+        .with_context(|| format!("unable to read priority list {}", path.display()))?;
+    let mut priorities = HashMap::new();
+    for line in file.lines() {
+        let name = line.trim();
+        if !name.is_empty() && !name.starts_with('#') {
+            // Crate names are case-insensitive, like crates.io does.
+            let n = priorities.len();
+            priorities.entry(name.to_ascii_lowercase()).or_insert(n);
+        }
+    }
+    let queue = queue.make_contiguous();
+    queue.sort_by_cached_key(|d| {
+        priorities
+            .get(&d.name.to_ascii_lowercase())
+            .copied()
+            .unwrap_or(usize::MAX)
+    });
+    let n = queue
+        .iter()
+        .take_while(|d| priorities.contains_key(&d.name.to_ascii_lowercase()))
+        .count();
+    if n > 0 {
+        println!("Downloading {n} crate files of the priority list first");
+    }
+    Ok(())
+}
+
+/// The position of every version in its crate, from the highest (0) to the lowest.
+///
+/// Versions that aren't valid semver aren't included.
+fn ranks(index: &Index) -> HashMap<(&str, &str), usize> {
+    let mut ranks = HashMap::new();
+    for (name, versions) in &index.crates {
+        let mut versions: Vec<_> = versions
+            .keys()
+            .filter_map(|v| Some((Version::parse(v).ok()?, v.as_str())))
+            .collect();
+        versions.sort_by(|a, b| b.0.cmp(&a.0));
+        for (rank, (_, version)) in versions.into_iter().enumerate() {
+            ranks.insert((name.as_str(), version), rank);
+        }
+    }
+    ranks
+}
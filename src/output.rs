@@ -0,0 +1,83 @@
+//! Newline-delimited JSON events on stdout, for `--output json`.
+//!
+//! This is for driving cratesync from other programs, which shouldn't have to
+//! scrape the progress meant for humans. In JSON mode, stdout only gets the
+//! events, and all other output goes to stderr instead.
+
+use crate::Summary;
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+    fs::File,
+    io::Write,
+    sync::{Mutex, OnceLock},
+    time::Instant,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    Human,
+    Json,
+}
+
+/// Where the events go, once JSON mode is enabled.
+static EVENTS: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Send events to stdout, and everything else that would go to stdout to stderr.
+#[cfg(unix)]
+pub fn enable_json() -> Result<()> {
+    use std::os::fd::FromRawFd;
+    let stdout = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if stdout < 0 || unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let _ = EVENTS.set(Mutex::new(unsafe { File::from_raw_fd(stdout) }));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn enable_json() -> Result<()> {
+    anyhow::bail!("--output json is only supported on Unix")
+}
+
+pub fn is_json() -> bool {
+    EVENTS.get().is_some()
+}
+
+/// Write an event of the given kind with the fields of `data`, if in JSON mode.
+pub fn event(kind: &str, data: impl Serialize) {
+    let Some(events) = EVENTS.get() else { return };
+    let mut value = serde_json::to_value(data).unwrap_or(Value::Null);
+    let mut object = serde_json::Map::new();
+    object.insert("event".into(), kind.into());
+    if let Value::Object(fields) = &mut value {
+        object.append(fields);
+    }
+    let mut line = serde_json::to_vec(&object).unwrap();
+    line.push(b'\n');
+    let _ = events.lock().unwrap().write_all(&line);
+}
+
+#[derive(Serialize)]
+struct Finished<'a> {
+    success: bool,
+    duration_secs: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(flatten)]
+    summary: Option<&'a Summary>,
+}
+
+/// Write the `summary` event at the end of a sync that started at `start`.
+pub fn finished(start: Instant, result: &Result<Summary>) {
+    event(
+        "summary",
+        Finished {
+            success: result.is_ok(),
+            duration_secs: start.elapsed().as_secs_f64(),
+            error: result.as_ref().err().map(|e| format!("{e:#}")),
+            summary: result.as_ref().ok(),
+        },
+    );
+}
@@ -0,0 +1,301 @@
+//! Removing crate files that shouldn't be in the mirror (anymore).
+//!
+//! By default, pruned files are moved to `trash/<time>/`, where `<time>` is
+//! the Unix timestamp of the prune, such that they can be restored if a prune
+//! turns out to have been too aggressive. `trash empty` deletes them for good.
+
+use crate::{
+    access_log,
+    cas::Cas,
+    cold,
+    filter::Filter,
+    immutable,
+    index::Index,
+    merkle::{self, Merkle},
+    state::{parse_file, State},
+};
+use anyhow::{ensure, Context, Result};
+use std::{
+    fs::{create_dir_all, read_dir, remove_dir, remove_dir_all, remove_file, rename},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const TRASH: &str = "trash";
+
+/// Remove crate files that aren't in the index, and (with `yanked`) those of yanked versions.
+///
+/// Crates that don't match the `filter` are left alone.
+///
+/// With a `selected` part of the index (see [`budget`](crate::budget)), files outside of it are removed too.
+///
+/// With `keep_requested`, files that were downloaded from the built-in server
+/// within that time (see [`access_log`]) are kept regardless.
+pub fn prune(
+    index: &Index,
+    filter: &Filter,
+    selected: Option<&Index>,
+    keep_requested: Option<Duration>,
+    yanked: bool,
+    delete: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let trash = format!("{TRASH}/{time}");
+
+    let requested = match keep_requested {
+        Some(max_age) => access_log::requested_within(max_age)?,
+        None => Default::default(),
+    };
+
+    let state = State::open()?;
+    let mut n = 0;
+    let mut n_kept = 0;
+    let mut bytes = 0;
+    for dir in read_dir("crates")? {
+        let dir = dir?;
+        let name = dir.file_name();
+        let name = name.to_str().context("invalid utf-8 file name")?;
+        if !filter.matches(name) {
+            continue;
+        }
+        for file in read_dir(dir.path())? {
+            let file = file?;
+            let file_name = file.file_name();
+            let file_name = file_name.to_str().context("invalid utf-8 file name")?;
+            let Some(version) = file_name.strip_prefix(&format!("{name}-")).and_then(|f| {
+                f.strip_suffix(".crate")
+                    .or_else(|| f.strip_suffix(cold::SUFFIX))
+            }) else {
+                continue;
+            };
+            let reason = match index.crates.get(name).and_then(|c| c.get(version)) {
+                None => "not in index",
+                Some(data) if yanked && data.yanked => "yanked",
+                Some(_)
+                    if selected.is_some_and(|s| {
+                        s.crates.get(name).and_then(|c| c.get(version)).is_none()
+                    }) =>
+                {
+                    "outside size budget"
+                }
+                Some(_) => continue,
+            };
+            let path = format!("crates/{name}/{file_name}");
+            let crate_file = format!("crates/{name}/{name}-{version}.crate");
+            if let Some(age) = requested.get(&crate_file) {
+                let days = age.as_secs() / (24 * 60 * 60);
+                println!("{path} ({reason}, but kept: requested {days} days ago)");
+                n_kept += 1;
+                continue;
+            }
+            println!("{path} ({reason})");
+            n += 1;
+            bytes += file.metadata()?.len();
+            if dry_run {
+                continue;
+            }
+            immutable::clear(&path)?;
+            if delete {
+                remove_file(&path)?;
+            } else {
+                create_dir_all(format!("{trash}/crates/{name}"))?;
+                rename(&path, format!("{trash}/{path}"))?;
+            }
+            state.remove(&crate_file)?;
+        }
+    }
+
+    let action = if dry_run {
+        "Would prune"
+    } else if delete {
+        "Deleted"
+    } else {
+        "Moved to the trash:"
+    };
+    println!("{action} {n} crate files ({} MiB)", bytes >> 20);
+    if n_kept > 0 {
+        println!("Kept {n_kept} crate files that were recently requested");
+    }
+
+    if n > 0 && !dry_run && Path::new(merkle::FILE).exists() {
+        Merkle::compute(index)?.write()?;
+    }
+
+    Ok(())
+}
+
+/// Clean up everything in `crates/` that isn't referenced by the index: crate files
+/// (which are moved to the trash, like `prune` does), `.partial` files left behind
+/// by interrupted downloads, and empty directories.
+///
+/// Without `remove`, only lists what would be removed. With `yanked`, the
+/// crate files of yanked versions are included. Crates that don't match the `filter` are left alone.
+///
+/// With a `cas`, this also removes its unused objects, and (with `remove`)
+/// adds the verified crate files that aren't in it yet.
+pub fn gc(
+    index: &Index,
+    filter: &Filter,
+    cas: Option<&Cas>,
+    remove: bool,
+    yanked: bool,
+) -> Result<()> {
+    prune(index, filter, None, None, yanked, false, !remove)?;
+
+    let mut n_partial = 0;
+    let mut n_dirs = 0;
+    for dir in read_dir("crates")? {
+        let dir = dir?;
+        if !dir.file_type()?.is_dir()
+            || !dir.file_name().to_str().is_some_and(|n| filter.matches(n))
+        {
+            continue;
+        }
+        let mut empty = true;
+        for file in read_dir(dir.path())? {
+            let path = file?.path();
+            if path.extension().is_some_and(|e| e == "partial") {
+                println!("{} (leftover of interrupted download)", path.display());
+                n_partial += 1;
+                if remove {
+                    remove_file(&path)?;
+                    continue;
+                }
+            }
+            empty = false;
+        }
+        if empty {
+            println!("{} (empty directory)", dir.path().display());
+            n_dirs += 1;
+            if remove {
+                remove_dir(dir.path())?;
+            }
+        }
+    }
+
+    let action = if remove { "Removed" } else { "Would remove" };
+    println!("{action} {n_partial} partial files and {n_dirs} empty directories");
+
+    if let Some(cas) = cas {
+        let mut n_added = 0;
+        let mut n_deduplicated = 0;
+        for (file, sha256) in State::open()?.checksums()? {
+            let matches = parse_file(&file).is_some_and(|(name, version)| {
+                filter.matches(name)
+                    && index
+                        .crates
+                        .get(name)
+                        .and_then(|c| c.get(version))
+                        .is_some_and(|data| data.cksum == sha256)
+            });
+            // The state might be out of date, if files were removed by hand.
+            if !matches || !Path::new(&file).exists() || cas.contains(&file, &sha256)? {
+                continue;
+            }
+            n_added += 1;
+            if remove {
+                n_deduplicated += cas.add(&file, &sha256)? as usize;
+            }
+        }
+        if remove {
+            println!(
+                "Added {n_added} crate files to the CAS, of which {n_deduplicated} were duplicates"
+            );
+        } else if n_added > 0 {
+            println!("Would add {n_added} crate files to the CAS");
+        }
+        // After adding, as the files that are replaced by links might leave objects unused.
+        let (n_objects, bytes) = cas.gc(remove)?;
+        println!(
+            "{action} {n_objects} unused objects from the CAS ({} MiB)",
+            bytes >> 20
+        );
+    }
+
+    Ok(())
+}
+
+/// The prunes in the trash: their timestamp and age.
+fn trash_entries() -> Result<Vec<(u64, Duration)>> {
+    if !Path::new(TRASH).exists() {
+        return Ok(Vec::new());
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut entries = Vec::new();
+    for entry in read_dir(TRASH)? {
+        let name = entry?.file_name();
+        if let Some(time) = name.to_str().and_then(|t| t.parse::<u64>().ok()) {
+            entries.push((time, Duration::from_secs(now.saturating_sub(time))));
+        }
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+pub fn list_trash() -> Result<()> {
+    for (time, age) in trash_entries()? {
+        let n = count_files(&Path::new(TRASH).join(time.to_string()))?;
+        println!(
+            "{time}: {n} crate files, pruned {} days ago",
+            age.as_secs() / (24 * 60 * 60)
+        );
+    }
+    Ok(())
+}
+
+/// Delete the prunes older than `retention` from the trash.
+pub fn empty_trash(retention: Duration) -> Result<()> {
+    let mut n = 0;
+    for (time, age) in trash_entries()? {
+        if age >= retention {
+            remove_dir_all(Path::new(TRASH).join(time.to_string()))?;
+            n += 1;
+        }
+    }
+    println!("Removed {n} prunes from the trash");
+    Ok(())
+}
+
+/// Move the files of a prune back into the mirror.
+pub fn restore_trash(index: &Index, time: u64) -> Result<()> {
+    let dir = Path::new(TRASH).join(time.to_string());
+    ensure!(dir.exists(), "no prune {time} in the trash");
+    let state = State::open()?;
+    let mut n = 0;
+    for crate_dir in read_dir(dir.join("crates"))? {
+        let crate_dir = crate_dir?;
+        let name = crate_dir.file_name();
+        create_dir_all(Path::new("crates").join(&name))?;
+        for file in read_dir(crate_dir.path())? {
+            let file = file?;
+            let path = Path::new("crates").join(&name).join(file.file_name());
+            rename(file.path(), &path)?;
+            if let Some(path) = path.to_str() {
+                // Crate files stored with zstd (see `cold`) are recorded by their original name.
+                let path = path.strip_suffix(".zst").unwrap_or(path);
+                state.set_present(path, file.metadata()?.len(), None)?;
+            }
+            n += 1;
+        }
+    }
+    remove_dir_all(dir)?;
+    println!("Restored {n} crate files");
+    if Path::new(merkle::FILE).exists() {
+        Merkle::compute(index)?.write()?;
+    }
+    Ok(())
+}
+
+fn count_files(dir: &Path) -> Result<usize> {
+    let mut n = 0;
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            n += count_files(&entry.path())?;
+        } else {
+            n += 1;
+        }
+    }
+    Ok(n)
+}
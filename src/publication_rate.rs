@@ -0,0 +1,134 @@
+//! Monitoring the rate at which new versions are published upstream.
+//!
+//! After every sync (and every poll of `watch`), the number of versions in the
+//! index is recorded in publication-rate.json whenever it changed. A sudden
+//! spike usually means an incident at the registry (like a spam wave), and a
+//! long time without any new versions usually means that syncing silently
+//! broke. Both are reported as alerts, in the log and optionally to a webhook.
+
+use crate::{http_client, quarantine::now, Args, SyncArgs};
+use anyhow::{Context, Result};
+use reqwest::header::CONTENT_TYPE;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{read_to_string, rename, write},
+    io::ErrorKind,
+};
+
+pub const FILE: &str = "publication-rate.json";
+
+/// How long samples are kept, which is the period the normal rate is averaged over.
+const HISTORY: u64 = 7 * 24 * 60 * 60;
+
+/// The period of which the number of new versions is compared to the normal rate.
+const WINDOW: u64 = 60 * 60;
+
+/// How much history is needed before spikes are reported.
+const MIN_HISTORY: u64 = 24 * 60 * 60;
+
+#[derive(Default, Serialize, Deserialize)]
+struct State {
+    /// Unix timestamp and number of versions in the index, for every change.
+    samples: Vec<(u64, u64)>,
+    /// Unix timestamp of the last spike alert, to not repeat it on every poll.
+    spike_alerted: u64,
+    /// Whether the current stall was alerted about already.
+    stall_alerted: bool,
+}
+
+/// Record that the index now has `n_versions` versions, and return the alerts, if any.
+pub fn check_total(args: &Args, opts: &SyncArgs, n_versions: u64) -> Result<Vec<String>> {
+    check(args, opts, |_| n_versions)
+}
+
+/// Record that `n_new` versions were added to the index, and return the alerts, if any.
+pub fn check_new(args: &Args, opts: &SyncArgs, n_new: u64) -> Result<Vec<String>> {
+    check(args, opts, |last| last.unwrap_or(0) + n_new)
+}
+
+fn check(
+    args: &Args,
+    opts: &SyncArgs,
+    n_versions: impl FnOnce(Option<u64>) -> u64,
+) -> Result<Vec<String>> {
+    let mut state = match read_to_string(FILE) {
+        Ok(s) => serde_json::from_str(&s).with_context(|| format!("unable to parse {FILE}"))?,
+        Err(e) if e.kind() == ErrorKind::NotFound => State::default(),
+        Err(e) => return Err(e.into()),
+    };
+    let now = now();
+    let last = state.samples.last().copied();
+    let n_versions = n_versions(last.map(|(_, n)| n));
+
+    let mut alerts = Vec::new();
+    if last.is_none_or(|(_, n)| n != n_versions) {
+        state.samples.push((now, n_versions));
+        state.stall_alerted = false;
+    }
+    // Always keep the last sample, to know since when nothing changed.
+    let keep_from = state
+        .samples
+        .iter()
+        .position(|&(t, _)| now.saturating_sub(t) < HISTORY)
+        .unwrap_or(state.samples.len() - 1);
+    state.samples.drain(..keep_from);
+
+    let &(first_time, first_n) = state.samples.first().unwrap();
+    let &(last_time, _) = state.samples.last().unwrap();
+
+    // The number of versions an hour ago, which is that of the last change before then.
+    let window_start = state
+        .samples
+        .iter()
+        .take_while(|&&(t, _)| t + WINDOW <= now)
+        .last();
+    if let Some(&(_, n)) = window_start {
+        if first_time + MIN_HISTORY <= now && state.spike_alerted + WINDOW <= now {
+            let n_new = n_versions.saturating_sub(n);
+            let normal = n_versions.saturating_sub(first_n) as f64 * WINDOW as f64
+                / now.saturating_sub(first_time) as f64;
+            if n_new as f64 > opts.alert_spike_factor * normal.max(1.0) {
+                alerts.push(format!(
+                    "{n_new} new versions were published in the last hour, \
+                     while the average of the last week is {normal:.0} per hour"
+                ));
+                state.spike_alerted = now;
+            }
+        }
+    }
+
+    let stalled = now.saturating_sub(last_time);
+    if stalled >= opts.alert_stalled.as_secs() && !state.stall_alerted {
+        alerts.push(format!(
+            "no new versions were published in the last {} hours, which might mean syncing is broken",
+            stalled / (60 * 60)
+        ));
+        state.stall_alerted = true;
+    }
+
+    write(format!("{FILE}.partial"), serde_json::to_string(&state)?)?;
+    rename(format!("{FILE}.partial"), FILE)?;
+
+    for alert in &alerts {
+        println!("alert: {alert}");
+        if let Some(url) = &opts.alert_webhook {
+            if let Err(e) = post(args, url, alert) {
+                println!("error: unable to post alert to {url}: {e:#}");
+            }
+        }
+    }
+
+    Ok(alerts)
+}
+
+/// Post an alert to a webhook, in the format that (among others) Slack and Mattermost accept.
+fn post(args: &Args, url: &str, alert: &str) -> Result<()> {
+    http_client(args)
+        .build()?
+        .post(url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(serde_json::json!({ "text": format!("cratesync: {alert}") }).to_string())
+        .send()?
+        .error_for_status()?;
+    Ok(())
+}
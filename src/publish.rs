@@ -0,0 +1,302 @@
+//! Publishing the mirrored crates into another registry, like Artifactory or Nexus.
+//!
+//! This uses the publish endpoint of cargo's registry web API
+//! (<https://doc.rust-lang.org/cargo/reference/registry-web-api.html#publish>),
+//! so it works with any registry that accepts `cargo publish`.
+//! Which files have been published to which registry is recorded in
+//! publish-state/, such that later runs only publish the new files.
+
+use crate::{
+    cold, http_client,
+    index::{Dependency, DependencyKind, Details, Index},
+    Args,
+};
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    fs::{create_dir_all, read_to_string, File},
+    io::{Read, Write},
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Mutex,
+    },
+    thread,
+};
+
+/// The metadata sent along with a crate file, as `cargo publish` does.
+#[derive(Serialize)]
+struct NewCrate<'a> {
+    name: &'a str,
+    vers: &'a str,
+    deps: Vec<NewDependency<'a>>,
+    features: BTreeMap<&'a str, &'a [String]>,
+    authors: Vec<String>,
+    description: Option<String>,
+    documentation: Option<String>,
+    homepage: Option<String>,
+    readme: Option<String>,
+    readme_file: Option<String>,
+    keywords: Vec<String>,
+    categories: Vec<String>,
+    license: Option<String>,
+    license_file: Option<String>,
+    repository: Option<String>,
+    badges: BTreeMap<String, String>,
+    links: Option<&'a str>,
+    rust_version: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct NewDependency<'a> {
+    name: &'a str,
+    version_req: &'a str,
+    features: &'a [String],
+    optional: bool,
+    default_features: bool,
+    target: Option<&'a str>,
+    kind: &'static str,
+    registry: Option<&'a str>,
+    explicit_name_in_toml: Option<&'a str>,
+}
+
+/// The parts of Cargo.toml that aren't in the index, but that registries show.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case")]
+struct Package {
+    #[serde(default)]
+    authors: Vec<String>,
+    description: Option<String>,
+    documentation: Option<String>,
+    homepage: Option<String>,
+    #[serde(default)]
+    keywords: Vec<String>,
+    #[serde(default)]
+    categories: Vec<String>,
+    license: Option<String>,
+    license_file: Option<String>,
+    repository: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    package: Option<Package>,
+    /// The old name of the `[package]` section.
+    project: Option<Package>,
+}
+
+#[derive(Deserialize)]
+struct Errors {
+    #[serde(default)]
+    errors: Vec<ErrorDetail>,
+}
+
+#[derive(Deserialize)]
+struct ErrorDetail {
+    detail: String,
+}
+
+/// Publish all crate files that weren't published to the registry at `url` yet.
+///
+/// `url` is the base of the registry's web API, i.e. the `api` in its config.json.
+pub fn publish(
+    args: &Args,
+    index: &Index<Details>,
+    url: &str,
+    token: &str,
+    connections: usize,
+) -> Result<()> {
+    let url = url.trim_end_matches('/');
+    let client = http_client(args).timeout(None).build()?;
+
+    create_dir_all("publish-state")?;
+    let state_file = format!(
+        "publish-state/{}",
+        url.replace(|c: char| !c.is_ascii_alphanumeric(), "_")
+    );
+    let state = read_to_string(&state_file).unwrap_or_default();
+    let published: HashSet<&str> = state.lines().collect();
+    let state_file = Mutex::new(
+        File::options()
+            .create(true)
+            .append(true)
+            .open(&state_file)?,
+    );
+
+    let mut queue = VecDeque::new();
+    for (name, versions) in &index.crates {
+        for version in versions.keys() {
+            let file = format!("crates/{name}/{name}-{version}.crate");
+            if !published.contains(file.as_str()) && cold::exists(&file) {
+                queue.push_back((name, version, file));
+            }
+        }
+    }
+    let n_todo = queue.len();
+    if n_todo == 0 {
+        println!("Registry is up to date");
+        return Ok(());
+    }
+    println!("Publishing {n_todo} crate files...");
+
+    let queue = Mutex::new(queue);
+    let errors = Mutex::new(Vec::new());
+    let n_done = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for _ in 0..connections.min(n_todo) {
+            s.spawn(|| loop {
+                let item = queue.lock().unwrap().pop_front();
+                let Some((name, version, file)) = item else {
+                    break;
+                };
+                if let Err(e) = || -> Result<()> {
+                    let data = &index.crates[name][version];
+                    let body = publish_body(
+                        name,
+                        version,
+                        &data.details,
+                        data.rust_version.as_deref(),
+                        &file,
+                    )?;
+                    let response = client
+                        .put(format!("{url}/api/v1/crates/new"))
+                        .header("Authorization", token)
+                        .body(body)
+                        .send()?;
+                    check_response(response)?;
+                    if data.yanked {
+                        let response = client
+                            .delete(format!("{url}/api/v1/crates/{name}/{version}/yank"))
+                            .header("Authorization", token)
+                            .send()?;
+                        check_response(response).context("unable to yank")?;
+                    }
+                    writeln!(state_file.lock().unwrap(), "{file}")?;
+                    Ok(())
+                }() {
+                    errors
+                        .lock()
+                        .unwrap()
+                        .push(e.context(format!("unable to publish {file:?}")));
+                }
+                n_done.fetch_add(1, Relaxed);
+            });
+        }
+    });
+
+    let errors = errors.into_inner().unwrap();
+    for e in &errors {
+        println!("error: {e:#}");
+    }
+    println!(
+        "Published {} crate files to {url}",
+        n_done.into_inner() - errors.len()
+    );
+
+    Ok(())
+}
+
+/// Check a response of the registry API, which can report errors with a successful status.
+///
+/// A crate that already exists in the registry is not an error.
+fn check_response(response: reqwest::blocking::Response) -> Result<()> {
+    let status = response.status();
+    let body = response.text()?;
+    let errors: Vec<String> = serde_json::from_str::<Errors>(&body)
+        .map(|e| e.errors.into_iter().map(|e| e.detail).collect())
+        .unwrap_or_default();
+    if errors.iter().any(|e| e.contains("already")) {
+        return Ok(());
+    }
+    if !status.is_success() || !errors.is_empty() {
+        let detail = if errors.is_empty() {
+            body.trim().to_string()
+        } else {
+            errors.join("; ")
+        };
+        bail!("registry responded with {status}: {detail}");
+    }
+    Ok(())
+}
+
+/// The body of a publish request: the metadata and the crate file, each prefixed by its length.
+fn publish_body(
+    name: &str,
+    version: &str,
+    details: &Details,
+    rust_version: Option<&str>,
+    file: &str,
+) -> Result<Vec<u8>> {
+    let crate_file = cold::read_file(file)?;
+    // The index doesn't have all metadata, so take the rest from the Cargo.toml in the crate file.
+    let package = read_package(&crate_file, name, version).unwrap_or_default();
+    let metadata = NewCrate {
+        name,
+        vers: version,
+        deps: details.deps.iter().map(new_dependency).collect(),
+        features: details
+            .features
+            .iter()
+            .chain(&details.features2)
+            .map(|(k, v)| (k.as_str(), v.as_slice()))
+            .collect(),
+        authors: package.authors,
+        description: package.description,
+        documentation: package.documentation,
+        homepage: package.homepage,
+        readme: None,
+        readme_file: None,
+        keywords: package.keywords,
+        categories: package.categories,
+        license: package.license,
+        license_file: package.license_file,
+        repository: package.repository,
+        badges: BTreeMap::new(),
+        links: details.links.as_deref(),
+        rust_version,
+    };
+    let metadata = serde_json::to_vec(&metadata)?;
+    let mut body = Vec::with_capacity(8 + metadata.len() + crate_file.len());
+    body.extend_from_slice(&(metadata.len() as u32).to_le_bytes());
+    body.extend_from_slice(&metadata);
+    body.extend_from_slice(&(crate_file.len() as u32).to_le_bytes());
+    body.extend_from_slice(&crate_file);
+    Ok(body)
+}
+
+fn new_dependency(dep: &Dependency) -> NewDependency<'_> {
+    NewDependency {
+        name: dep.crate_name(),
+        version_req: &dep.req,
+        features: &dep.features,
+        optional: dep.optional,
+        default_features: dep.default_features,
+        target: dep.target.as_deref(),
+        kind: match dep.kind {
+            DependencyKind::Normal => "normal",
+            DependencyKind::Build => "build",
+            DependencyKind::Dev => "dev",
+        },
+        registry: dep.registry.as_deref(),
+        explicit_name_in_toml: dep.package.is_some().then_some(dep.name.as_str()),
+    }
+}
+
+fn read_package(crate_file: &[u8], name: &str, version: &str) -> Result<Package> {
+    let manifest_path = format!("{name}-{version}/Cargo.toml");
+    let mut archive = tar::Archive::new(GzDecoder::new(crate_file));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.to_str() == Some(&manifest_path) {
+            let mut manifest = String::new();
+            entry.read_to_string(&mut manifest)?;
+            let manifest: Manifest = toml::from_str(&manifest)?;
+            return manifest
+                .package
+                .or(manifest.project)
+                .context("no [package] in Cargo.toml");
+        }
+    }
+    bail!("no Cargo.toml in crate file")
+}
@@ -0,0 +1,188 @@
+//! Replicating crate files from another cratesync mirror, using its Merkle tree.
+//!
+//! Starting at the root, only the nodes of the remote's tree (see [`merkle`])
+//! whose hash differs from ours are fetched, down to the crates, of which the
+//! missing files are downloaded. Every file is verified against the checksum
+//! in our own index, so the remote mirror doesn't need to be trusted.
+
+use crate::{
+    check_checksum, http_client,
+    index::Index,
+    merkle::{self, Merkle},
+    store::{Hasher, Store},
+    Args,
+};
+use anyhow::{Context, Result};
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, File},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+enum Task {
+    /// A node of the tree to compare.
+    Node(String),
+    /// A crate file to download: name, version, checksum.
+    File(String, String, String),
+}
+
+/// Whether the node at `path` is a crate rather than a prefix, like `2/ab` or `se/rd/serde`.
+fn is_crate(path: &str) -> bool {
+    let parts: Vec<&str> = path.split('/').collect();
+    parts.len() == 3 || parts.len() == 2 && (parts[0] == "1" || parts[0] == "2")
+}
+
+pub fn pull(args: &Args, url: &str, store: &Store) -> Result<()> {
+    let url = url.trim_end_matches('/');
+    let client = http_client(args).build()?;
+
+    println!("Loading index...");
+    let index = Index::read_cached()?;
+    let local = Merkle::compute(&index)?;
+    let names: HashMap<String, &String> = index
+        .crates
+        .keys()
+        .map(|name| (merkle::crate_path(name), name))
+        .collect();
+
+    let queue = Mutex::new(vec![Task::Node(String::new())]);
+    let n_pending = AtomicUsize::new(1);
+    let n_nodes = AtomicUsize::new(0);
+    let n_files = AtomicUsize::new(0);
+    let n_unknown = AtomicUsize::new(0);
+    let errors = Mutex::new(Vec::new());
+
+    let fetch = |path: &str| -> Result<Vec<(String, String)>> {
+        let listing = client
+            .get(format!("{url}/merkle/{path}"))
+            .send()?
+            .error_for_status()?
+            .text()?;
+        let mut lines = listing.lines();
+        let hash = lines.next().context("empty response")?;
+        if local.get(path) == Some(hash) {
+            return Ok(Vec::new());
+        }
+        lines
+            .map(|line| {
+                let (name, hash) = line.split_once(' ').context("invalid response")?;
+                Ok((name.to_string(), hash.to_string()))
+            })
+            .collect()
+    };
+
+    let process = |task: Task| -> Result<()> {
+        match task {
+            Task::Node(path) => {
+                n_nodes.fetch_add(1, Relaxed);
+                let children =
+                    fetch(&path).with_context(|| format!("unable to compare {path:?}"))?;
+                let mut queue = queue.lock().unwrap();
+                if is_crate(&path) {
+                    let Some(&name) = names.get(&path) else {
+                        n_unknown.fetch_add(children.len(), Relaxed);
+                        return Ok(());
+                    };
+                    for (version, cksum) in children {
+                        match index.crates[name].get(&version) {
+                            Some(data) if data.cksum == cksum => {
+                                let file = format!("crates/{name}/{name}-{version}.crate");
+                                if !Path::new(&file).exists() {
+                                    n_pending.fetch_add(1, Relaxed);
+                                    queue.push(Task::File(name.clone(), version, cksum));
+                                }
+                            }
+                            Some(_) => println!(
+                                "error: remote checksum of {name} {version} does not match the index"
+                            ),
+                            None => {
+                                n_unknown.fetch_add(1, Relaxed);
+                            }
+                        }
+                    }
+                } else {
+                    for (child, hash) in children {
+                        let child = if path.is_empty() {
+                            child
+                        } else {
+                            format!("{path}/{child}")
+                        };
+                        if local.get(&child) == Some(&hash) {
+                            continue;
+                        }
+                        n_pending.fetch_add(1, Relaxed);
+                        queue.push(Task::Node(child));
+                    }
+                }
+            }
+            Task::File(name, version, cksum) => {
+                let file = format!("crates/{name}/{name}-{version}.crate");
+                let partial_file = format!("{file}.partial");
+                create_dir_all(format!("crates/{name}"))?;
+                let mut f = File::options()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&partial_file)?;
+                let mut response = client
+                    .get(format!("{url}/{file}"))
+                    .send()?
+                    .error_for_status()?;
+                let mut hasher = Hasher::default();
+                store.copy(&mut response, &mut f, Some(&mut hasher))?;
+                let hashes = check_checksum(hasher, &file, &cksum)?;
+                drop(f);
+                store.commit(&partial_file, &file, &hashes)?;
+                n_files.fetch_add(1, Relaxed);
+            }
+        }
+        Ok(())
+    };
+
+    println!("Comparing with {url}...");
+    thread::scope(|s| {
+        for _ in 0..args.connections {
+            s.spawn(|| loop {
+                let task = queue.lock().unwrap().pop();
+                let Some(task) = task else {
+                    if n_pending.load(Relaxed) == 0 {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                };
+                if let Err(e) = process(task) {
+                    errors.lock().unwrap().push(e);
+                }
+                n_pending.fetch_sub(1, Relaxed);
+            });
+        }
+    });
+
+    let errors = errors.into_inner().unwrap();
+    for e in &errors {
+        println!("error: {e:#}");
+    }
+    let n_unknown = n_unknown.into_inner();
+    if n_unknown > 0 {
+        println!("Skipped {n_unknown} crate files that are not in the local index (yet)");
+    }
+    println!(
+        "Compared {} nodes and pulled {} crate files from {url}",
+        n_nodes.into_inner(),
+        n_files.into_inner()
+    );
+
+    let merkle = Merkle::compute(&index)?;
+    merkle.write()?;
+    println!("Merkle root: {}", merkle.root());
+
+    Ok(())
+}
@@ -0,0 +1,272 @@
+//! Fetching files from upstream when they are requested from `serve --pull-through`.
+//!
+//! Crate files are only fetched if they are in the local index, and are verified
+//! against it like during a sync. They are downloaded from where the registry's
+//! `dl` says, with the `--token`. For crates.io, index files are fetched from the
+//! sparse index if the local index doesn't have them (yet), which is mostly useful
+//! for mirrors that use `--sparse-index`.
+//!
+//! Everything that is fetched is kept in the mirror, unless there is a maximum size
+//! for the fetched crate files. Then they are listed in pull-through.jsonl, and the
+//! least recently requested ones are removed again when they take up more than that.
+//! Requests from before the server was started are taken from the [`access_log`].
+
+use crate::{
+    access_log, check_checksum, cold, http_client, immutable,
+    index::Index,
+    merkle::crate_path,
+    quarantine::now,
+    registry::{self, Registry},
+    sparse_index,
+    state::State,
+    store::{Hasher, Store},
+    Args,
+};
+use anyhow::{Context, Result};
+use reqwest::{
+    blocking::{Client, RequestBuilder},
+    header::{HeaderValue, AUTHORIZATION},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{create_dir_all, read_to_string, remove_file, rename, write, File},
+    io::{ErrorKind, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+pub const FILE: &str = "pull-through.jsonl";
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// The path of the crate file, like `crates/foo/foo-1.0.0.crate`.
+    file: String,
+    size: u64,
+}
+
+/// The crate files fetched by pull-through, when their total size is limited.
+struct Cache {
+    max_size: u64,
+    size: u64,
+    /// file -> (size, Unix timestamp of the last request).
+    files: HashMap<String, (u64, u64)>,
+    state: State,
+}
+
+impl Cache {
+    fn open(max_size: u64) -> Result<Self> {
+        let entries = match read_to_string(FILE) {
+            Ok(s) => s
+                .lines()
+                .map(|line| {
+                    serde_json::from_str(line).with_context(|| format!("unable to parse {FILE}"))
+                })
+                .collect::<Result<Vec<Entry>>>()?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let requested = access_log::requested_within(Duration::MAX)?;
+        let now = now();
+        let mut cache = Self {
+            max_size,
+            size: 0,
+            files: HashMap::new(),
+            state: State::open()?,
+        };
+        for Entry { file, size } in entries {
+            // Unless it was removed by something else, like `prune`.
+            if cold::exists(&file) {
+                let time = requested.get(&file).map_or(0, |age| now - age.as_secs());
+                cache.size += size;
+                cache.files.insert(file, (size, time));
+            }
+        }
+        cache.evict(None)?;
+        Ok(cache)
+    }
+
+    /// Add a fetched file, and remove others if that makes the cache too large.
+    fn add(&mut self, file: &str, size: u64) -> Result<()> {
+        let entry = Entry {
+            file: file.to_string(),
+            size,
+        };
+        let mut log = File::options().create(true).append(true).open(FILE)?;
+        writeln!(log, "{}", serde_json::to_string(&entry)?)?;
+        self.size += size;
+        if let Some((old_size, _)) = self.files.insert(entry.file, (size, now())) {
+            self.size -= old_size;
+        }
+        self.evict(Some(file))
+    }
+
+    /// Remove the least recently requested files (except `keep`) until the cache fits.
+    fn evict(&mut self, keep: Option<&str>) -> Result<()> {
+        let mut evicted = false;
+        while self.size > self.max_size {
+            let Some(file) = self
+                .files
+                .iter()
+                .filter(|(f, _)| Some(f.as_str()) != keep)
+                .min_by_key(|(_, (_, time))| *time)
+                .map(|(f, _)| f.clone())
+            else {
+                break;
+            };
+            let path = match Path::new(&file).exists() {
+                true => file.clone(),
+                false => cold::path(&file),
+            };
+            println!("Evicting {file}");
+            immutable::clear(&path)?;
+            match remove_file(&path) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            self.state.remove(&file)?;
+            let (size, _) = self.files.remove(&file).unwrap();
+            self.size -= size;
+            evicted = true;
+        }
+        if evicted {
+            let mut content = String::new();
+            for (file, (size, _)) in &self.files {
+                let entry = Entry {
+                    file: file.clone(),
+                    size: *size,
+                };
+                content += &(serde_json::to_string(&entry)? + "\n");
+            }
+            write(format!("{FILE}.partial"), content)?;
+            rename(format!("{FILE}.partial"), FILE)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct PullThrough {
+    client: Client,
+    store: Store,
+    registry: Registry,
+    /// The sparse index to fetch index files from, which only crates.io has.
+    sparse_index: Option<&'static str>,
+    /// The Authorization header for the registry, from --token.
+    token: Option<HeaderValue>,
+    /// A lock for every file that is being fetched, such that concurrent requests for it wait for one download.
+    in_progress: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+    /// With a maximum size for the fetched crate files.
+    cache: Option<Mutex<Cache>>,
+}
+
+impl PullThrough {
+    /// With `max_size`, the fetched crate files are limited to that size in total.
+    pub fn new(args: &Args, max_size: Option<u64>) -> Result<Self> {
+        Ok(Self {
+            client: http_client(args).build()?,
+            store: Store::new(args)?,
+            registry: Registry::read()?,
+            sparse_index: (args.index_url == registry::CRATES_IO).then_some(sparse_index::URL),
+            token: args
+                .token
+                .as_deref()
+                .map(|token| {
+                    let mut value = HeaderValue::from_str(token).context("invalid token")?;
+                    value.set_sensitive(true);
+                    anyhow::Ok(value)
+                })
+                .transpose()?,
+            in_progress: Mutex::new(HashMap::new()),
+            cache: max_size.map(Cache::open).transpose()?.map(Mutex::new),
+        })
+    }
+
+    /// Run `f` for `file` while no other thread is doing so, unless it exists by then.
+    fn once(&self, file: &str, f: impl FnOnce() -> Result<()>) -> Result<()> {
+        let lock = self
+            .in_progress
+            .lock()
+            .unwrap()
+            .entry(file.to_string())
+            .or_default()
+            .clone();
+        let guard = lock.lock().unwrap();
+        let result = if cold::exists(file) { Ok(()) } else { f() };
+        drop(guard);
+        let mut in_progress = self.in_progress.lock().unwrap();
+        // Unless other threads are still waiting for it.
+        if Arc::strong_count(&lock) == 2 {
+            in_progress.remove(file);
+        }
+        result
+    }
+
+    /// A GET request to the registry, with the token if there is one.
+    fn get(&self, url: &str) -> RequestBuilder {
+        let request = self.client.get(url);
+        match &self.token {
+            Some(token) => request.header(AUTHORIZATION, token.clone()),
+            None => request,
+        }
+    }
+
+    /// Make sure the index has the file at `path` (like `se/rd/serde`), if it exists upstream.
+    pub fn fetch_index_file(&self, path: &str) -> Result<()> {
+        let Some(sparse_index) = self.sparse_index else {
+            // The git index of other registries is only updated by a sync.
+            return Ok(());
+        };
+        let file = format!("crates.io-index/{path}");
+        self.once(&file, || {
+            let response = self.get(&format!("{sparse_index}/{path}")).send()?;
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(());
+            }
+            let content = response.error_for_status()?.bytes()?;
+            create_dir_all(Path::new(&file).parent().unwrap())?;
+            let partial_file = format!("{file}.partial");
+            write(&partial_file, content)?;
+            rename(partial_file, &file)?;
+            Ok(())
+        })
+    }
+
+    /// Make sure the mirror has the crate file of `name` and `version`, if it's in the index.
+    pub fn fetch_crate_file(&self, name: &str, version: &str) -> Result<()> {
+        self.fetch_index_file(&crate_path(name))?;
+        let index: Index = Index::read_files([crate_path(name)])?;
+        let Some(data) = index.crates.get(name).and_then(|c| c.get(version)) else {
+            return Ok(());
+        };
+        let file = format!("crates/{name}/{name}-{version}.crate");
+        if let Some(cache) = &self.cache {
+            if let Some((_, time)) = cache.lock().unwrap().files.get_mut(&file) {
+                *time = now();
+            }
+        }
+        let url = self.registry.download_url(name, version, &data.cksum);
+        self.once(&file, || {
+            println!("Fetching {file} from {url}");
+            create_dir_all(format!("crates/{name}"))?;
+            let partial_file = format!("{file}.partial");
+            let mut f = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&partial_file)?;
+            let mut response = self.get(&url).send()?.error_for_status()?;
+            let mut hasher = Hasher::default();
+            let size = self.store.copy(&mut response, &mut f, Some(&mut hasher))?;
+            let hashes = check_checksum(hasher, &file, &data.cksum)?;
+            drop(f);
+            self.store.commit(&partial_file, &file, &hashes)?;
+            if let Some(cache) = &self.cache {
+                cache.lock().unwrap().add(&file, size)?;
+            }
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,157 @@
+//! Pushing the index and crate files to a downstream `cratesync ingest`.
+
+use crate::{
+    cold::{self, CrateFile},
+    http_client,
+    index::Index,
+    state::parse_file,
+    Args,
+};
+use anyhow::{bail, Context, Result};
+use reqwest::blocking::Body;
+use std::{
+    collections::VecDeque,
+    fs::{remove_file, File},
+    process::Command,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Mutex,
+    },
+    thread,
+};
+
+pub fn push(args: &Args, url: &str, token: &str) -> Result<()> {
+    let url = url.trim_end_matches('/');
+    let client = http_client(args).timeout(None).build()?;
+
+    // First bring the downstream index up to date, such that it knows
+    // about (and accepts) all the crate files we're about to push.
+    let remote_head = client
+        .get(format!("{url}/index/head"))
+        .bearer_auth(token)
+        .send()?
+        .error_for_status()?
+        .text()?;
+    let remote_head = remote_head.trim();
+    let local_head = git_output(&["rev-parse", "HEAD"])?;
+    if remote_head != local_head {
+        // Only send the new commits if the downstream has a commit we know
+        // of. Otherwise (e.g. after the index history was squashed), send everything.
+        let range = if !remote_head.is_empty()
+            && Command::new("git")
+                .args(["-C", "crates.io-index", "merge-base", "--is-ancestor"])
+                .args([remote_head, &local_head])
+                .status()?
+                .success()
+        {
+            format!("{remote_head}..HEAD")
+        } else {
+            "HEAD".to_string()
+        };
+        Command::new("git")
+            .args([
+                "-C",
+                "crates.io-index",
+                "bundle",
+                "create",
+                "../push.bundle",
+            ])
+            .arg(range)
+            .spawn()?
+            .wait()?
+            .exit_ok()?;
+        let bundle = File::open("push.bundle")?;
+        client
+            .put(format!("{url}/index/bundle"))
+            .bearer_auth(token)
+            .body(bundle)
+            .send()?
+            .error_for_status()
+            .context("downstream rejected index bundle")?;
+        remove_file("push.bundle")?;
+    }
+
+    // Then ask which crate files it is missing, and send the ones we have.
+    let missing = client
+        .get(format!("{url}/missing"))
+        .bearer_auth(token)
+        .send()?
+        .error_for_status()?
+        .text()?;
+    // Only the files that the index refers to, whatever the downstream asks for.
+    let index = Index::read_cached()?;
+    let (queue, invalid): (Vec<&str>, Vec<&str>) = missing.lines().partition(|f| {
+        parse_file(f).is_some_and(|(name, version)| {
+            index
+                .crates
+                .get(name)
+                .is_some_and(|c| c.contains_key(version))
+        })
+    });
+    if !invalid.is_empty() {
+        println!(
+            "warning: ignoring {} files that downstream is missing but aren't in the index",
+            invalid.len()
+        );
+    }
+    let queue: VecDeque<&str> = queue.into_iter().filter(|f| cold::exists(f)).collect();
+    let n_todo = queue.len();
+    if n_todo == 0 {
+        println!("Downstream is up to date");
+        return Ok(());
+    }
+    println!("Pushing {n_todo} crate files...");
+
+    let queue = Mutex::new(queue);
+    let errors = Mutex::new(Vec::new());
+    let n_done = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for _ in 0..args.connections.min(n_todo) {
+            s.spawn(|| loop {
+                let item = queue.lock().unwrap().pop_front();
+                let Some(file) = item else { break };
+                if let Err(e) = || -> Result<()> {
+                    client
+                        .put(format!("{url}/{file}"))
+                        .bearer_auth(token)
+                        .body(match cold::open(file)? {
+                            CrateFile::File(f) => Body::from(f),
+                            CrateFile::Restored(data) => Body::from(data.into_inner()),
+                        })
+                        .send()?
+                        .error_for_status()?;
+                    Ok(())
+                }() {
+                    errors
+                        .lock()
+                        .unwrap()
+                        .push(e.context(format!("unable to push {file:?}")));
+                }
+                n_done.fetch_add(1, Relaxed);
+            });
+        }
+    });
+
+    let errors = errors.into_inner().unwrap();
+    for e in &errors {
+        println!("error: {e:#}");
+    }
+    println!(
+        "Pushed {} crate files to {url}",
+        n_done.into_inner() - errors.len()
+    );
+    if !errors.is_empty() {
+        bail!("unable to push {} crate files", errors.len());
+    }
+
+    Ok(())
+}
+
+pub fn git_output(args: &[&str]) -> Result<String> {
+    let output = Command::new("git")
+        .args(["-C", "crates.io-index"])
+        .args(args)
+        .output()?;
+    output.status.exit_ok()?;
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
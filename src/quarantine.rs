@@ -0,0 +1,172 @@
+//! Files that crates.io refused to serve, such as with 403 Forbidden, or 404 Not Found
+//! and 410 Gone.
+//!
+//! These are not retried on every sync, but only after they expire (see
+//! `--recheck-403` and `--recheck-gone`), or with `recheck-forbidden`.
+//!
+//! They are kept in the state database (see [`state`](crate::state)). This replaces
+//! quarantine.jsonl, with a line of JSON per entry, and the even older `403` file,
+//! which simply listed one path per line.
+
+use crate::{
+    filter::Filter,
+    index::{CrateData, Index},
+    state::{parse_file, State},
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{read_to_string, remove_file},
+    io::ErrorKind,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The file this used to be stored in.
+const LEGACY_FILE: &str = "quarantine.jsonl";
+
+/// The file this used to be stored in before that, without any details.
+const OLDER_LEGACY_FILE: &str = "403";
+
+/// How much of the response body to keep.
+const MAX_RESPONSE_LEN: usize = 200;
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    /// The path of the crate file, like `crates/foo/foo-1.0.0.crate`.
+    pub file: String,
+    /// Unix timestamp of when the file was quarantined.
+    pub time: u64,
+    /// The HTTP status of the response.
+    pub status: u16,
+    /// The start of the response body.
+    pub response: String,
+}
+
+impl Entry {
+    /// Whether the file doesn't exist (anymore), rather than being refused.
+    pub fn is_gone(&self) -> bool {
+        matches!(self.status, 404 | 410)
+    }
+
+    /// How long ago this entry was added.
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(now().saturating_sub(self.time))
+    }
+}
+
+pub struct Quarantine {
+    state: Arc<State>,
+    files: HashSet<String>,
+}
+
+impl Quarantine {
+    /// Load the quarantine, dropping the entries older than `max_age` (or for files that
+    /// are gone, `max_age_gone`) so they are retried.
+    pub fn open(state: Arc<State>, max_age: Duration, max_age_gone: Duration) -> Result<Self> {
+        let (expired, entries): (Vec<_>, Vec<_>) = state
+            .quarantined()?
+            .into_iter()
+            .partition(|e| e.age() >= if e.is_gone() { max_age_gone } else { max_age });
+        if !expired.is_empty() {
+            println!(
+                "Retrying {} crate files from quarantine that expired",
+                expired.len()
+            );
+        }
+        for e in &expired {
+            state.remove(&e.file)?;
+        }
+        Ok(Self {
+            state,
+            files: entries.into_iter().map(|e| e.file).collect(),
+        })
+    }
+
+    pub fn contains(&self, file: &str) -> bool {
+        self.files.contains(file)
+    }
+
+    /// Quarantine `file` because of the given response.
+    pub fn add(&self, file: &str, status: u16, response: &str) -> Result<()> {
+        let mut end = response.len().min(MAX_RESPONSE_LEN);
+        while !response.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.state.set_quarantined(&Entry {
+            file: file.to_string(),
+            time: now(),
+            status,
+            response: response[..end].trim().to_string(),
+        })
+    }
+}
+
+/// Take the files that match `filter` out of quarantine, and return the part of `index` with them.
+pub fn recheck(state: &State, index: &Index, filter: &Filter) -> Result<Index> {
+    let mut crates = BTreeMap::<String, BTreeMap<String, CrateData>>::new();
+    for e in state.quarantined()? {
+        let Some((name, version)) = parse_file(&e.file) else {
+            continue;
+        };
+        if !filter.matches(name) {
+            continue;
+        }
+        state.remove(&e.file)?;
+        if let Some(data) = index.crates.get(name).and_then(|c| c.get(version)) {
+            crates
+                .entry(name.to_string())
+                .or_default()
+                .insert(version.to_string(), data.clone());
+        }
+    }
+    let index = Index { crates };
+    println!(
+        "Retrying {} crate files from quarantine",
+        index.crates.values().map(|c| c.len()).sum::<usize>()
+    );
+    Ok(index)
+}
+
+/// Read the entries of the files this used to be stored in, to move them into the state database.
+pub fn read_legacy() -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    if let Ok(legacy) = read_to_string(OLDER_LEGACY_FILE) {
+        let time = now();
+        entries.extend(legacy.lines().map(|file| Entry {
+            file: file.to_string(),
+            time,
+            status: 403,
+            response: String::new(),
+        }));
+    }
+    if Path::new(LEGACY_FILE).exists() {
+        for line in read_to_string(LEGACY_FILE)?.lines() {
+            entries.push(
+                serde_json::from_str(line)
+                    .with_context(|| format!("unable to parse {LEGACY_FILE}"))?,
+            );
+        }
+    }
+    Ok(entries)
+}
+
+/// Remove the files of [`read_legacy`].
+pub fn remove_legacy() -> Result<()> {
+    for file in [LEGACY_FILE, OLDER_LEGACY_FILE] {
+        match remove_file(file) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
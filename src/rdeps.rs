@@ -0,0 +1,74 @@
+//! Reverse dependency queries against the local index.
+
+use crate::index::{DependencyKind, Details, Index};
+use anyhow::{Context, Result};
+use semver::{Version, VersionReq};
+use std::{cmp::Reverse, collections::HashMap};
+
+/// Print the crates that depend on `name`.
+///
+/// With `range`, only dependencies that accept a version of `name` in that range are included.
+/// By default only the latest dependent version of each crate is printed.
+pub fn rdeps(
+    index: &Index<Details>,
+    name: &str,
+    range: Option<&VersionReq>,
+    all_versions: bool,
+    dev: bool,
+) -> Result<()> {
+    let versions: Vec<Version> = index
+        .crates
+        .get(name)
+        .with_context(|| format!("crate {name:?} not found in index"))?
+        .keys()
+        .filter_map(|v| Version::parse(v).ok())
+        .filter(|v| range.is_none_or(|r| r.matches(v)))
+        .collect();
+
+    // Many dependents use the same requirement, so only check each one once.
+    let mut req_matches = HashMap::<&str, bool>::new();
+
+    let mut n_crates = 0;
+    let mut n_versions = 0;
+    for (dependent, dependent_versions) in &index.crates {
+        // Newest first.
+        let mut dependent_versions: Vec<_> = dependent_versions.iter().collect();
+        dependent_versions.sort_by_cached_key(|(v, _)| Reverse(Version::parse(v).ok()));
+
+        let mut found = false;
+        for (version, data) in dependent_versions {
+            let dep = data.details.deps.iter().find(|dep| {
+                dep.crate_name() == name
+                    && dep.registry.is_none()
+                    && (dev || dep.kind != DependencyKind::Dev)
+                    && *req_matches.entry(&dep.req).or_insert_with(|| {
+                        VersionReq::parse(&dep.req)
+                            .is_ok_and(|req| versions.iter().any(|v| req.matches(v)))
+                    })
+            });
+            let Some(dep) = dep else { continue };
+            n_versions += 1;
+            if !found || all_versions {
+                let mut notes = String::new();
+                if dep.kind != DependencyKind::Normal {
+                    notes += &format!(" ({:?})", dep.kind).to_lowercase();
+                }
+                if dep.optional {
+                    notes += " (optional)";
+                }
+                if data.yanked {
+                    notes += " (yanked)";
+                }
+                println!("{dependent} {version} depends on {name} {}{notes}", dep.req);
+            }
+            found = true;
+        }
+        if found {
+            n_crates += 1;
+        }
+    }
+
+    eprintln!("{n_crates} crates ({n_versions} versions) depend on {name}");
+
+    Ok(())
+}
@@ -0,0 +1,136 @@
+//! The registry that is mirrored, which is crates.io unless `--index-url` says otherwise.
+//!
+//! Crate files are downloaded from where the `dl` of the index's config.json
+//! says, like cargo does. Private registries get the `--token` with the
+//! requests for the index and the crate files.
+
+use anyhow::{ensure, Context, Result};
+use serde::Deserialize;
+use std::{env, fs::read_to_string, path::Path, process::Command, sync::OnceLock};
+
+/// The git index of crates.io.
+pub const CRATES_IO: &str = "https://github.com/rust-lang/crates.io-index";
+
+/// The `dl` of crates.io's config.json.
+const CRATES_IO_DL: &str = "https://static.crates.io/crates";
+
+/// The placeholders in a `dl` template.
+const MARKERS: [&str; 5] = [
+    "{crate}",
+    "{version}",
+    "{prefix}",
+    "{lowerprefix}",
+    "{sha256-checksum}",
+];
+
+#[derive(Deserialize)]
+struct Config {
+    dl: String,
+}
+
+pub struct Registry {
+    /// The template of download URLs.
+    dl: String,
+}
+
+impl Registry {
+    /// Read the config.json of the index, or assume crates.io if there is none (yet).
+    pub fn read() -> Result<Self> {
+        let file = Path::new("crates.io-index/config.json");
+        let dl = if file.exists() {
+            let config: Config = serde_json::from_str(&read_to_string(file)?)
+                .context("unable to parse index config.json")?;
+            config.dl
+        } else {
+            CRATES_IO_DL.to_string()
+        };
+        Ok(Self::new(dl))
+    }
+
+    fn new(dl: String) -> Self {
+        let dl = if dl.trim_end_matches('/') == CRATES_IO_DL {
+            // Where static.crates.io has the files, avoiding the redirect of the default.
+            format!("{CRATES_IO_DL}/{{crate}}/{{crate}}-{{version}}.crate")
+        } else if MARKERS.iter().any(|marker| dl.contains(marker)) {
+            dl
+        } else {
+            // Like cargo does without any markers.
+            format!(
+                "{}/{{crate}}/{{version}}/download",
+                dl.trim_end_matches('/')
+            )
+        };
+        Self { dl }
+    }
+
+    /// The URL to download the crate file of `name` (which must be [valid](is_valid_name)) and `version` from.
+    pub fn download_url(&self, name: &str, version: &str, cksum: &str) -> String {
+        let prefix = match name.len() {
+            1 => "1".to_string(),
+            2 => "2".to_string(),
+            3 => format!("3/{}", &name[..1]),
+            _ => format!("{}/{}", &name[..2], &name[2..4]),
+        };
+        self.dl
+            .replace("{crate}", name)
+            .replace("{version}", version)
+            .replace("{lowerprefix}", &prefix.to_lowercase())
+            .replace("{prefix}", &prefix)
+            .replace("{sha256-checksum}", cksum)
+    }
+}
+
+/// Whether `name` could be a crate name: only ASCII letters, digits, `-` and `_`, and at most 64 of them.
+///
+/// Names from requests must be checked before they're used for paths, which assume ASCII.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Get a token from the output of a (shell) command, for --token-command.
+pub fn run_token_command(command: &str) -> Result<String> {
+    let output = Command::new("sh")
+        .args(["-c", command])
+        .output()
+        .context("unable to run --token-command")?;
+    output.status.exit_ok().with_context(|| {
+        format!(
+            "--token-command failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+    })?;
+    let token = String::from_utf8(output.stdout)?.trim().to_string();
+    ensure!(!token.is_empty(), "--token-command printed no token");
+    Ok(token)
+}
+
+/// Make git send the token to the index, by adding to its configuration through the environment.
+///
+/// Called again for every registry, which replaces (or removes) the token of the previous one.
+/// This must be done before starting any threads.
+pub fn authenticate_git(token: Option<&str>) {
+    // The number of entries that were there before we added ours.
+    static N: OnceLock<usize> = OnceLock::new();
+    let n = *N.get_or_init(|| {
+        env::var("GIT_CONFIG_COUNT")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(0)
+    });
+    match token {
+        Some(token) => {
+            env::set_var(format!("GIT_CONFIG_KEY_{n}"), "http.extraHeader");
+            env::set_var(
+                format!("GIT_CONFIG_VALUE_{n}"),
+                format!("Authorization: {token}"),
+            );
+            env::set_var("GIT_CONFIG_COUNT", (n + 1).to_string());
+        }
+        None if n == 0 => env::remove_var("GIT_CONFIG_COUNT"),
+        None => env::set_var("GIT_CONFIG_COUNT", n.to_string()),
+    }
+}
@@ -0,0 +1,83 @@
+//! The download queue of an interrupted sync, for `sync --resume`.
+//!
+//! While downloading, the remaining queue (including the downloads in
+//! progress) is written to queue.tsv every minute, and when the downloads
+//! stop early. After a crash, `sync --resume` continues with those right
+//! away, without waiting for the index to be updated and read, which takes
+//! minutes. The file is removed when all downloads are done.
+
+use crate::{
+    index::{CrateData, Index},
+    Download,
+};
+use anyhow::{Context, Result};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::{read_to_string, remove_file, rename, File},
+    io::{BufWriter, ErrorKind, Write},
+};
+
+pub const FILE: &str = "queue.tsv";
+
+/// Write the downloads that are left, with their number of failed attempts.
+pub fn save<'a>(downloads: impl IntoIterator<Item = &'a Download>) -> Result<()> {
+    let partial = format!("{FILE}.partial");
+    let mut out = BufWriter::new(File::create(&partial)?);
+    let mut seen = HashSet::new();
+    for d in downloads {
+        // A download can be in progress and back in the queue at the same time, briefly.
+        if seen.insert((&d.name, &d.version)) {
+            writeln!(
+                out,
+                "{}\t{}\t{}\t{}",
+                d.name, d.version, d.cksum, d.attempts
+            )?;
+        }
+    }
+    out.into_inner()?.sync_all()?;
+    rename(partial, FILE)?;
+    Ok(())
+}
+
+/// Remove the saved queue, once all downloads are done.
+pub fn clear() -> Result<()> {
+    match remove_file(FILE) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The saved queue as an index, and the number of failed attempts of each of its crate files.
+pub fn load() -> Result<Option<(Index, HashMap<String, u32>)>> {
+    let content = match read_to_string(FILE) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut crates = BTreeMap::<String, BTreeMap<String, CrateData>>::new();
+    let mut attempts = HashMap::new();
+    for (i, line) in content.lines().enumerate() {
+        let mut fields = line.split('\t');
+        let mut next = || {
+            fields
+                .next()
+                .with_context(|| format!("{FILE}:{}: truncated line", i + 1))
+        };
+        let (name, version, cksum, n) = (next()?, next()?, next()?, next()?);
+        let n = n
+            .parse()
+            .with_context(|| format!("{FILE}:{}: invalid attempts", i + 1))?;
+        attempts.insert(format!("crates/{name}/{name}-{version}.crate"), n);
+        crates.entry(name.to_string()).or_default().insert(
+            version.to_string(),
+            CrateData {
+                cksum: cksum.to_string(),
+                yanked: false,
+                rust_version: None,
+                pubtime: None,
+                details: (),
+            },
+        );
+    }
+    Ok(Some((Index { crates }, attempts)))
+}
@@ -0,0 +1,207 @@
+//! Searching for crates by name and description, without access to crates.io.
+//!
+//! The names come from the index. The descriptions and download counts come
+//! from the crates.io database dump (db-dump.tar.gz, see `db-dump`), if the
+//! mirror has one. Without it, only the names are searched.
+//!
+//! `serve` has the same search at `/api/v1/crates?q=...`, in the format of the
+//! crates.io API that `cargo search` uses.
+
+use crate::{
+    db_dump::{self, DbDump},
+    graph::Resolver,
+    index::Index,
+};
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    cmp::Reverse,
+    fs::metadata,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tiny_http::{Header, Request, Response};
+
+#[derive(Serialize)]
+pub struct Crate {
+    pub name: String,
+    /// The latest version that isn't yanked.
+    pub max_version: String,
+    pub description: Option<String>,
+    pub downloads: Option<u64>,
+    /// The lowercase name and description, with `_` as `-`, to search in.
+    #[serde(skip)]
+    text: String,
+}
+
+pub struct Search {
+    /// Most downloaded first.
+    crates: Vec<Crate>,
+    /// Whether the descriptions and download counts are known.
+    pub has_db_dump: bool,
+}
+
+impl Search {
+    /// Prepare searching the crates of `index`, with the db dump if there is one.
+    pub fn new(index: &Index) -> Result<Self> {
+        let has_db_dump = Path::new(db_dump::FILE).exists();
+        let db_dump = if has_db_dump {
+            DbDump::read(db_dump::FILE)?
+        } else {
+            DbDump::default()
+        };
+        let resolver = Resolver::new(index);
+        let mut crates: Vec<Crate> = index
+            .crates
+            .keys()
+            .filter_map(|name| {
+                let max_version = resolver.latest(name)?.to_string();
+                let description = db_dump.description(name).map(str::to_string);
+                let text = format!("{name}\n{}", description.as_deref().unwrap_or(""))
+                    .to_lowercase()
+                    .replace('_', "-");
+                Some(Crate {
+                    name: name.clone(),
+                    max_version,
+                    description,
+                    downloads: db_dump.downloads(name),
+                    text,
+                })
+            })
+            .collect();
+        crates.sort_by_key(|c| Reverse(c.downloads));
+        Ok(Self {
+            crates,
+            has_db_dump,
+        })
+    }
+
+    /// The crates that contain all words of `query` in their name or description.
+    ///
+    /// A crate with exactly that name comes first, then the others by downloads.
+    pub fn find(&self, query: &str) -> Vec<&Crate> {
+        let query = query.to_lowercase().replace('_', "-");
+        let words: Vec<&str> = query.split_whitespace().collect();
+        let mut found: Vec<&Crate> = self
+            .crates
+            .iter()
+            .filter(|c| words.iter().all(|word| c.text.contains(word)))
+            .collect();
+        if let Some(i) = found
+            .iter()
+            .position(|c| c.name.to_lowercase().replace('_', "-") == query.trim())
+        {
+            let exact = found.remove(i);
+            found.insert(0, exact);
+        }
+        found
+    }
+}
+
+/// Print the crates matching `query` for `search`, like `cargo search` does.
+pub fn search(index: &Index, query: &str, limit: usize) -> Result<()> {
+    let search = Search::new(index)?;
+    let found = search.find(query);
+    let shown = &found[..found.len().min(limit)];
+    let width = shown
+        .iter()
+        .map(|c| c.name.len() + c.max_version.len())
+        .max()
+        .unwrap_or(0);
+    for c in shown {
+        let line = format!("{} = \"{}\"", c.name, c.max_version);
+        match &c.description {
+            Some(description) => {
+                let description = description.split_whitespace().collect::<Vec<_>>().join(" ");
+                let padding = width - c.name.len() - c.max_version.len();
+                println!("{line}{:padding$}    # {description}", "");
+            }
+            None => println!("{line}"),
+        }
+    }
+    if found.len() > shown.len() {
+        println!(
+            "... and {} crates more (use --limit N to see more)",
+            found.len() - shown.len()
+        );
+    } else if found.is_empty() {
+        println!("No crates found");
+    }
+    if !search.has_db_dump {
+        println!(
+            "note: only crate names were searched, run `cratesync db-dump` to search descriptions and sort by downloads"
+        );
+    }
+    Ok(())
+}
+
+/// A [`Search`] that is prepared again when the index or the db dump changed, for `serve`.
+#[derive(Default)]
+pub struct LiveSearch {
+    /// The search, with the index commit and the time of the db dump it was prepared for.
+    current: Mutex<Option<(Version, Arc<Search>)>>,
+}
+
+type Version = (Option<String>, Option<SystemTime>);
+
+impl LiveSearch {
+    fn get(&self) -> Result<Arc<Search>> {
+        let version = (
+            Index::head_commit().ok(),
+            metadata(db_dump::FILE).and_then(|m| m.modified()).ok(),
+        );
+        let mut current = self.current.lock().unwrap();
+        if let Some((v, search)) = &*current {
+            if *v == version {
+                return Ok(search.clone());
+            }
+        }
+        let search = Arc::new(Search::new(&Index::read_cached()?)?);
+        *current = Some((version, search.clone()));
+        Ok(search)
+    }
+}
+
+#[derive(Serialize)]
+struct ApiResponse<'a> {
+    crates: &'a [&'a Crate],
+    meta: Meta,
+}
+
+#[derive(Serialize)]
+struct Meta {
+    total: usize,
+}
+
+/// Respond to a request for `/api/v1/crates` with this `query` string, like crates.io does.
+///
+/// This supports the `q`, `page` and `per_page` parameters.
+pub fn handle(request: Request, query: &str, search: &LiveSearch) -> Result<()> {
+    let (mut q, mut page, mut per_page) = (String::new(), 1, 10);
+    for (key, value) in reqwest::Url::parse(&format!("http://localhost/?{query}"))?.query_pairs() {
+        match &*key {
+            "q" => q = value.into_owned(),
+            "page" => page = value.parse().unwrap_or(1).max(1),
+            "per_page" => per_page = value.parse().unwrap_or(10).clamp(1, 100),
+            _ => {}
+        }
+    }
+    let search = match search.get() {
+        Ok(search) => search,
+        Err(e) => {
+            request.respond(Response::from_string("internal error").with_status_code(500))?;
+            return Err(e.context("unable to prepare the search"));
+        }
+    };
+    let found = search.find(&q);
+    let start = ((page - 1) * per_page).min(found.len());
+    let end = (start + per_page).min(found.len());
+    let body = serde_json::to_string(&ApiResponse {
+        crates: &found[start..end],
+        meta: Meta { total: found.len() },
+    })?;
+    let content_type = Header::from_bytes("Content-Type", "application/json").unwrap();
+    request.respond(Response::from_string(body).with_header(content_type))?;
+    Ok(())
+}
@@ -0,0 +1,97 @@
+//! Checking that cargo can actually use the mirror.
+//!
+//! Runs the built-in server on a local port, and runs `cargo fetch` for a
+//! project with a temporary cargo home in which crates.io is replaced by the mirror.
+
+use crate::serve::{self, Rewrite};
+use anyhow::{anyhow, ensure, Result};
+use std::{
+    env::current_dir,
+    fs::{create_dir_all, remove_dir_all, write},
+    path::Path,
+    process::Command,
+    sync::Arc,
+    thread,
+};
+use tiny_http::Server;
+
+const INDEX: &str = "selftest-index.git";
+const CARGO_HOME: &str = "selftest-cargo-home";
+const PROJECT: &str = "selftest-project";
+
+/// The project used when no lockfile is given.
+const SAMPLE_MANIFEST: &str = r#"[package]
+name = "cratesync-selftest"
+version = "0.0.0"
+edition = "2021"
+
+[dependencies]
+anyhow = "1"
+serde = { version = "1", features = ["derive"] }
+"#;
+
+pub fn selftest(lockfile: Option<&Path>) -> Result<()> {
+    let server =
+        Arc::new(Server::http("127.0.0.1:0").map_err(|e| anyhow!("unable to start server: {e}"))?);
+    let addr = server.server_addr().to_ip().unwrap();
+    let dl_url = format!("http://{addr}/crates/{{crate}}/{{crate}}-{{version}}.crate");
+    println!("Serving the mirror on {addr}...");
+    let rewrite = Rewrite {
+        dl: Some(&dl_url),
+        api: None,
+    };
+    serve::update_rewritten_index(INDEX, rewrite)?;
+    thread::spawn({
+        let server = server.clone();
+        move || {
+            let rewrite = Rewrite {
+                dl: Some(&dl_url),
+                api: None,
+            };
+            // Not recorded in the access log, as these aren't real downloads.
+            serve::run(&server, Some((INDEX, rewrite)), None, None)
+        }
+    });
+
+    let _ = remove_dir_all(CARGO_HOME);
+    create_dir_all(CARGO_HOME)?;
+    write(
+        format!("{CARGO_HOME}/config.toml"),
+        format!(
+            "[source.crates-io]\n\
+             replace-with = \"cratesync\"\n\
+             \n\
+             [source.cratesync]\n\
+             registry = \"http://{addr}/git/index\"\n"
+        ),
+    )?;
+
+    let mut cargo = Command::new("cargo");
+    cargo.env("CARGO_HOME", current_dir()?.join(CARGO_HOME));
+    if let Some(lockfile) = lockfile {
+        let manifest = lockfile.with_file_name("Cargo.toml");
+        ensure!(manifest.exists(), "no Cargo.toml next to {lockfile:?}");
+        println!("Fetching the dependencies of {manifest:?} from the mirror...");
+        cargo
+            .args(["fetch", "--locked", "--manifest-path"])
+            .arg(manifest);
+    } else {
+        let _ = remove_dir_all(PROJECT);
+        create_dir_all(format!("{PROJECT}/src"))?;
+        write(format!("{PROJECT}/Cargo.toml"), SAMPLE_MANIFEST)?;
+        write(format!("{PROJECT}/src/lib.rs"), "")?;
+        println!("Fetching the dependencies of a sample project from the mirror...");
+        cargo
+            .args(["fetch", "--manifest-path"])
+            .arg(format!("{PROJECT}/Cargo.toml"));
+    }
+    let result = cargo.status()?.exit_ok();
+
+    let _ = remove_dir_all(CARGO_HOME);
+    let _ = remove_dir_all(PROJECT);
+
+    result.map_err(|e| anyhow!("self-test failed: cargo fetch: {e}"))?;
+    println!("Self-test passed: cargo fetched all dependencies from the mirror");
+
+    Ok(())
+}
@@ -0,0 +1,360 @@
+//! The built-in HTTP server.
+//!
+//! Serves the index clone over the git smart HTTP protocol at `/git/index`,
+//! by running `git http-backend` for each request, and the crate files at `/crates/`.
+//! The index is also available over the sparse protocol at `/index/` (see [`sparse`]).
+//! The Merkle tree of the mirror (see [`merkle`]) is available at `/merkle/`.
+//! Downloads of crate files are recorded in the [`access_log`].
+//!
+//! With `--pull-through`, files that aren't in the mirror yet are fetched
+//! from upstream when requested (see [`pull_through`]).
+//!
+//! The search API of crates.io is available at `/api/v1/crates`, for `cargo
+//! search` (see [`search`]). Cargo finds it through the `api` of `config.json`,
+//! which is crates.io unless it is rewritten with `--api-url`.
+//!
+//! With a `dl_url` or `api_url`, the served index is a separate bare repository
+//! that borrows all objects from `crates.io-index`, with one extra commit on
+//! top that points `config.json` at those URLs.
+
+use crate::{
+    access_log::AccessLog,
+    cold, merkle,
+    pull_through::PullThrough,
+    registry,
+    search::{self, LiveSearch},
+    sparse,
+};
+use anyhow::{anyhow, Context, Result};
+use std::{
+    env::current_dir,
+    fs::{create_dir_all, read_to_string, remove_file, write, File},
+    io::{BufRead, BufReader, Write},
+    path::{Component, Path},
+    process::{Command, Stdio},
+    sync::Mutex,
+    thread,
+};
+use tiny_http::{Header, Request, Response, Server, StatusCode};
+
+const REWRITTEN_INDEX: &str = "served-index.git";
+
+/// What to change in the `config.json` of the served index.
+#[derive(Clone, Copy, Default)]
+pub struct Rewrite<'a> {
+    /// Where to download crate files from.
+    pub dl: Option<&'a str>,
+    /// Where the web API (for `cargo search`) is.
+    pub api: Option<&'a str>,
+}
+
+impl Rewrite<'_> {
+    fn is_empty(&self) -> bool {
+        self.dl.is_none() && self.api.is_none()
+    }
+
+    pub fn apply(&self, config: &mut serde_json::Value) {
+        if let Some(dl) = self.dl {
+            config["dl"] = dl.into();
+        }
+        if let Some(api) = self.api {
+            config["api"] = api.into();
+        }
+    }
+
+    /// The message of the commit that makes these changes.
+    fn message(&self) -> String {
+        let changes: Vec<String> = [("dl", self.dl), ("api", self.api)]
+            .into_iter()
+            .filter_map(|(key, url)| Some(format!("{key} at {}", url?)))
+            .collect();
+        format!("Point {}", changes.join(" and "))
+    }
+}
+
+pub fn serve(listen: &str, rewrite: Rewrite, pull_through: Option<PullThrough>) -> Result<()> {
+    let server = Server::http(listen).map_err(|e| anyhow!("unable to listen on {listen}: {e}"))?;
+    println!("Serving on {listen}");
+    println!("Git index available at http://{listen}/git/index");
+    println!("Sparse index available at sparse+http://{listen}/index/");
+    let access_log = AccessLog::open()?;
+    run(
+        &server,
+        (!rewrite.is_empty()).then_some((REWRITTEN_INDEX, rewrite)),
+        Some(&access_log),
+        pull_through.as_ref(),
+    )
+}
+
+/// Handle requests using 16 threads.
+///
+/// `rewrite` is the repository to use for the rewritten index, and what to change in it.
+pub fn run(
+    server: &Server,
+    rewrite: Option<(&str, Rewrite)>,
+    access_log: Option<&AccessLog>,
+    pull_through: Option<&PullThrough>,
+) -> Result<()> {
+    if let Some((repo, rewrite)) = rewrite {
+        update_rewritten_index(repo, rewrite)?;
+    }
+    let rewrite_lock = Mutex::new(());
+    let search = LiveSearch::default();
+
+    thread::scope(|s| {
+        for _ in 0..16 {
+            s.spawn(|| {
+                for request in server.incoming_requests() {
+                    if let Err(e) = handle(
+                        request,
+                        rewrite,
+                        &rewrite_lock,
+                        access_log,
+                        pull_through,
+                        &search,
+                    ) {
+                        println!("error: {e:#}");
+                    }
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+fn handle(
+    request: Request,
+    rewrite: Option<(&str, Rewrite)>,
+    rewrite_lock: &Mutex<()>,
+    access_log: Option<&AccessLog>,
+    pull_through: Option<&PullThrough>,
+    search: &LiveSearch,
+) -> Result<()> {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    if let Some(git_path) = path.strip_prefix("/git/index") {
+        let repo = match rewrite {
+            Some((repo, rewrite)) => {
+                // Every fetch starts with a ref advertisement, which is a
+                // good moment to pick up index updates made by a sync.
+                if git_path == "/info/refs" {
+                    let _guard = rewrite_lock.lock().unwrap();
+                    update_rewritten_index(repo, rewrite)?;
+                }
+                repo
+            }
+            None => "crates.io-index",
+        };
+        return git_http_backend(request, &format!("/{repo}{git_path}"), query);
+    }
+    if path == "/api/v1/crates" {
+        return search::handle(request, query, search);
+    }
+    if let Some(index_path) = path.strip_prefix("/index/") {
+        // Checked before anything is written to the index for it.
+        let pull_through = pull_through.filter(|_| sparse::is_valid_path(index_path));
+        if let Some(pull_through) = pull_through {
+            if let Err(e) = pull_through.fetch_index_file(index_path) {
+                request.respond(Response::from_string("bad gateway").with_status_code(502))?;
+                return Err(e);
+            }
+        }
+        let rewrite = rewrite.map_or_else(Rewrite::default, |(_, rewrite)| rewrite);
+        return sparse::handle(request, index_path, rewrite);
+    }
+    if let Some(merkle_path) = path.strip_prefix("/merkle/") {
+        if let Some(listing) = merkle::listing(merkle_path.trim_end_matches('/'))? {
+            request.respond(Response::from_string(listing))?;
+            return Ok(());
+        }
+    }
+    if path.starts_with("/crates/") && path.ends_with(".crate") {
+        let file = Path::new(&path[1..]);
+        if file.components().all(|c| matches!(c, Component::Normal(_))) {
+            if let (Some(pull_through), Some((name, version))) =
+                (pull_through, parse_crate_path(path))
+            {
+                if let Err(e) = pull_through.fetch_crate_file(name, version) {
+                    request.respond(Response::from_string("bad gateway").with_status_code(502))?;
+                    return Err(e);
+                }
+            }
+            if let Ok(f) = File::open(file) {
+                if let Some(access_log) = access_log {
+                    access_log.record(&path[1..])?;
+                }
+                request.respond(Response::from_file(f))?;
+                return Ok(());
+            }
+            if Path::new(&cold::path(&path[1..])).exists() {
+                let data = match cold::read_file(&path[1..]) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        request.respond(
+                            Response::from_string("internal error").with_status_code(500),
+                        )?;
+                        return Err(e);
+                    }
+                };
+                if let Some(access_log) = access_log {
+                    access_log.record(&path[1..])?;
+                }
+                request.respond(Response::from_data(data))?;
+                return Ok(());
+            }
+        }
+    }
+    request.respond(Response::from_string("not found").with_status_code(404))?;
+    Ok(())
+}
+
+/// The name and version from a path like `/crates/foo/foo-1.0.0.crate`.
+fn parse_crate_path(path: &str) -> Option<(&str, &str)> {
+    let (name, file) = path.strip_prefix("/crates/")?.split_once('/')?;
+    let version = file
+        .strip_prefix(name)?
+        .strip_prefix('-')?
+        .strip_suffix(".crate")?;
+    registry::is_valid_name(name).then_some((name, version))
+}
+
+/// Handle a request by running `git http-backend` as a CGI program.
+fn git_http_backend(mut request: Request, path_info: &str, query: &str) -> Result<()> {
+    let header = |name: &'static str| {
+        request
+            .headers()
+            .iter()
+            .find(|h| h.field.equiv(name))
+            .map(|h| h.value.to_string())
+            .unwrap_or_default()
+    };
+    let mut child = Command::new("git")
+        .arg("http-backend")
+        .env("GIT_PROJECT_ROOT", current_dir()?)
+        .env("GIT_HTTP_EXPORT_ALL", "1")
+        .env("PATH_INFO", path_info)
+        .env("QUERY_STRING", query)
+        .env("REQUEST_METHOD", request.method().as_str())
+        .env("CONTENT_TYPE", header("Content-Type"))
+        .env("HTTP_CONTENT_ENCODING", header("Content-Encoding"))
+        .env("GIT_PROTOCOL", header("Git-Protocol"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    let mut body = Vec::new();
+    request.as_reader().read_to_end(&mut body)?;
+    let mut stdin = child.stdin.take().unwrap();
+    thread::spawn(move || stdin.write_all(&body));
+
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut status = 200;
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        stdout.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .with_context(|| format!("invalid header from git http-backend: {line:?}"))?;
+        if name.eq_ignore_ascii_case("Status") {
+            status = value
+                .split_whitespace()
+                .next()
+                .and_then(|code| code.parse().ok())
+                .with_context(|| format!("invalid status from git http-backend: {line:?}"))?;
+        } else {
+            headers.push(
+                Header::from_bytes(name, value.trim())
+                    .map_err(|()| anyhow!("invalid header from git http-backend: {line:?}"))?,
+            );
+        }
+    }
+
+    request.respond(Response::new(
+        StatusCode(status),
+        headers,
+        stdout,
+        None,
+        None,
+    ))?;
+    child.wait()?;
+    Ok(())
+}
+
+/// Make sure the bare `repo` is the current index plus a commit that makes the changes of `rewrite`.
+pub fn update_rewritten_index(repo: &str, rewrite: Rewrite) -> Result<()> {
+    if !Path::new(repo).exists() {
+        git(Command::new("git").args(["init", "--quiet", "--bare", repo]))?;
+        create_dir_all(format!("{repo}/objects/info"))?;
+        write(
+            format!("{repo}/objects/info/alternates"),
+            format!(
+                "{}\n",
+                current_dir()?
+                    .join("crates.io-index/.git/objects")
+                    .display()
+            ),
+        )?;
+    }
+
+    let head = git(Command::new("git").args(["-C", "crates.io-index", "rev-parse", "HEAD"]))?;
+    let message = rewrite.message();
+    let current = git(Command::new("git").args(["-C", repo, "log", "-1", "--format=%P %s"]));
+    if current.ok() == Some(format!("{head} {message}")) {
+        return Ok(());
+    }
+
+    let mut config: serde_json::Value = serde_json::from_str(
+        &read_to_string("crates.io-index/config.json")
+            .context("unable to read index config.json")?,
+    )?;
+    rewrite.apply(&mut config);
+    write(
+        "served-config.json",
+        serde_json::to_string_pretty(&config)? + "\n",
+    )?;
+    let blob =
+        git(Command::new("git").args(["-C", repo, "hash-object", "-w", "../served-config.json"]))?;
+    remove_file("served-config.json")?;
+
+    let index_file = current_dir()?.join("served-index.tmp");
+    let git_with_index = || {
+        let mut cmd = Command::new("git");
+        cmd.args(["-C", repo]).env("GIT_INDEX_FILE", &index_file);
+        cmd
+    };
+    git(git_with_index().args(["read-tree", &head]))?;
+    git(git_with_index()
+        .args(["update-index", "--add", "--cacheinfo"])
+        .arg(format!("100644,{blob},config.json")))?;
+    let tree = git(git_with_index().arg("write-tree"))?;
+    remove_file(&index_file)?;
+
+    let commit = git(Command::new("git")
+        .args(["-C", repo, "commit-tree", &tree, "-p", &head, "-m"])
+        .arg(message)
+        .env("GIT_AUTHOR_NAME", "cratesync")
+        .env("GIT_AUTHOR_EMAIL", "cratesync@localhost")
+        .env("GIT_COMMITTER_NAME", "cratesync")
+        .env("GIT_COMMITTER_EMAIL", "cratesync@localhost"))?;
+    git(Command::new("git").args(["-C", repo, "update-ref", "HEAD", &commit]))?;
+
+    Ok(())
+}
+
+/// Run a git command and return its trimmed output.
+fn git(cmd: &mut Command) -> Result<String> {
+    let output = cmd.output()?;
+    output.status.exit_ok().with_context(|| {
+        format!(
+            "{cmd:?} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+    })?;
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
@@ -0,0 +1,34 @@
+//! Stopping cleanly on Ctrl+C (or SIGTERM), such that the next run can continue where this one left off.
+//!
+//! The first signal only sets a flag, after which no new work is started, but
+//! the work in progress is finished and the state is written. A second signal
+//! exits immediately.
+
+use std::sync::atomic::{AtomicBool, Ordering::Relaxed};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn handle(_: libc::c_int) {
+    if REQUESTED.swap(true, Relaxed) {
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// Catch Ctrl+C and SIGTERM, for commands that check [`requested`].
+#[cfg(unix)]
+pub fn install() {
+    let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install() {}
+
+/// Whether we were asked to stop.
+pub fn requested() -> bool {
+    REQUESTED.load(Relaxed)
+}
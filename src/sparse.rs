@@ -0,0 +1,137 @@
+//! Serving the index over the sparse protocol at `/index/`.
+//!
+//! Index files are served from the working tree of `crates.io-index`.
+//! Compressed copies are kept in `sparse-cache/` and used for clients that
+//! accept them, so files are only compressed again after they change.
+//!
+//! See <https://doc.rust-lang.org/cargo/reference/registry-index.html#sparse-protocol>.
+
+use crate::serve::Rewrite;
+use anyhow::{Context, Result};
+use flate2::{write::GzEncoder, Compression};
+use std::{
+    fs::{create_dir_all, metadata, read, read_to_string, rename, write, File},
+    io::Write,
+    path::{Component, Path, PathBuf},
+    thread,
+};
+use tiny_http::{Header, Request, Response};
+
+const CACHE: &str = "sparse-cache";
+
+#[derive(Clone, Copy)]
+enum Encoding {
+    Zstd,
+    Gzip,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Encoding::Zstd => "zst",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Encoding::Zstd => zstd::encode_all(data, 19)?,
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+                encoder.write_all(data)?;
+                encoder.finish()?
+            }
+        })
+    }
+}
+
+/// The preferred encoding accepted according to an `Accept-Encoding` header, if any.
+fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+    let accepted = |name: &str| {
+        accept_encoding.split(',').any(|e| {
+            let mut params = e.split(';').map(str::trim);
+            params.next() == Some(name)
+                && params.all(|p| p.strip_prefix("q=").is_none_or(|q| q.parse() != Ok(0.0)))
+        })
+    };
+    [Encoding::Zstd, Encoding::Gzip]
+        .into_iter()
+        .find(|e| accepted(e.name()))
+}
+
+/// Whether `path` (from a request) is a path within the index, without `..` or hidden files like `.git`.
+pub fn is_valid_path(path: &str) -> bool {
+    Path::new(path).components().all(|c| match c {
+        Component::Normal(c) => !c.to_string_lossy().starts_with('.'),
+        _ => false,
+    })
+}
+
+/// Respond to a request for `path` within the sparse index.
+///
+/// `config.json` is served with the changes of `rewrite`.
+pub fn handle(request: Request, path: &str, rewrite: Rewrite) -> Result<()> {
+    let file = Path::new("crates.io-index").join(path);
+    if !is_valid_path(path) || !file.is_file() {
+        request.respond(Response::from_string("not found").with_status_code(404))?;
+        return Ok(());
+    }
+
+    if path == "config.json" {
+        let mut config: serde_json::Value = serde_json::from_str(
+            &read_to_string(&file).context("unable to read index config.json")?,
+        )?;
+        rewrite.apply(&mut config);
+        request.respond(Response::from_string(
+            serde_json::to_string_pretty(&config)? + "\n",
+        ))?;
+        return Ok(());
+    }
+
+    let accept_encoding = request
+        .headers()
+        .iter()
+        .find(|h| h.field.equiv("Accept-Encoding"))
+        .map(|h| h.value.to_string())
+        .unwrap_or_default();
+    let vary = Header::from_bytes("Vary", "Accept-Encoding").unwrap();
+    let response = match negotiate(&accept_encoding) {
+        Some(encoding) => {
+            let compressed = compressed(&file, path, encoding)?;
+            Response::from_file(File::open(compressed)?)
+                .with_header(Header::from_bytes("Content-Encoding", encoding.name()).unwrap())
+        }
+        None => Response::from_file(File::open(&file)?),
+    };
+    request.respond(response.with_header(vary))?;
+    Ok(())
+}
+
+/// The compressed copy of `file`, which is (re)created if it's missing or outdated.
+fn compressed(file: &Path, path: &str, encoding: Encoding) -> Result<PathBuf> {
+    let cached = Path::new(CACHE).join(format!("{path}.{}", encoding.extension()));
+    let modified = metadata(file)?.modified()?;
+    if metadata(&cached)
+        .and_then(|m| m.modified())
+        .is_ok_and(|t| t >= modified)
+    {
+        return Ok(cached);
+    }
+    create_dir_all(cached.parent().unwrap())?;
+    // Other threads might be compressing the same file at the same time.
+    let partial = cached.with_extension(format!(
+        "{}.{:?}.partial",
+        encoding.extension(),
+        thread::current().id()
+    ));
+    write(&partial, encoding.compress(&read(file)?)?)?;
+    rename(&partial, &cached)?;
+    Ok(cached)
+}
@@ -0,0 +1,160 @@
+//! Updating the index over the sparse protocol, from index.crates.io.
+//!
+//! The sparse index can't list its crates, so the names are taken from the
+//! crates.io database dump and from the files already in the local index.
+//! Every file is requested with the validators (ETag and Last-Modified) of
+//! the local copy, so only the files that changed are downloaded again.
+//!
+//! The files are put in `crates.io-index`, in the same layout as a clone of
+//! the git index, such that reading the index works the same. That directory
+//! isn't a git repository in this case.
+//!
+//! See <https://doc.rust-lang.org/cargo/reference/registry-index.html#sparse-protocol>.
+
+use crate::{
+    db_dump::{DbDump, Validators},
+    merkle::crate_path,
+};
+use anyhow::{bail, Context, Result};
+use reqwest::{blocking::Client, StatusCode};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{create_dir_all, metadata, read_dir, read_to_string, remove_file, rename, write, File},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering::Relaxed},
+        Mutex,
+    },
+    thread,
+};
+
+pub const URL: &str = "https://index.crates.io";
+
+/// The validators of all local index files, by path.
+const VALIDATORS: &str = "sparse-index.validators.json";
+
+/// Fetch the files of all crates in the `db_dump` or the local index that changed.
+pub fn update(client: &Client, db_dump: &DbDump, connections: usize) -> Result<()> {
+    create_dir_all("crates.io-index")?;
+    let config = client
+        .get(format!("{URL}/config.json"))
+        .send()?
+        .error_for_status()?
+        .text()?;
+    write("crates.io-index/config.json", config)?;
+
+    let mut paths: BTreeSet<String> = db_dump.names().map(crate_path).collect();
+    // Also the ones we have, to notice when they are deleted.
+    local_files(Path::new(""), &mut paths)?;
+
+    let validators: BTreeMap<String, Validators> = read_to_string(VALIDATORS)
+        .ok()
+        .and_then(|v| serde_json::from_str(&v).ok())
+        .unwrap_or_default();
+
+    let queue = Mutex::new(paths.into_iter().collect::<Vec<_>>());
+    let validators = Mutex::new(validators);
+    let errors = Mutex::new(Vec::new());
+    let n_changed = AtomicUsize::new(0);
+    let n_removed = AtomicUsize::new(0);
+    thread::scope(|s| {
+        for _ in 0..connections {
+            s.spawn(|| loop {
+                let item = queue.lock().unwrap().pop();
+                let Some(path) = item else { break };
+                if let Err(e) = || -> Result<()> {
+                    let file = format!("crates.io-index/{path}");
+                    let mut request = client.get(format!("{URL}/{path}"));
+                    if Path::new(&file).exists() {
+                        if let Some(v) = validators.lock().unwrap().get(&path) {
+                            request = v.apply(request);
+                        }
+                    }
+                    let mut response = request.send()?;
+                    match response.status() {
+                        StatusCode::NOT_MODIFIED => {}
+                        StatusCode::NOT_FOUND => {
+                            if Path::new(&file).exists() {
+                                remove_file(&file)?;
+                                n_removed.fetch_add(1, Relaxed);
+                            }
+                            validators.lock().unwrap().remove(&path);
+                        }
+                        _ => {
+                            response = response.error_for_status()?;
+                            let new = Validators::of(&response);
+                            create_dir_all(Path::new(&file).parent().unwrap())?;
+                            let partial_file = format!("{file}.partial");
+                            response.copy_to(&mut File::create(&partial_file)?)?;
+                            rename(partial_file, file)?;
+                            validators.lock().unwrap().insert(path.clone(), new);
+                            n_changed.fetch_add(1, Relaxed);
+                        }
+                    }
+                    Ok(())
+                }() {
+                    errors
+                        .lock()
+                        .unwrap()
+                        .push(e.context(format!("unable to update index file {path:?}")));
+                }
+            });
+        }
+    });
+
+    // Also keep the validators of the files that did update if others failed.
+    write(
+        format!("{VALIDATORS}.partial"),
+        serde_json::to_string(&validators.into_inner().unwrap())?,
+    )?;
+    rename(format!("{VALIDATORS}.partial"), VALIDATORS)?;
+
+    println!(
+        "{} index files changed, {} removed",
+        n_changed.into_inner(),
+        n_removed.into_inner()
+    );
+
+    let errors = errors.into_inner().unwrap();
+    for e in &errors {
+        println!("error: {e:#}");
+    }
+    if !errors.is_empty() {
+        bail!("{} index files failed to update", errors.len());
+    }
+
+    Ok(())
+}
+
+/// Add the paths of the crate files in the local index under `dir`.
+fn local_files(dir: &Path, paths: &mut BTreeSet<String>) -> Result<()> {
+    let full = Path::new("crates.io-index").join(dir);
+    if !full.exists() {
+        return Ok(());
+    }
+    for e in read_dir(full)? {
+        let e = e?;
+        let name = e.file_name();
+        let name = name.to_str().context("invalid utf-8 file name in index")?;
+        let path = dir.join(name);
+        if name.starts_with('.') || name.ends_with(".partial") || name == "config.json" {
+            continue;
+        } else if e.file_type()?.is_dir() {
+            local_files(&path, paths)?;
+        } else {
+            paths.insert(path.to_str().unwrap().to_string());
+        }
+    }
+    Ok(())
+}
+
+/// A description of the state of the sparse index, like `Index::head` for the git index.
+pub fn head() -> Result<String> {
+    let time = metadata(VALIDATORS)
+        .and_then(|m| m.modified())
+        .context("sparse index was never updated")?;
+    Ok(format!(
+        "sparse index (updated {})",
+        httpdate::fmt_http_date(time)
+    ))
+}
@@ -0,0 +1,445 @@
+//! The state of every crate file of the mirror, in the SQLite database state.sqlite.
+//!
+//! A row per crate file records whether it is present (with its size, checksums
+//! and when it was last verified), failed to download, or was refused by the
+//! registry (forbidden, or gone with 404 or 410). This way, a sync doesn't have to check every file on disk to find
+//! the missing ones.
+//!
+//! Downloads in progress are recorded too, such that the partial files of
+//! downloads that were interrupted by a crash can be found (and continued).
+//!
+//! The database is created on first use from the crate files in the mirror and
+//! the older quarantine.jsonl (or `403`) and errors.jsonl files. Crate files that
+//! are removed by hand are only noticed by `verify`.
+
+use crate::{
+    failures::{self, Failure},
+    filter::Filter,
+    quarantine::{self, now, Entry},
+    store::Hashes,
+};
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::read_dir,
+    io::ErrorKind,
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+pub const FILE: &str = "state.sqlite";
+
+/// The version of the schema, in `PRAGMA user_version`. Zero is a new database.
+const VERSION: u32 = 4;
+
+const SCHEMA: &str = "
+    CREATE TABLE files (
+        file TEXT PRIMARY KEY NOT NULL,
+        -- present, failed, forbidden or gone.
+        status TEXT NOT NULL,
+        size INTEGER,
+        -- The checksum, or for a failed download the one it should have.
+        sha256 TEXT,
+        verified_at INTEGER,
+        updated_at INTEGER NOT NULL,
+        http_status INTEGER,
+        category TEXT,
+        error TEXT,
+        attempts INTEGER NOT NULL DEFAULT 0
+    ) WITHOUT ROWID;
+    CREATE INDEX files_status ON files (status);
+";
+
+/// Added in version 2.
+const SCHEMA_PARTIALS: &str = "
+    CREATE TABLE partials (
+        -- The crate file, without the .partial.
+        file TEXT PRIMARY KEY NOT NULL,
+        -- Whether the partial file is the start of the crate file, rather
+        -- than a file with the size of the whole (like segmented downloads).
+        resumable INTEGER NOT NULL
+    ) WITHOUT ROWID;
+";
+
+/// Added in version 3. The BLAKE3 of a present file, for `verify --fast`.
+const SCHEMA_BLAKE3: &str = "ALTER TABLE files ADD COLUMN blake3 TEXT";
+
+/// Added in version 4. When a present file was added to the mirror, for `du`.
+/// Unknown (NULL) for files that were already there before.
+const SCHEMA_ADDED_AT: &str = "ALTER TABLE files ADD COLUMN added_at INTEGER";
+
+pub struct State {
+    db: Mutex<Connection>,
+}
+
+impl State {
+    /// Open the database of the mirror, creating it if it doesn't exist yet.
+    pub fn open() -> Result<Self> {
+        let mut db = Connection::open(FILE).with_context(|| format!("unable to open {FILE}"))?;
+        // Others (like `serve` or a second sync) might be using it too.
+        db.busy_timeout(Duration::from_secs(60))?;
+        db.pragma_update(None, "journal_mode", "WAL")?;
+        // Only a power failure (not a crash) can lose the last updates, which are then redone.
+        db.pragma_update(None, "synchronous", "NORMAL")?;
+
+        let tx = db.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        let version: u32 = tx.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version == 0 {
+            tx.execute_batch(SCHEMA)?;
+        }
+        if version < 2 {
+            tx.execute_batch(SCHEMA_PARTIALS)?;
+        }
+        if version < 3 {
+            tx.execute_batch(SCHEMA_BLAKE3)?;
+        }
+        if version < 4 {
+            tx.execute_batch(SCHEMA_ADDED_AT)?;
+        }
+        if version == 0 {
+            import(&tx)?;
+        }
+        if version < VERSION {
+            tx.pragma_update(None, "user_version", VERSION)?;
+        }
+        tx.commit()?;
+        // Only once the database has them.
+        quarantine::remove_legacy()?;
+        failures::remove_legacy()?;
+
+        Ok(Self { db: Mutex::new(db) })
+    }
+
+    /// The paths of all crate files in the mirror.
+    pub fn present(&self) -> Result<HashSet<String>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare("SELECT file FROM files WHERE status = 'present'")?;
+        let files = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(files)
+    }
+
+    /// The paths and sizes of all crate files in the mirror.
+    pub fn sizes(&self) -> Result<Vec<(String, u64)>> {
+        let db = self.db.lock().unwrap();
+        let mut statement =
+            db.prepare("SELECT file, COALESCE(size, 0) FROM files WHERE status = 'present'")?;
+        let sizes = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(sizes)
+    }
+
+    /// The paths and sizes of the crate files that were added to the mirror since `time`.
+    pub fn added_since(&self, time: u64) -> Result<Vec<(String, u64)>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(
+            "SELECT file, COALESCE(size, 0) FROM files WHERE status = 'present' AND added_at >= ?1",
+        )?;
+        let sizes = statement
+            .query_map([time], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(sizes)
+    }
+
+    /// The hashes of the crate files in the mirror that have a BLAKE3 hash,
+    /// which are those that were verified since it was added to the database.
+    pub fn hashes(&self) -> Result<HashMap<String, Hashes>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(
+            "SELECT file, sha256, blake3 FROM files
+            WHERE status = 'present' AND sha256 IS NOT NULL AND blake3 IS NOT NULL",
+        )?;
+        let hashes = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    Hashes {
+                        sha256: row.get(1)?,
+                        blake3: row.get(2)?,
+                    },
+                ))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(hashes)
+    }
+
+    /// The checksums of the crate files in the mirror that were verified.
+    pub fn checksums(&self) -> Result<HashMap<String, String>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(
+            "SELECT file, sha256 FROM files WHERE status = 'present' AND sha256 IS NOT NULL",
+        )?;
+        let checksums = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(checksums)
+    }
+
+    /// Record that `file` is in the mirror. With its `hashes`, it was just verified.
+    pub fn set_present(&self, file: &str, size: u64, hashes: Option<&Hashes>) -> Result<()> {
+        let now = now();
+        let db = self.db.lock().unwrap();
+        db.execute(
+            "INSERT OR REPLACE INTO files (file, status, size, sha256, blake3, verified_at, updated_at, added_at)
+            VALUES (?1, 'present', ?2, ?3, ?4, ?5, ?6,
+                -- Unchanged if it was present already, like when it's verified again.
+                CASE WHEN EXISTS (SELECT 1 FROM files WHERE file = ?1 AND status = 'present')
+                THEN (SELECT added_at FROM files WHERE file = ?1) ELSE ?6 END)",
+            params![
+                file,
+                size,
+                hashes.map(|h| &h.sha256),
+                hashes.map(|h| &h.blake3),
+                hashes.map(|_| now),
+                now
+            ],
+        )?;
+        db.execute("DELETE FROM partials WHERE file = ?1", [file])?;
+        Ok(())
+    }
+
+    /// Record that `file` is being downloaded into `{file}.partial`.
+    pub fn add_partial(&self, file: &str, resumable: bool) -> Result<()> {
+        self.db.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO partials (file, resumable) VALUES (?1, ?2)",
+            params![file, resumable],
+        )?;
+        Ok(())
+    }
+
+    /// Record that the partial file of `file` is gone.
+    pub fn remove_partial(&self, file: &str) -> Result<()> {
+        self.db
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM partials WHERE file = ?1", [file])?;
+        Ok(())
+    }
+
+    /// The files being downloaded (or whose download was interrupted), and whether they can be continued.
+    pub fn partials(&self) -> Result<Vec<(String, bool)>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare("SELECT file, resumable FROM partials")?;
+        let partials = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(partials)
+    }
+
+    /// Forget about `file`, after it was removed from the mirror.
+    pub fn remove(&self, file: &str) -> Result<()> {
+        self.db
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM files WHERE file = ?1", [file])?;
+        Ok(())
+    }
+
+    /// Forget about the crate files (of crates matching `filter`) that aren't in `files`,
+    /// because they were removed from the mirror by something else. Returns how many.
+    pub fn remove_missing(&self, filter: &Filter, files: &HashSet<String>) -> Result<usize> {
+        let missing: Vec<String> = self
+            .present()?
+            .into_iter()
+            .filter(|file| {
+                !files.contains(file)
+                    && file
+                        .split('/')
+                        .nth(1)
+                        .is_some_and(|name| filter.matches(name))
+            })
+            .collect();
+        let mut db = self.db.lock().unwrap();
+        let tx = db.transaction()?;
+        for file in &missing {
+            tx.execute("DELETE FROM files WHERE file = ?1", [file])?;
+        }
+        tx.commit()?;
+        Ok(missing.len())
+    }
+
+    /// Record a failed download, adding to the attempts of an earlier failure of the same file.
+    pub fn set_failed(&self, failure: &Failure) -> Result<()> {
+        insert_failure(&self.db.lock().unwrap(), failure, now())
+    }
+
+    /// All crate files that failed to download, and weren't downloaded since.
+    pub fn failures(&self) -> Result<Vec<Failure>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(
+            "SELECT file, sha256, category, error, attempts FROM files
+            WHERE status = 'failed' ORDER BY file",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get(1)?,
+                row.get(2)?,
+                row.get(3)?,
+                row.get(4)?,
+            ))
+        })?;
+        let mut failures = Vec::new();
+        for row in rows {
+            let (file, cksum, category, error, attempts) = row?;
+            let (name, version) =
+                parse_file(&file).with_context(|| format!("invalid file {file:?} in {FILE}"))?;
+            failures.push(Failure {
+                name: name.to_string(),
+                version: version.to_string(),
+                cksum,
+                category,
+                error,
+                attempts,
+            });
+        }
+        Ok(failures)
+    }
+
+    /// Record that the registry refused to serve `file` with this response.
+    pub fn set_quarantined(&self, entry: &Entry) -> Result<()> {
+        insert_quarantined(&self.db.lock().unwrap(), entry)
+    }
+
+    /// All crate files that the registry refused to serve, forbidden or gone.
+    pub fn quarantined(&self) -> Result<Vec<Entry>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(
+            "SELECT file, updated_at, http_status, error FROM files
+            WHERE status IN ('forbidden', 'gone') ORDER BY file",
+        )?;
+        let entries = statement
+            .query_map([], |row| {
+                Ok(Entry {
+                    file: row.get(0)?,
+                    time: row.get(1)?,
+                    status: row.get(2)?,
+                    response: row.get(3)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(entries)
+    }
+
+    /// The number of crate files with each status, and how many of the present ones were verified.
+    pub fn counts(&self) -> Result<Vec<(String, usize, usize)>> {
+        let db = self.db.lock().unwrap();
+        let mut statement = db.prepare(
+            "SELECT status, COUNT(*), COUNT(verified_at) FROM files GROUP BY status ORDER BY status",
+        )?;
+        let counts = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(counts)
+    }
+}
+
+/// The name and version of a crate file like `crates/foo/foo-1.0.0.crate`.
+pub fn parse_file(file: &str) -> Option<(&str, &str)> {
+    let (name, file_name) = file.strip_prefix("crates/")?.split_once('/')?;
+    let version = file_name
+        .strip_prefix(name)?
+        .strip_prefix('-')?
+        .strip_suffix(".crate")?;
+    Some((name, version))
+}
+
+fn insert_failure(db: &Connection, failure: &Failure, time: u64) -> Result<()> {
+    db.execute(
+        "INSERT INTO files (file, status, sha256, updated_at, category, error, attempts)
+        VALUES (?1, 'failed', ?2, ?3, ?4, ?5, ?6)
+        ON CONFLICT (file) DO UPDATE SET
+            status = 'failed', size = NULL, sha256 = ?2, verified_at = NULL,
+            updated_at = ?3, http_status = NULL, category = ?4, error = ?5,
+            attempts = CASE WHEN status = 'failed' THEN attempts + ?6 ELSE ?6 END",
+        params![
+            failure.file(),
+            failure.cksum,
+            time,
+            failure.category,
+            failure.error,
+            failure.attempts
+        ],
+    )?;
+    Ok(())
+}
+
+fn insert_quarantined(db: &Connection, entry: &Entry) -> Result<()> {
+    let status = if entry.is_gone() { "gone" } else { "forbidden" };
+    db.execute(
+        "INSERT OR REPLACE INTO files (file, status, updated_at, http_status, error)
+        VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![entry.file, status, entry.time, entry.status, entry.response],
+    )?;
+    Ok(())
+}
+
+/// Fill a new database with the crate files in the mirror, and the older state files.
+fn import(db: &Connection) -> Result<()> {
+    let dirs = match read_dir("crates") {
+        Ok(dirs) => Some(dirs),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    if dirs.is_some() {
+        println!("Creating {FILE} from the crate files in the mirror...");
+    }
+    let now = now();
+    let mut n = 0;
+    for dir in dirs.into_iter().flatten() {
+        let dir = dir?;
+        if !dir.file_type()?.is_dir() {
+            continue;
+        }
+        for file in read_dir(dir.path())? {
+            let file = file?;
+            let path = Path::new("crates")
+                .join(dir.file_name())
+                .join(file.file_name());
+            let Some(path) = path.to_str() else {
+                continue;
+            };
+            if let Some(partial) = path.strip_suffix(".partial") {
+                // Left behind by an interrupted download. It's unknown what's in there.
+                db.execute(
+                    "INSERT INTO partials (file, resumable) VALUES (?1, 0)",
+                    [partial],
+                )?;
+                continue;
+            }
+            if parse_file(path).is_none() {
+                continue;
+            }
+            db.execute(
+                "INSERT INTO files (file, status, size, updated_at) VALUES (?1, 'present', ?2, ?3)",
+                params![path, file.metadata()?.len(), now],
+            )?;
+            n += 1;
+        }
+    }
+    if n > 0 {
+        println!("Found {n} crate files");
+    }
+
+    for entry in quarantine::read_legacy()? {
+        insert_quarantined(db, &entry)?;
+    }
+    for failure in failures::read_legacy()? {
+        // Not if it was downloaded after all.
+        let present: Option<String> = db
+            .query_row(
+                "SELECT status FROM files WHERE file = ?1",
+                [failure.file()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if present.is_none() {
+            insert_failure(db, &failure, now)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,117 @@
+//! Writing the index as a directory tree that can be served as a sparse index
+//! by any static web server, like nginx.
+//!
+//! The files are hard links to the files of the local index where possible,
+//! so updating the tree only touches the files that changed. `config.json` is
+//! rewritten to download crate files from the given URL.
+
+use anyhow::{Context, Result};
+use std::{
+    collections::HashSet,
+    fs::{
+        copy, create_dir_all, hard_link, metadata, read_dir, read_to_string, remove_file, rename,
+        write,
+    },
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+/// Make `dir` a sparse index with the contents of the local index.
+pub fn export(dir: &Path, dl_url: Option<&str>) -> Result<()> {
+    let mut files = HashSet::new();
+    add_files(Path::new("crates.io-index"), Path::new(""), &mut files)?;
+    let n_updated = export_files(dir, dl_url, files.iter().map(String::as_str))?;
+
+    // Remove the files of crates that are no longer in the index.
+    let mut n_removed = 0;
+    let mut existing = HashSet::new();
+    add_files(dir, Path::new(""), &mut existing)?;
+    for file in existing.difference(&files) {
+        remove_file(dir.join(file))?;
+        n_removed += 1;
+    }
+
+    println!(
+        "Static sparse index at {}: {n_updated} files updated, {n_removed} removed",
+        dir.display()
+    );
+    Ok(())
+}
+
+/// Update only the given files (relative to the index root), such as the ones that changed.
+///
+/// Returns the number of files that were updated.
+pub fn export_files<'a>(
+    dir: &Path,
+    dl_url: Option<&str>,
+    files: impl IntoIterator<Item = &'a str>,
+) -> Result<usize> {
+    let mut config: serde_json::Value = serde_json::from_str(
+        &read_to_string("crates.io-index/config.json")
+            .context("unable to read index config.json")?,
+    )?;
+    if let Some(dl_url) = dl_url {
+        config["dl"] = dl_url.into();
+    }
+    create_dir_all(dir)?;
+    write(
+        dir.join("config.json"),
+        serde_json::to_string_pretty(&config)? + "\n",
+    )?;
+
+    let mut n = 0;
+    for file in files {
+        if file.starts_with('.') || file == "config.json" {
+            continue;
+        }
+        let source = Path::new("crates.io-index").join(file);
+        let target = dir.join(file);
+        let Ok(source_meta) = metadata(&source) else {
+            // Removed from the index.
+            if target.exists() {
+                remove_file(&target)?;
+                n += 1;
+            }
+            continue;
+        };
+        if let Ok(target_meta) = metadata(&target) {
+            let same_file =
+                source_meta.dev() == target_meta.dev() && source_meta.ino() == target_meta.ino();
+            if same_file
+                || target_meta.mtime() > source_meta.mtime()
+                    && target_meta.len() == source_meta.len()
+            {
+                continue;
+            }
+        }
+        create_dir_all(target.parent().unwrap())?;
+        let partial = target.with_extension("partial");
+        let _ = remove_file(&partial);
+        // Fall back to copying if hard links aren't possible, like between file systems.
+        if hard_link(&source, &partial).is_err() {
+            copy(&source, &partial)?;
+        }
+        rename(&partial, &target)?;
+        n += 1;
+    }
+    Ok(n)
+}
+
+/// Add the paths (relative to `root`) of the crate files in `root`/`dir`.
+fn add_files(root: &Path, dir: &Path, files: &mut HashSet<String>) -> Result<()> {
+    for e in read_dir(root.join(dir))? {
+        let e = e?;
+        let name = e.file_name();
+        let name = name.to_str().context("invalid utf-8 file name in index")?;
+        if name.starts_with('.') || name.ends_with(".partial") || name == "config.json" {
+            continue;
+        }
+        let path = dir.join(name);
+        if e.file_type()?.is_dir() {
+            add_files(root, &path, files)?;
+        } else {
+            files.insert(path.to_str().unwrap().to_string());
+        }
+    }
+    Ok(())
+}
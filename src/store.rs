@@ -0,0 +1,267 @@
+//! Putting downloaded and verified crate files in place.
+
+use crate::{
+    bucket::Bucket, cas::Cas, immutable, index::Index, memory::Budget, state::State,
+    throttle::Throttle, Args,
+};
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{metadata, remove_file, rename, File},
+    io::{self, ErrorKind, Read, Write},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The hashes of a crate file, computed while it is written.
+#[derive(Default)]
+pub struct Hasher {
+    sha256: Sha256,
+    blake3: blake3::Hasher,
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        self.sha256.update(data);
+        self.blake3.update(data);
+    }
+
+    pub fn finalize(self) -> Hashes {
+        Hashes {
+            sha256: base16ct::lower::encode_string(&self.sha256.finalize()),
+            blake3: self.blake3.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.update(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The SHA-256 (as in the index) and BLAKE3 (for `verify --fast`) of a crate file, in hex.
+pub struct Hashes {
+    pub sha256: String,
+    pub blake3: String,
+}
+
+/// How crate files are stored, which is the same for every way they arrive.
+pub struct Store {
+    immutable: bool,
+    xattrs: bool,
+    /// The index commit the files are verified against, for the extended attributes.
+    index_commit: Mutex<Option<String>>,
+    /// Limits the bytes written per second, shared by all downloads.
+    write_throttle: Option<Throttle>,
+    /// Limits the bytes received per second, shared by all downloads.
+    bandwidth: Option<Throttle>,
+    /// Limits the bytes received per second of every download separately.
+    connection_bandwidth: Option<u64>,
+    /// Limits the requests per second, shared by all downloads.
+    request_rate: Option<Throttle>,
+    /// Limits the files flushed to disk per second. Without it, files aren't flushed explicitly.
+    fsync_throttle: Option<Throttle>,
+    /// The size of the buffer of every download.
+    buffer_size: usize,
+    /// Limits the memory of all those buffers together.
+    memory: Option<Budget>,
+    /// Where the files go instead of the mirror directory, with --object-store.
+    bucket: Option<Arc<Bucket>>,
+    /// Where the files are stored once per content, with --cas.
+    cas: Option<Cas>,
+    /// Where the files that arrived are recorded.
+    state: State,
+}
+
+impl Store {
+    pub fn new(args: &Args) -> Result<Self> {
+        Ok(Self {
+            immutable: args.immutable_files,
+            xattrs: args.xattrs,
+            index_commit: Mutex::new(Index::head_commit().ok()),
+            write_throttle: args.max_write_rate.map(Throttle::new),
+            bandwidth: args.max_bandwidth.map(Throttle::new),
+            connection_bandwidth: args.max_connection_bandwidth,
+            request_rate: args.max_requests_per_second.map(Throttle::new),
+            fsync_throttle: args.max_fsync_rate.map(Throttle::new),
+            buffer_size: args.buffer_size.max(1) as usize,
+            memory: args.max_memory.map(|m| Budget::new(m as usize)),
+            bucket: args.object_store.clone(),
+            cas: args.cas.as_deref().map(Cas::new),
+            state: State::open()?,
+        })
+    }
+
+    /// Copy a response body into the (partial) file a crate is being downloaded into.
+    ///
+    /// This applies --buffer-size, --max-memory, --max-write-rate and the bandwidth limits.
+    /// The data is also fed to the `hasher`, if any, such that the file doesn't have to be read back.
+    pub fn copy(
+        &self,
+        reader: &mut (impl Read + ?Sized),
+        file: &mut File,
+        mut hasher: Option<&mut Hasher>,
+    ) -> io::Result<u64> {
+        let _reservation = self.memory.as_ref().map(|m| m.reserve(self.buffer_size));
+        let connection_bandwidth = self.connection_bandwidth.map(Throttle::new);
+        let mut buffer = vec![0; self.buffer_size];
+        let mut total = 0;
+        loop {
+            let n = match reader.read(&mut buffer) {
+                Ok(0) => return Ok(total),
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            for throttle in [&self.bandwidth, &connection_bandwidth]
+                .into_iter()
+                .flatten()
+            {
+                throttle.take(n as u64);
+            }
+            file.write_all(&buffer[..n])?;
+            if let Some(hasher) = &mut hasher {
+                hasher.update(&buffer[..n]);
+            }
+            if let Some(throttle) = &self.write_throttle {
+                throttle.take(n as u64);
+            }
+            total += n as u64;
+        }
+    }
+
+    /// Wait until another request can be sent without exceeding --max-requests-per-second.
+    pub async fn wait_for_request(&self) {
+        if let Some(throttle) = &self.request_rate {
+            tokio::time::sleep(throttle.delay(1)).await;
+        }
+    }
+
+    /// Like [`Store::copy`], but for async downloads.
+    pub async fn copy_async(
+        &self,
+        response: &mut reqwest::Response,
+        file: &mut File,
+        mut hasher: Option<&mut Hasher>,
+    ) -> Result<u64> {
+        let _reservation = match &self.memory {
+            Some(m) => Some(m.reserve_async(self.buffer_size).await),
+            None => None,
+        };
+        let connection_bandwidth = self.connection_bandwidth.map(Throttle::new);
+        let mut buffer = Vec::with_capacity(self.buffer_size);
+        let mut total = 0;
+        while let Some(chunk) = response.chunk().await? {
+            for throttle in [&self.bandwidth, &connection_bandwidth]
+                .into_iter()
+                .flatten()
+            {
+                tokio::time::sleep(throttle.delay(chunk.len() as u64)).await;
+            }
+            total += chunk.len() as u64;
+            if let Some(hasher) = &mut hasher {
+                hasher.update(&chunk);
+            }
+            buffer.extend_from_slice(&chunk);
+            if buffer.len() >= self.buffer_size {
+                self.write_async(file, &buffer).await?;
+                buffer.clear();
+            }
+        }
+        self.write_async(file, &buffer).await?;
+        Ok(total)
+    }
+
+    async fn write_async(&self, file: &mut File, data: &[u8]) -> io::Result<()> {
+        file.write_all(data)?;
+        if let Some(throttle) = &self.write_throttle {
+            tokio::time::sleep(throttle.delay(data.len() as u64)).await;
+        }
+        Ok(())
+    }
+
+    /// Update the index commit to record, after the index changed.
+    pub fn index_updated(&self) {
+        *self.index_commit.lock().unwrap() = Index::head_commit().ok();
+    }
+
+    /// Move a verified `partial_file` with these `hashes` into place as `file`.
+    pub fn commit(&self, partial_file: &str, file: &str, hashes: &Hashes) -> Result<()> {
+        if let Some(throttle) = &self.fsync_throttle {
+            throttle.take(1);
+        }
+        self.put_in_place(partial_file, file, hashes)
+    }
+
+    /// [`Store::commit`], after waiting for --max-fsync-rate.
+    fn put_in_place(&self, partial_file: &str, file: &str, hashes: &Hashes) -> Result<()> {
+        if self.xattrs {
+            // Before anything else, as this isn't possible anymore once the file is immutable.
+            self.stamp(partial_file, &hashes.sha256)
+                .with_context(|| format!("unable to set extended attributes on {file:?}"))?;
+        }
+        if self.fsync_throttle.is_some() {
+            File::options().write(true).open(partial_file)?.sync_all()?;
+        }
+        let size = metadata(partial_file)?.len();
+        rename(partial_file, file)?;
+        if let Some(cas) = &self.cas {
+            cas.add(file, &hashes.sha256)?;
+        }
+        if self.immutable {
+            immutable::set(file)?;
+        }
+        self.state.set_present(file, size, Some(hashes))
+    }
+
+    /// Like [`Store::commit`], but uploads the file to the bucket instead with --object-store.
+    ///
+    /// The fsync, rename and database writes happen on a thread for blocking work.
+    pub async fn commit_async(
+        self: &Arc<Self>,
+        partial_file: &str,
+        file: &str,
+        hashes: Hashes,
+    ) -> Result<()> {
+        let (store, partial_file, file) =
+            (self.clone(), partial_file.to_string(), file.to_string());
+        match &self.bucket {
+            Some(bucket) => {
+                bucket.upload(&partial_file, &file).await?;
+                tokio::task::spawn_blocking(move || {
+                    let size = metadata(&partial_file)?.len();
+                    remove_file(&partial_file)?;
+                    store.state.set_present(&file, size, Some(&hashes))
+                })
+                .await?
+            }
+            None => {
+                if let Some(throttle) = &self.fsync_throttle {
+                    tokio::time::sleep(throttle.delay(1)).await;
+                }
+                tokio::task::spawn_blocking(move || {
+                    store.put_in_place(&partial_file, &file, &hashes)
+                })
+                .await?
+            }
+        }
+    }
+
+    /// Record the checksum, the time of verification, and the index commit in extended attributes,
+    /// such that this information stays with the file and can be read by other tools.
+    fn stamp(&self, file: &str, cksum: &str) -> Result<()> {
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        xattr::set(file, "user.cratesync.sha256", cksum.as_bytes())?;
+        xattr::set(file, "user.cratesync.verified", time.to_string().as_bytes())?;
+        if let Some(commit) = &*self.index_commit.lock().unwrap() {
+            xattr::set(file, "user.cratesync.index", commit.as_bytes())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+//! Limiting the rate of something shared by all threads, like bytes written to disk.
+
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+pub struct Throttle {
+    /// Units per second.
+    rate: u64,
+    /// When the units taken so far will have been used up, at the given rate.
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(rate: u64) -> Self {
+        Self {
+            rate: rate.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until `n` more units can be used without exceeding the rate.
+    pub fn take(&self, n: u64) {
+        thread::sleep(self.delay(n));
+    }
+
+    /// Take `n` units, and return how long to wait before using them, for async code.
+    pub fn delay(&self, n: u64) -> Duration {
+        let mut next = self.next.lock().unwrap();
+        let now = Instant::now();
+        let start = (*next).max(now);
+        *next = start + Duration::from_secs_f64(n as f64 / self.rate as f64);
+        start - now
+    }
+}
@@ -0,0 +1,100 @@
+//! Checking that downloaded crate files are well-formed, with --validate-archives.
+//!
+//! The checksum only shows that a file is what the index says it should be,
+//! not that what the index says is a usable crate. A valid crate file is a
+//! gzipped tar archive with everything in a `{name}-{version}/` directory,
+//! including a Cargo.toml with that name and version.
+
+use anyhow::{ensure, Context, Result};
+use flate2::read::GzDecoder;
+use serde::Deserialize;
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read},
+    path::PathBuf,
+};
+
+/// The reason a crate file isn't a valid crate archive.
+#[derive(Debug)]
+pub struct InvalidArchive {
+    pub file: String,
+    pub reason: String,
+}
+
+impl fmt::Display for InvalidArchive {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} is not a valid crate file: {}",
+            self.file, self.reason
+        )
+    }
+}
+
+impl std::error::Error for InvalidArchive {}
+
+#[derive(Deserialize)]
+struct Manifest {
+    package: Option<Package>,
+    /// The old name of the `[package]` section.
+    project: Option<Package>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    name: String,
+    version: String,
+}
+
+/// Check that `path` is a valid crate archive for version `version` of crate `name`.
+///
+/// `file` is the name of the crate file in the mirror, for the error.
+pub fn validate(path: &str, file: &str, name: &str, version: &str) -> Result<()> {
+    check(path, name, version).map_err(|e| {
+        InvalidArchive {
+            file: file.to_string(),
+            reason: format!("{e:#}"),
+        }
+        .into()
+    })
+}
+
+fn check(path: &str, name: &str, version: &str) -> Result<()> {
+    let dir = PathBuf::from(format!("{name}-{version}"));
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(path)?));
+    let mut manifest = None;
+    for entry in archive.entries().context("unable to read the archive")? {
+        let mut entry = entry.context("unable to read the archive")?;
+        let path = entry.path()?.into_owned();
+        ensure!(
+            path.starts_with(&dir),
+            "{} is outside of {}/",
+            path.display(),
+            dir.display()
+        );
+        if path == dir.join("Cargo.toml") {
+            let mut s = String::new();
+            entry
+                .read_to_string(&mut s)
+                .context("unable to read Cargo.toml")?;
+            manifest = Some(s);
+        }
+    }
+    // The end of the tar archive isn't the end of the gzip stream, which has a checksum of its own.
+    io::copy(&mut archive.into_inner(), &mut io::sink()).context("invalid gzip stream")?;
+
+    let manifest = manifest.context("no Cargo.toml")?;
+    let manifest: Manifest = toml::from_str(&manifest).context("unable to parse Cargo.toml")?;
+    let package = manifest
+        .package
+        .or(manifest.project)
+        .context("no [package] in Cargo.toml")?;
+    ensure!(
+        package.name == name && package.version == version,
+        "Cargo.toml is for {} {}",
+        package.name,
+        package.version
+    );
+    Ok(())
+}
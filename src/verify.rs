@@ -0,0 +1,351 @@
+//! Checking all crate files in the mirror against the index, to detect bit rot.
+//!
+//! Files are verified in order of their path, and the progress is recorded in
+//! verify-progress.json, such that an interrupted (or time-limited) verification
+//! continues where it left off. That file is removed once all files are verified.
+//!
+//! With `--fast`, files are checked against the BLAKE3 hash that was recorded
+//! in the state database when they were downloaded (or last verified), which is
+//! much faster than SHA-256 and uses all cores even for a single large file.
+//! Files without one are checked with SHA-256 as usual, which records one.
+//!
+//! Crate files stored with zstd (see `cold`) are checked by restoring the
+//! original file, such that its checksum is still that of the index.
+
+use crate::{
+    check_checksum,
+    cold::{self, CrateFile},
+    failures::ChecksumMismatch,
+    filter::Filter,
+    immutable,
+    index::Index,
+    merkle::{self, Merkle},
+    shutdown,
+    state::State,
+    store::{Hasher, Hashes},
+};
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fs::{read_dir, read_to_string, remove_file, rename, write},
+    io::{self, ErrorKind},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering::Relaxed},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+pub const PROGRESS_FILE: &str = "verify-progress.json";
+
+/// How often the progress is saved while verifying.
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The progress of an unfinished verification.
+#[derive(Default, Serialize, Deserialize)]
+struct Progress {
+    /// All crate files up to and including this path are verified.
+    last: String,
+    n_files: usize,
+    bytes: u64,
+    /// The files that don't match the index.
+    bad: BTreeSet<String>,
+    /// The number of files that couldn't be read.
+    n_errors: usize,
+}
+
+impl Progress {
+    fn read() -> Result<Self> {
+        match read_to_string(PROGRESS_FILE) {
+            Ok(s) => {
+                serde_json::from_str(&s).with_context(|| format!("unable to parse {PROGRESS_FILE}"))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn write(&self) -> Result<()> {
+        write(
+            format!("{PROGRESS_FILE}.partial"),
+            serde_json::to_string(self)?,
+        )?;
+        rename(format!("{PROGRESS_FILE}.partial"), PROGRESS_FILE)?;
+        Ok(())
+    }
+}
+
+/// Which of the queued files are taken by a thread, and which of those are still being verified.
+struct Cursor {
+    next: usize,
+    in_flight: BTreeSet<usize>,
+}
+
+/// Recompute the checksum of every crate file, and report the ones that don't match the index.
+///
+/// This continues the previous verification if it was interrupted, unless `restart` is set.
+/// With `batch_duration`, this stops after that time, leaving the rest for the next run.
+/// Crates that don't match the `filter` are skipped.
+///
+/// With `delete`, invalid files are removed, such that the next sync downloads them again.
+///
+/// With `fast`, files are checked against their BLAKE3 hash instead, if the state database has one.
+///
+/// This also updates the state database, with the files that are verified, and
+/// those that were removed from the mirror without it knowing.
+pub fn verify(
+    index: &Index,
+    filter: &Filter,
+    delete: bool,
+    fast: bool,
+    restart: bool,
+    batch_duration: Option<Duration>,
+) -> Result<()> {
+    let mut progress = if restart {
+        Progress::default()
+    } else {
+        Progress::read()?
+    };
+    if !progress.last.is_empty() {
+        println!(
+            "Continuing verification after {} ({} crate files verified so far)",
+            progress.last, progress.n_files
+        );
+    }
+
+    let state = State::open()?;
+    let known = if fast {
+        state.hashes()?
+    } else {
+        HashMap::new()
+    };
+    let mut queue = Vec::new();
+    let mut seen = HashSet::new();
+    let mut n_unknown = 0;
+    let mut n_yanked = 0;
+    for dir in read_dir("crates")? {
+        let dir = dir?;
+        let name = dir.file_name();
+        let name = name
+            .to_str()
+            .context("invalid utf-8 file name")?
+            .to_string();
+        if !filter.matches(&name) {
+            continue;
+        }
+        for file in read_dir(dir.path())? {
+            let file_name = file?.file_name();
+            let file_name = file_name.to_str().context("invalid utf-8 file name")?;
+            let Some(version) = file_name.strip_prefix(&format!("{name}-")).and_then(|f| {
+                f.strip_suffix(".crate")
+                    .or_else(|| f.strip_suffix(cold::SUFFIX))
+            }) else {
+                continue;
+            };
+            let file = format!("crates/{name}/{name}-{version}.crate");
+            if !seen.insert(file.clone()) {
+                // Both as is and stored with zstd, while `cold` is running.
+                continue;
+            }
+            match index.crates.get(&name).and_then(|c| c.get(version)) {
+                Some(data) => {
+                    n_yanked += data.yanked as usize;
+                    if file > progress.last {
+                        queue.push((file, data.cksum.as_str()));
+                    }
+                }
+                None => n_unknown += 1,
+            }
+        }
+    }
+    queue.sort_unstable();
+
+    let n_missing = state.remove_missing(filter, &seen)?;
+    if n_missing > 0 {
+        println!("{n_missing} crate files were removed from the mirror by something else");
+    }
+
+    let n_queued = queue.len();
+    println!("Verifying {n_queued} crate files...");
+
+    let deadline = batch_duration.map(|d| Instant::now() + d);
+    let cursor = Mutex::new(Cursor {
+        next: 0,
+        in_flight: BTreeSet::new(),
+    });
+    let bad = Mutex::new(Vec::new());
+    let n_verified = AtomicUsize::new(0);
+    let n_errors = AtomicUsize::new(0);
+    let bytes = AtomicU64::new(0);
+    let n_threads = thread::available_parallelism().map_or(4, |n| n.get());
+    let n_finished = AtomicUsize::new(0);
+    thread::scope(|s| -> Result<()> {
+        for _ in 0..n_threads {
+            s.spawn(|| {
+                loop {
+                    if shutdown::requested() || deadline.is_some_and(|d| Instant::now() >= d) {
+                        break;
+                    }
+                    let i = {
+                        let mut cursor = cursor.lock().unwrap();
+                        if cursor.next == n_queued {
+                            break;
+                        }
+                        let i = cursor.next;
+                        cursor.next += 1;
+                        cursor.in_flight.insert(i);
+                        i
+                    };
+                    let (file, cksum) = &queue[i];
+                    if let Err(e) = || -> Result<()> {
+                        let mut f = cold::open(file)?;
+                        let size = f.size()?;
+                        bytes.fetch_add(size, Relaxed);
+                        let hashes = match known.get(file.as_str()) {
+                            // Unless the index changed its checksum since.
+                            Some(known) if known.sha256 == *cksum => {
+                                let actual = match &f {
+                                    CrateFile::File(_) => {
+                                        blake3::Hasher::new().update_mmap_rayon(file)?.finalize()
+                                    }
+                                    CrateFile::Restored(data) => blake3::hash(data.get_ref()),
+                                }
+                                .to_hex()
+                                .to_string();
+                                ensure!(
+                                    actual == known.blake3,
+                                    ChecksumMismatch {
+                                        file: file.clone(),
+                                        expected: known.blake3.clone(),
+                                        actual,
+                                    }
+                                );
+                                Hashes {
+                                    sha256: known.sha256.clone(),
+                                    blake3: actual,
+                                }
+                            }
+                            _ => {
+                                let mut hasher = Hasher::default();
+                                io::copy(&mut f, &mut hasher)?;
+                                check_checksum(hasher, file, cksum)?
+                            }
+                        };
+                        state.set_present(file, size, Some(&hashes))
+                    }() {
+                        println!("error: {e:#}");
+                        if e.is::<ChecksumMismatch>() {
+                            bad.lock().unwrap().push(file.clone());
+                        } else {
+                            n_errors.fetch_add(1, Relaxed);
+                        }
+                    }
+                    n_verified.fetch_add(1, Relaxed);
+                    cursor.lock().unwrap().in_flight.remove(&i);
+                }
+                n_finished.fetch_add(1, Relaxed);
+            });
+        }
+        // Save the progress regularly, in case we get interrupted.
+        let mut last_save = Instant::now();
+        while n_finished.load(Relaxed) < n_threads {
+            thread::sleep(Duration::from_millis(100));
+            if last_save.elapsed() >= SAVE_INTERVAL {
+                last_save = Instant::now();
+                let done = {
+                    let cursor = cursor.lock().unwrap();
+                    cursor.in_flight.first().copied().unwrap_or(cursor.next)
+                };
+                if done > 0 {
+                    Progress {
+                        last: queue[done - 1].0.clone(),
+                        n_files: progress.n_files + n_verified.load(Relaxed),
+                        bytes: progress.bytes + bytes.load(Relaxed),
+                        bad: (progress.bad.iter().cloned())
+                            .chain(bad.lock().unwrap().iter().cloned())
+                            .collect(),
+                        n_errors: progress.n_errors + n_errors.load(Relaxed),
+                    }
+                    .write()?;
+                }
+            }
+        }
+        Ok(())
+    })?;
+
+    let bad = bad.into_inner().unwrap();
+    let n_verified = n_verified.into_inner();
+    let n_errors = n_errors.into_inner();
+    let bytes = bytes.into_inner();
+    let done = cursor.into_inner().unwrap().next;
+    if done < n_queued {
+        println!(
+            "Verified {n_verified} crate files ({} MiB): {} invalid, {n_errors} unreadable",
+            bytes >> 20,
+            bad.len(),
+        );
+        println!(
+            "{}, {} crate files remain for the next run",
+            if shutdown::requested() {
+                "Interrupted"
+            } else {
+                "Stopped after --batch-duration"
+            },
+            n_queued - done
+        );
+        if done > 0 {
+            progress.last = queue[done - 1].0.clone();
+        }
+        progress.n_files += n_verified;
+        progress.bytes += bytes;
+        progress.bad.extend(bad.iter().cloned());
+        progress.n_errors += n_errors;
+        progress.write()?;
+    } else {
+        let n_bad = progress.bad.len() + bad.len();
+        println!(
+            "Verified {} crate files ({} MiB): {n_bad} invalid, {} unreadable",
+            progress.n_files + n_verified,
+            (progress.bytes + bytes) >> 20,
+            progress.n_errors + n_errors,
+        );
+        if Path::new(PROGRESS_FILE).exists() {
+            remove_file(PROGRESS_FILE)?;
+        }
+    }
+    if n_yanked > 0 {
+        println!("{n_yanked} of them are yanked versions (see `gc --yanked`)");
+    }
+    if n_unknown > 0 {
+        println!("Skipped {n_unknown} crate files that aren't in the index (see `prune`)");
+    }
+
+    if delete {
+        for file in &bad {
+            let path = match Path::new(file).exists() {
+                true => file.clone(),
+                false => cold::path(file),
+            };
+            immutable::clear(&path)?;
+            remove_file(&path)?;
+            state.remove(file)?;
+        }
+        if !bad.is_empty() {
+            println!(
+                "Deleted {} invalid crate files, to be downloaded again by the next sync",
+                bad.len()
+            );
+            if Path::new(merkle::FILE).exists() {
+                Merkle::compute(index)?.write()?;
+            }
+        }
+    }
+
+    if !bad.is_empty() || n_errors > 0 {
+        bail!("verification failed");
+    }
+    Ok(())
+}
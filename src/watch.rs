@@ -0,0 +1,98 @@
+//! Continuously syncing new versions shortly after they are published.
+
+use crate::{
+    download_crates, filter::Filter, git_client, index::Index, merkle::Merkle, metrics, output,
+    publication_rate, publish_downstream, push_downstream, shutdown, static_index, sync, Args,
+    Summary, SyncArgs,
+};
+use anyhow::{ensure, Result};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+pub fn watch(args: &Args, opts: &SyncArgs, interval: Duration) -> Result<()> {
+    // Every poll would download all new versions, not only the selected ones.
+    ensure!(
+        opts.size_budget.is_none() && opts.top_crates.is_none() && opts.lockfile.is_empty(),
+        "--size-budget, --top-crates and --lockfile can't be used with watch"
+    );
+    ensure!(
+        !args.sparse_index,
+        "watch needs the git index, so can't be used with --sparse-index"
+    );
+    let start = Instant::now();
+    let result = sync(args, opts);
+    output::finished(start, &result);
+    metrics::sync_finished(result.is_ok(), args.metrics_file.as_deref())?;
+    result?;
+
+    let mut head = Index::head_commit()?;
+    loop {
+        let start = Instant::now();
+        while start.elapsed() < interval {
+            if shutdown::requested() {
+                println!("Stopped watching");
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        let start = Instant::now();
+        let result = poll(args, opts, &mut head);
+        if let Err(e) = &result {
+            println!("error: {e:#}");
+        }
+        output::finished(start, &result);
+        metrics::sync_finished(result.is_ok(), args.metrics_file.as_deref())?;
+    }
+}
+
+/// Check for index changes since `head`, and download new versions.
+fn poll(args: &Args, opts: &SyncArgs, head: &mut String) -> Result<Summary> {
+    let new_head = if args.no_index_update {
+        // Something else updates the index for us.
+        Index::head_commit()?
+    } else {
+        Index::fetch(&git_client(args)?, args.shallow_index)?
+    };
+    if new_head == *head {
+        let alerts = publication_rate::check_new(args, opts, 0)?;
+        return Ok(Summary {
+            alerts,
+            ..Summary::default()
+        });
+    }
+
+    // Every line in the index is a version, so the number of new lines is the number of new versions.
+    let (changed, n_new) = Index::changes(head, &new_head)?;
+    let alerts = publication_rate::check_new(args, opts, n_new.max(0) as u64)?;
+    if !args.no_index_update {
+        if args.verify_index_signatures {
+            Index::verify_commit(&new_head, args.index_allowed_signers.as_deref())?;
+        }
+        Index::reset(&new_head)?;
+    }
+    *head = new_head;
+
+    let index = Index::read_files(&changed)?;
+    println!("Index updated: {} crates changed", index.crates.len());
+    let filtered = Filter::new(args)?.apply(&index);
+    let mut summary = download_crates(filtered.as_ref().unwrap_or(&index), None, args, opts)?;
+    summary.alerts = alerts;
+    let mut merkle = Merkle::read()?;
+    merkle.update(&index)?;
+    merkle.write()?;
+    if let Some(dir) = &opts.static_index {
+        static_index::export_files(
+            dir,
+            opts.static_index_dl_url.as_deref(),
+            changed.iter().map(String::as_str),
+        )?;
+    }
+    push_downstream(args, opts)?;
+    if !opts.publish_to.is_empty() {
+        publish_downstream(args, opts, &Index::read_files(&changed)?)?;
+    }
+
+    Ok(summary)
+}